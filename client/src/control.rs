@@ -0,0 +1,270 @@
+use anyhow::{anyhow, Result};
+use common::stats::{PeerStats, TrafficSnapshot};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use tokio::task::JoinHandle;
+
+use crate::vpn_client::ClientHandle;
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::time::Instant;
+#[cfg(unix)]
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use tracing::{debug, info, warn};
+
+#[cfg(unix)]
+use crate::vpn_client::ConnectionState;
+
+/// Longest request line accepted on the control socket
+#[cfg(unix)]
+const MAX_REQUEST_LEN: u64 = 4096;
+
+/// Request sent to the running client, one JSON object per line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Status,
+    Stats,
+    Disconnect,
+}
+
+/// Reply from the running client, one JSON object per line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Status(StatusReport),
+    Stats(StatsReport),
+    /// No session yet: the first connection is still being set up
+    Connecting,
+    /// The connection dropped and the client is waiting to retry
+    Reconnecting { attempt: u32, retry_in_secs: u64 },
+    Disconnecting,
+    Error { message: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatusReport {
+    pub server_addr: SocketAddr,
+    pub interface: String,
+    pub assigned_ip: IpAddr,
+    pub assigned_ipv6: Option<Ipv6Addr>,
+    pub protocol_version: u16,
+    pub capabilities: String,
+    pub datagrams: bool,
+    pub uptime_secs: u64,
+    pub rtt_ms: u64,
+    pub traffic: TrafficSnapshot,
+    /// Counters from the server's last `Stats` message
+    pub server: Option<PeerStats>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatsReport {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub rtt_ms: u64,
+    pub lost_packets: u64,
+    pub congestion_window: u64,
+    pub udp_bytes_sent: u64,
+    pub udp_bytes_received: u64,
+    /// Counters from the server's last `Stats` message
+    pub server: Option<PeerStats>,
+}
+
+impl fmt::Display for StatusReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "VPN status: connected")?;
+        writeln!(f, "  Server:       {}", self.server_addr)?;
+        writeln!(f, "  Interface:    {}", self.interface)?;
+        writeln!(f, "  Address:      {}", self.assigned_ip)?;
+        if let Some(ipv6) = self.assigned_ipv6 {
+            writeln!(f, "  IPv6 address: {}", ipv6)?;
+        }
+        writeln!(f, "  Protocol:     {} ({})", self.protocol_version, self.capabilities)?;
+        writeln!(f, "  Transport:    {}", if self.datagrams { "datagrams" } else { "stream" })?;
+        writeln!(f, "  Uptime:       {}s", self.uptime_secs)?;
+        writeln!(f, "  Traffic:      {} bytes sent, {} bytes received", self.traffic.bytes_sent, self.traffic.bytes_received)?;
+        if let Some(server) = &self.server {
+            writeln!(
+                f,
+                "  Server saw:   {} bytes received, {} bytes sent",
+                server.traffic.bytes_received, server.traffic.bytes_sent
+            )?;
+        }
+        write!(f, "  RTT:          {} ms", self.rtt_ms)
+    }
+}
+
+impl fmt::Display for StatsReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Tunnel traffic:")?;
+        writeln!(f, "  Sent:         {} bytes in {} packets", self.bytes_sent, self.packets_sent)?;
+        writeln!(f, "  Received:     {} bytes in {} packets", self.bytes_received, self.packets_received)?;
+        writeln!(f, "QUIC connection:")?;
+        writeln!(f, "  RTT:          {} ms", self.rtt_ms)?;
+        writeln!(f, "  Lost packets: {}", self.lost_packets)?;
+        writeln!(f, "  Cwnd:         {} bytes", self.congestion_window)?;
+        write!(f, "  UDP:          {} bytes sent, {} bytes received", self.udp_bytes_sent, self.udp_bytes_received)?;
+
+        if let Some(server) = &self.server {
+            writeln!(f)?;
+            writeln!(f, "Server report:")?;
+            writeln!(f, "  Received:     {} bytes in {} packets", server.traffic.bytes_received, server.traffic.packets_received)?;
+            writeln!(f, "  Sent:         {} bytes in {} packets", server.traffic.bytes_sent, server.traffic.packets_sent)?;
+            write!(f, "  RTT:          {} ms", server.latency_ms)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Listen on `path` and answer control requests for the client behind
+/// `handle` until the returned guard is dropped
+#[cfg(unix)]
+pub fn serve(path: &Path, handle: ClientHandle) -> Result<SocketGuard> {
+    remove_stale_socket(path)?;
+
+    let listener = UnixListener::bind(path)
+        .map_err(|e| anyhow!("Failed to bind control socket {}: {}", path.display(), e))?;
+
+    // Only the user running the client may control it
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Control socket listening on {}", path.display());
+
+    let task = tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed to accept control connection: {}", e);
+                    continue;
+                }
+            };
+
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(stream, handle).await {
+                    debug!("Control connection failed: {}", e);
+                }
+            });
+        }
+    });
+
+    Ok(SocketGuard {
+        path: path.to_path_buf(),
+        task,
+    })
+}
+
+/// Send one request to the running client and wait for its reply
+#[cfg(unix)]
+pub async fn request(path: &Path, request: ControlRequest) -> Result<ControlResponse> {
+    let stream = UnixStream::connect(path).await
+        .map_err(|e| anyhow!("VPN client is not running (control socket {}: {})", path.display(), e))?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut line = serde_json::to_string(&request)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+
+    let mut response = String::new();
+    BufReader::new(reader).read_line(&mut response).await?;
+    if response.is_empty() {
+        return Err(anyhow!("VPN client closed the control connection without replying"));
+    }
+
+    Ok(serde_json::from_str(&response)?)
+}
+
+#[cfg(not(unix))]
+pub fn serve(_path: &Path, _handle: ClientHandle) -> Result<SocketGuard> {
+    Err(anyhow!("The control socket is not supported on this platform yet"))
+}
+
+#[cfg(not(unix))]
+pub async fn request(_path: &Path, _request: ControlRequest) -> Result<ControlResponse> {
+    Err(anyhow!("The control socket is not supported on this platform yet"))
+}
+
+#[cfg(unix)]
+async fn handle_connection(stream: UnixStream, handle: ClientHandle) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+
+    let mut line = String::new();
+    BufReader::new(reader.take(MAX_REQUEST_LEN)).read_line(&mut line).await?;
+
+    let request = serde_json::from_str::<ControlRequest>(&line);
+    let response = match (&request, handle.state()) {
+        (Err(e), _) => ControlResponse::Error {
+            message: format!("Invalid request: {}", e),
+        },
+        (Ok(ControlRequest::Disconnect), _) => ControlResponse::Disconnecting,
+        (Ok(ControlRequest::Status), ConnectionState::Connected(session)) => {
+            ControlResponse::Status(session.status())
+        }
+        (Ok(ControlRequest::Stats), ConnectionState::Connected(session)) => {
+            ControlResponse::Stats(session.stats())
+        }
+        (Ok(_), ConnectionState::Connecting) => ControlResponse::Connecting,
+        (Ok(_), ConnectionState::Reconnecting { attempt, retry_at }) => ControlResponse::Reconnecting {
+            attempt,
+            retry_in_secs: retry_at.saturating_duration_since(Instant::now()).as_secs(),
+        },
+    };
+
+    let mut reply = serde_json::to_string(&response)?;
+    reply.push('\n');
+    writer.write_all(reply.as_bytes()).await?;
+    writer.shutdown().await?;
+
+    // Reply first, since closing the session ends the process
+    if let Ok(ControlRequest::Disconnect) = request {
+        info!("Disconnect requested through the control socket");
+        handle.disconnect().await;
+    }
+
+    Ok(())
+}
+
+/// Remove a socket left behind by a client that did not exit cleanly,
+/// refusing to touch one that still has a live client behind it
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(anyhow!(
+            "Another VPN client is already running (control socket {} is in use)",
+            path.display()
+        ));
+    }
+
+    std::fs::remove_file(path)?;
+
+    Ok(())
+}
+
+/// Removes the control socket when the session ends
+#[cfg_attr(not(unix), allow(dead_code))]
+pub struct SocketGuard {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl Drop for SocketGuard {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
\ No newline at end of file
@@ -1,13 +1,18 @@
+mod control;
+mod routes;
+mod trust;
+// Session state is only read through the control socket, which is Unix only
+#[cfg_attr(not(unix), allow(dead_code))]
 mod vpn_client;
 #[cfg(target_os = "windows")]
 mod windows_service;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::{Parser, Subcommand};
-use common::config::ClientConfig;
-use std::path::PathBuf;
-use tokio::fs;
-use tracing::{error, info};
+use common::config::{ClientConfig, ReconnectConfig, TunnelMode};
+use control::{ControlRequest, ControlResponse};
+use std::path::{Path, PathBuf};
+use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 use vpn_client::VpnClient;
 
@@ -31,8 +36,16 @@ enum Command {
         #[clap(short, long)]
         username: String,
         
+        /// May be left out when the client certificate is enough
         #[clap(short, long)]
-        password: String,
+        password: Option<String>,
+        
+        /// Client certificate for servers that use certificate authentication
+        #[clap(long, requires = "client_key")]
+        client_cert: Option<PathBuf>,
+        
+        #[clap(long, requires = "client_cert")]
+        client_key: Option<PathBuf>,
         
         #[clap(short, long)]
         game_optimized: bool,
@@ -49,11 +62,14 @@ enum Command {
     /// Connect to VPN server
     Connect,
     
-    /// Disconnect from VPN server
+    /// Disconnect the running client from the VPN server
     Disconnect,
     
     /// Get VPN status
     Status,
+    
+    /// Show traffic and connection statistics of the running client
+    Stats,
 }
 
 #[tokio::main]
@@ -61,8 +77,9 @@ async fn main() -> Result<()> {
     let args = Args::parse();
     
     match args.command {
-        Some(Command::Init { server, username, password, game_optimized }) => {
-            create_config(&args.config, server, username, password, game_optimized).await?;
+        Some(Command::Init { server, username, password, client_cert, client_key, game_optimized }) => {
+            let password = password.unwrap_or_default();
+            create_config(&args.config, server, username, password, client_cert, client_key, game_optimized).await?;
             println!("Configuration file created at: {}", args.config.display());
             return Ok(());
         },
@@ -82,13 +99,15 @@ async fn main() -> Result<()> {
         },
         
         Some(Command::Status) => {
-            println!("VPN status: Not implemented yet");
-            return Ok(());
+            return control_command(&args.config, ControlRequest::Status).await;
+        },
+        
+        Some(Command::Stats) => {
+            return control_command(&args.config, ControlRequest::Stats).await;
         },
         
         Some(Command::Disconnect) => {
-            println!("VPN disconnected");
-            return Ok(());
+            return control_command(&args.config, ControlRequest::Disconnect).await;
         },
         
         Some(Command::Connect) | None => {
@@ -119,27 +138,49 @@ async fn main() -> Result<()> {
     // Create VPN client
     let mut client = VpnClient::new(config.clone());
     
-    // Connect to server
-    match client.connect().await {
-        Ok(_) => {
-            info!("Connected to server: {}", config.server_addr);
-            
-            // Wait for client to disconnect
-            client.wait_for_disconnect().await?;
-        }
+    // Let `status`, `stats` and `disconnect` reach this client
+    let _control_socket = match control::serve(&config.control_socket_path(), client.handle()) {
+        Ok(guard) => Some(guard),
         Err(e) => {
-            error!("Failed to connect to server: {}", e);
+            warn!("Control socket unavailable: {}", e);
+            None
         }
+    };
+    
+    // Connect to server and stay connected until told to stop
+    if let Err(e) = client.run().await {
+        error!("VPN connection failed: {}", e);
+    }
+    
+    Ok(())
+}
+
+/// Send a request to the running client and print its reply
+async fn control_command(config_path: &Path, request: ControlRequest) -> Result<()> {
+    let config = ClientConfig::load(config_path.to_str().unwrap())?;
+    
+    match control::request(&config.control_socket_path(), request).await? {
+        ControlResponse::Status(status) => println!("{}", status),
+        ControlResponse::Stats(stats) => println!("{}", stats),
+        ControlResponse::Connecting => println!("VPN status: connecting"),
+        ControlResponse::Reconnecting { attempt, retry_in_secs } => println!(
+            "VPN status: reconnecting (attempt {}, next try in {}s)",
+            attempt, retry_in_secs
+        ),
+        ControlResponse::Disconnecting => println!("VPN disconnected"),
+        ControlResponse::Error { message } => bail!("VPN client refused the request: {}", message),
     }
     
     Ok(())
 }
 
 async fn create_config(
-    path: &PathBuf,
+    path: &Path,
     server: String,
     username: String,
     password: String,
+    client_cert_path: Option<PathBuf>,
+    client_key_path: Option<PathBuf>,
     game_optimized: bool,
 ) -> Result<()> {
     let server_addr = server.parse()?;
@@ -148,12 +189,21 @@ async fn create_config(
         server_addr,
         server_hostname: "quicvpn.server".to_string(),
         server_cert_path: None,
+        client_cert_path,
+        client_key_path,
+        server_fingerprint: None,
+        known_servers_path: None,
+        insecure_skip_verify: false,
         username,
         password,
         log_level: "info".to_string(),
         interface_name: None,
         gaming_optimization: game_optimized,
         game_type: if game_optimized { Some("default".to_string()) } else { None },
+        control_socket: None,
+        reconnect: ReconnectConfig::default(),
+        tunnel_mode: TunnelMode::default(),
+        stats_interval_secs: 30,
     };
     
     config.save(path.to_str().unwrap())?;
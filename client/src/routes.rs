@@ -0,0 +1,504 @@
+use anyhow::Result;
+use common::config::TunnelMode;
+use common::protocol::RouteInfo;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use tracing::{debug, info, warn};
+
+/// An entry in the kernel's main routing table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Route {
+    destination: IpAddr,
+    prefix_len: u8,
+    gateway: Option<IpAddr>,
+    /// Index of the interface the route leaves through
+    interface: u32,
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.destination, self.prefix_len)?;
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {}", gateway)?;
+        }
+        write!(f, " dev #{}", self.interface)
+    }
+}
+
+/// TUN interface the tunnel routes point at
+struct Tunnel {
+    name: String,
+    ipv6: bool,
+}
+
+/// Keeps the routes pushed by the server installed on the TUN interface.
+///
+/// Routes stay in place across reconnects so traffic does not leak out of
+/// the tunnel while it is down, and are removed by [`RouteManager::clear`].
+pub struct RouteManager {
+    mode: TunnelMode,
+    server_ip: IpAddr,
+    tunnel: Option<Tunnel>,
+    installed: Vec<Route>,
+    netlink: Option<netlink::Netlink>,
+}
+
+impl RouteManager {
+    pub fn new(mode: TunnelMode, server_ip: IpAddr) -> Self {
+        Self {
+            mode,
+            server_ip,
+            tunnel: None,
+            installed: Vec::new(),
+            netlink: None,
+        }
+    }
+
+    /// Point tunnel routes at the given TUN interface
+    pub fn attach(&mut self, interface: &str, ipv6: bool) {
+        self.tunnel = Some(Tunnel {
+            name: interface.to_string(),
+            ipv6,
+        });
+    }
+
+    /// Make the installed routes match the list last pushed by the server
+    pub async fn apply(&mut self, pushed: &[RouteInfo]) -> Result<()> {
+        let tunnel = match &self.tunnel {
+            Some(tunnel) => tunnel,
+            None => return Ok(()),
+        };
+
+        if self.netlink.is_none() {
+            self.netlink = Some(netlink::Netlink::new()?);
+        }
+        let netlink = self.netlink.as_ref().unwrap();
+        let tun_index = netlink.interface_index(&tunnel.name).await?;
+
+        let mut wanted = self.tunnel_routes(tunnel, pushed, tun_index);
+
+        // Keep reaching the server over the physical network
+        let server_in_tunnel = wanted.iter()
+            .any(|route| prefix_contains(route.destination, route.prefix_len, self.server_ip));
+        if server_in_tunnel {
+            match netlink.lookup(self.server_ip, tun_index).await? {
+                Some(route) => wanted.push(route),
+                None => warn!("No route to server {} outside the tunnel", self.server_ip),
+            }
+        }
+
+        // Remove routes the server no longer pushes, then add the new ones
+        for route in self.installed.iter().filter(|route| !wanted.contains(route)) {
+            match netlink.delete(route).await {
+                Ok(()) => info!("Removed route {}", route),
+                Err(e) => warn!("Failed to remove route {}: {}", route, e),
+            }
+        }
+        self.installed.retain(|route| wanted.contains(route));
+
+        for route in wanted {
+            if self.installed.contains(&route) {
+                continue;
+            }
+
+            match netlink.add(&route).await {
+                Ok(()) => {
+                    info!("Added route {}", route);
+                    self.installed.push(route);
+                }
+                Err(e) => warn!("Failed to add route {}: {}", route, e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Routes through the tunnel for the pushed list, with default routes
+    /// turned into two halves of the address space in full tunnel mode
+    fn tunnel_routes(&self, tunnel: &Tunnel, pushed: &[RouteInfo], tun_index: u32) -> Vec<Route> {
+        let mut wanted = Vec::new();
+        let mut full_v4 = self.mode == TunnelMode::Full;
+        let mut full_v6 = self.mode == TunnelMode::Full && tunnel.ipv6;
+
+        for route in pushed {
+            let prefix_len = match route.prefix_len() {
+                Some(prefix_len) => prefix_len,
+                None => {
+                    warn!("Ignoring route {} with invalid netmask {}", route.destination, route.netmask);
+                    continue;
+                }
+            };
+
+            if route.destination.is_ipv6() && !tunnel.ipv6 {
+                debug!("Ignoring IPv6 route {}/{}: tunnel has no IPv6 address", route.destination, prefix_len);
+                continue;
+            }
+
+            // Default routes switch to full tunnel instead of being installed as is
+            if prefix_len == 0 {
+                match (self.mode, route.destination) {
+                    (TunnelMode::Split, _) => info!("Not routing all traffic through the tunnel: split tunnel mode"),
+                    (_, IpAddr::V4(_)) => full_v4 = true,
+                    (_, IpAddr::V6(_)) => full_v6 = true,
+                }
+                continue;
+            }
+
+            wanted.push(Route {
+                destination: network_address(route.destination, prefix_len),
+                prefix_len,
+                gateway: route.gateway,
+                interface: tun_index,
+            });
+        }
+
+        // Two halves of the address space win over the existing default
+        // route without replacing it, so it is still there afterwards
+        let mut halves = Vec::new();
+        if full_v4 {
+            halves.push((IpAddr::V4(Ipv4Addr::UNSPECIFIED), 1));
+            halves.push((IpAddr::V4(Ipv4Addr::new(128, 0, 0, 0)), 1));
+        }
+        if full_v6 {
+            halves.push((IpAddr::V6(Ipv6Addr::UNSPECIFIED), 1));
+            halves.push((IpAddr::V6(Ipv6Addr::new(0x8000, 0, 0, 0, 0, 0, 0, 0)), 1));
+        }
+        wanted.extend(halves.into_iter().map(|(destination, prefix_len)| Route {
+            destination,
+            prefix_len,
+            gateway: None,
+            interface: tun_index,
+        }));
+
+        wanted
+    }
+
+    /// Remove every route this client installed
+    pub async fn clear(&mut self) {
+        let netlink = match &self.netlink {
+            Some(netlink) => netlink,
+            None => return,
+        };
+
+        for route in self.installed.drain(..) {
+            match netlink.delete(&route).await {
+                Ok(()) => debug!("Removed route {}", route),
+                Err(e) => debug!("Failed to remove route {}: {}", route, e),
+            }
+        }
+    }
+}
+
+/// Whether `ip` lies within `network/prefix_len`
+fn prefix_contains(network: IpAddr, prefix_len: u8, ip: IpAddr) -> bool {
+    let (network, ip, width) = match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => (u32::from(network) as u128, u32::from(ip) as u128, 32),
+        (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+        _ => return false,
+    };
+
+    let mask = prefix_mask(width, prefix_len);
+    network & mask == ip & mask
+}
+
+/// `ip` with the host bits below `prefix_len` cleared, as the kernel wants
+/// route destinations
+fn network_address(ip: IpAddr, prefix_len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => Ipv4Addr::from(u32::from(ip) & prefix_mask(32, prefix_len) as u32).into(),
+        IpAddr::V6(ip) => Ipv6Addr::from(u128::from(ip) & prefix_mask(128, prefix_len)).into(),
+    }
+}
+
+/// Mask of the top `prefix_len` bits of a `width`-bit address
+fn prefix_mask(width: u32, prefix_len: u8) -> u128 {
+    let mask = u128::MAX.checked_shl(width - prefix_len.min(width as u8) as u32).unwrap_or(0);
+    mask & (u128::MAX >> (128 - width))
+}
+
+#[cfg(target_os = "linux")]
+mod netlink {
+    use anyhow::{anyhow, Result};
+    use futures::TryStreamExt;
+    use netlink_packet_route::nlas::route::Nla;
+    use netlink_packet_route::{
+        RouteMessage, AF_INET, AF_INET6, RTN_UNICAST, RTPROT_STATIC, RT_SCOPE_UNIVERSE, RT_TABLE_MAIN,
+    };
+    use rtnetlink::{Handle, IpVersion};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::{prefix_contains, Route};
+
+    /// Route table access over an rtnetlink socket
+    pub struct Netlink {
+        handle: Handle,
+    }
+
+    impl Netlink {
+        pub fn new() -> Result<Self> {
+            let (connection, handle, _) = rtnetlink::new_connection()?;
+            tokio::spawn(connection);
+
+            Ok(Self { handle })
+        }
+
+        pub async fn interface_index(&self, name: &str) -> Result<u32> {
+            let link = self.handle.link().get().match_name(name.to_string()).execute()
+                .try_next()
+                .await?
+                .ok_or_else(|| anyhow!("Interface {} not found", name))?;
+
+            Ok(link.header.index)
+        }
+
+        pub async fn add(&self, route: &Route) -> Result<()> {
+            let mut request = self.handle.route().add();
+            *request.message_mut() = route_message(route);
+
+            Ok(request.execute().await?)
+        }
+
+        pub async fn delete(&self, route: &Route) -> Result<()> {
+            Ok(self.handle.route().del(route_message(route)).execute().await?)
+        }
+
+        /// Host route to `destination` along the path the main table uses
+        /// today, ignoring routes through `skip_interface`
+        pub async fn lookup(&self, destination: IpAddr, skip_interface: u32) -> Result<Option<Route>> {
+            let (version, host_prefix_len, unspecified) = match destination {
+                IpAddr::V4(_) => (IpVersion::V4, 32, IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+                IpAddr::V6(_) => (IpVersion::V6, 128, IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+            };
+
+            let mut routes = self.handle.route().get(version).execute();
+            let mut best: Option<(u8, u32, Route)> = None;
+
+            while let Some(message) = routes.try_next().await? {
+                if message.header.table != RT_TABLE_MAIN || message.header.kind != RTN_UNICAST {
+                    continue;
+                }
+
+                let mut network = unspecified;
+                let mut gateway = None;
+                let mut interface = None;
+                let mut priority = 0;
+                for nla in &message.nlas {
+                    match nla {
+                        Nla::Destination(bytes) => network = parse_ip(bytes).unwrap_or(unspecified),
+                        Nla::Gateway(bytes) => gateway = parse_ip(bytes),
+                        Nla::Oif(index) => interface = Some(*index),
+                        Nla::Priority(value) => priority = *value,
+                        _ => {}
+                    }
+                }
+
+                let interface = match interface {
+                    Some(interface) if interface != skip_interface => interface,
+                    _ => continue,
+                };
+
+                let prefix_len = message.header.destination_prefix_length;
+                if !prefix_contains(network, prefix_len, destination) {
+                    continue;
+                }
+
+                // Longest prefix wins, then the lowest metric
+                let better = match &best {
+                    Some((best_len, best_priority, _)) => {
+                        prefix_len > *best_len || (prefix_len == *best_len && priority < *best_priority)
+                    }
+                    None => true,
+                };
+                if better {
+                    best = Some((prefix_len, priority, Route {
+                        destination,
+                        prefix_len: host_prefix_len,
+                        gateway,
+                        interface,
+                    }));
+                }
+            }
+
+            Ok(best.map(|(_, _, route)| route))
+        }
+    }
+
+    fn route_message(route: &Route) -> RouteMessage {
+        let mut message = RouteMessage::default();
+
+        message.header.address_family = match route.destination {
+            IpAddr::V4(_) => AF_INET as u8,
+            IpAddr::V6(_) => AF_INET6 as u8,
+        };
+        message.header.destination_prefix_length = route.prefix_len;
+        message.header.table = RT_TABLE_MAIN;
+        message.header.protocol = RTPROT_STATIC;
+        message.header.scope = RT_SCOPE_UNIVERSE;
+        message.header.kind = RTN_UNICAST;
+
+        message.nlas.push(Nla::Destination(ip_octets(route.destination)));
+        if let Some(gateway) = route.gateway {
+            message.nlas.push(Nla::Gateway(ip_octets(gateway)));
+        }
+        message.nlas.push(Nla::Oif(route.interface));
+
+        message
+    }
+
+    fn ip_octets(ip: IpAddr) -> Vec<u8> {
+        match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        }
+    }
+
+    fn parse_ip(bytes: &[u8]) -> Option<IpAddr> {
+        match bytes.len() {
+            4 => Some(IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))),
+            16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(bytes);
+                Some(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod netlink {
+    use anyhow::{anyhow, Result};
+    use std::net::IpAddr;
+
+    use super::Route;
+
+    /// Stand-in for platforms without route support
+    pub struct Netlink;
+
+    impl Netlink {
+        pub fn new() -> Result<Self> {
+            Err(unsupported())
+        }
+
+        pub async fn interface_index(&self, _name: &str) -> Result<u32> {
+            Err(unsupported())
+        }
+
+        pub async fn add(&self, _route: &Route) -> Result<()> {
+            Err(unsupported())
+        }
+
+        pub async fn delete(&self, _route: &Route) -> Result<()> {
+            Err(unsupported())
+        }
+
+        pub async fn lookup(&self, _destination: IpAddr, _skip_interface: u32) -> Result<Option<Route>> {
+            Err(unsupported())
+        }
+    }
+
+    fn unsupported() -> anyhow::Error {
+        anyhow!("Installing routes is only supported on Linux")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    fn route(destination: &str, netmask: &str) -> RouteInfo {
+        RouteInfo {
+            destination: ip(destination),
+            netmask: ip(netmask),
+            gateway: None,
+        }
+    }
+
+    fn planned(mode: TunnelMode, ipv6: bool, pushed: &[RouteInfo]) -> Vec<(IpAddr, u8)> {
+        let manager = RouteManager::new(mode, ip("203.0.113.1"));
+        let tunnel = Tunnel { name: "tun0".to_string(), ipv6 };
+
+        manager.tunnel_routes(&tunnel, pushed, 7).into_iter()
+            .inspect(|route| assert_eq!(route.interface, 7))
+            .map(|route| (route.destination, route.prefix_len))
+            .collect()
+    }
+
+    #[test]
+    fn prefix_contains_matches_within_the_prefix() {
+        assert!(prefix_contains(ip("10.0.0.0"), 8, ip("10.255.1.2")));
+        assert!(!prefix_contains(ip("10.0.0.0"), 8, ip("11.0.0.1")));
+        assert!(prefix_contains(ip("192.168.1.0"), 24, ip("192.168.1.255")));
+        assert!(!prefix_contains(ip("192.168.1.0"), 25, ip("192.168.1.128")));
+        assert!(prefix_contains(ip("203.0.113.1"), 32, ip("203.0.113.1")));
+        assert!(!prefix_contains(ip("203.0.113.1"), 32, ip("203.0.113.2")));
+
+        assert!(prefix_contains(ip("0.0.0.0"), 0, ip("255.255.255.255")));
+        assert!(prefix_contains(ip("128.0.0.0"), 1, ip("203.0.113.1")));
+        assert!(!prefix_contains(ip("0.0.0.0"), 1, ip("203.0.113.1")));
+
+        assert!(prefix_contains(ip("fd00::"), 64, ip("fd00::1234")));
+        assert!(!prefix_contains(ip("fd00::"), 64, ip("fd00:0:0:1::1")));
+        assert!(prefix_contains(ip("::"), 0, ip("2001:db8::1")));
+
+        // Families never contain each other
+        assert!(!prefix_contains(ip("0.0.0.0"), 0, ip("::1")));
+        assert!(!prefix_contains(ip("::"), 0, ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn full_tunnel_is_split_into_halves() {
+        let halves = vec![(ip("0.0.0.0"), 1), (ip("128.0.0.0"), 1)];
+        assert_eq!(planned(TunnelMode::Full, false, &[]), halves);
+        assert_eq!(planned(TunnelMode::Auto, false, &[route("0.0.0.0", "0.0.0.0")]), halves);
+
+        assert_eq!(
+            planned(TunnelMode::Auto, true, &[route("::", "::")]),
+            vec![(ip("::"), 1), (ip("8000::"), 1)]
+        );
+    }
+
+    #[test]
+    fn split_tunnel_keeps_only_specific_routes() {
+        let pushed = [route("0.0.0.0", "0.0.0.0"), route("10.1.0.0", "255.255.0.0")];
+
+        assert_eq!(planned(TunnelMode::Split, false, &pushed), vec![(ip("10.1.0.0"), 16)]);
+        assert_eq!(
+            planned(TunnelMode::Auto, false, &pushed),
+            vec![(ip("10.1.0.0"), 16), (ip("0.0.0.0"), 1), (ip("128.0.0.0"), 1)]
+        );
+    }
+
+    #[test]
+    fn host_bits_are_cleared_from_destinations() {
+        let pushed = [
+            route("10.1.2.3", "255.255.0.0"),
+            route("192.168.1.77", "255.255.255.255"),
+            route("fd00::1:2", "ffff:ffff:ffff:ffff::"),
+        ];
+
+        assert_eq!(
+            planned(TunnelMode::Auto, true, &pushed),
+            vec![(ip("10.1.0.0"), 16), (ip("192.168.1.77"), 32), (ip("fd00::"), 64)]
+        );
+
+        assert_eq!(network_address(ip("255.255.255.255"), 0), ip("0.0.0.0"));
+        assert_eq!(network_address(ip("203.0.113.129"), 25), ip("203.0.113.128"));
+        assert_eq!(network_address(ip("2001:db8::ffff"), 127), ip("2001:db8::fffe"));
+    }
+
+    #[test]
+    fn skips_invalid_and_unusable_routes() {
+        let pushed = [
+            route("10.1.0.0", "255.0.255.0"),
+            route("fd00::", "ffff:ffff:ffff:ffff::"),
+            route("10.2.0.0", "255.255.0.0"),
+        ];
+
+        assert_eq!(planned(TunnelMode::Auto, false, &pushed), vec![(ip("10.2.0.0"), 16)]);
+    }
+}
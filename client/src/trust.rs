@@ -0,0 +1,226 @@
+use anyhow::{anyhow, bail, Result};
+use common::crypto;
+use common::storage::write_atomic;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, Error, ServerName};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::warn;
+
+/// Accepts a server by the SHA-256 fingerprint of its public key, either
+/// pinned in the config or remembered on first use.
+///
+/// The certificate's name and validity are not checked, but rustls still
+/// verifies that the server holds the matching private key.
+pub struct FingerprintVerification {
+    /// Key of the server in the known-servers file
+    server: String,
+    trust: Trust,
+}
+
+enum Trust {
+    Pinned(String),
+    /// Trust on first use, remembered in this file
+    KnownServers(PathBuf),
+}
+
+/// Fingerprints of servers trusted on first use, keyed by address
+#[derive(Default, Serialize, Deserialize)]
+struct KnownServers {
+    servers: BTreeMap<String, String>,
+}
+
+impl FingerprintVerification {
+    pub fn pinned(server: String, fingerprint: &str) -> Result<Self> {
+        Ok(Self {
+            server,
+            trust: Trust::Pinned(normalize_fingerprint(fingerprint)?),
+        })
+    }
+
+    pub fn known_servers(server: String, path: PathBuf) -> Self {
+        Self {
+            server,
+            trust: Trust::KnownServers(path),
+        }
+    }
+
+    fn check(&self, fingerprint: &str) -> Result<()> {
+        let path = match &self.trust {
+            Trust::Pinned(pinned) if pinned == fingerprint => return Ok(()),
+            Trust::Pinned(pinned) => bail!(
+                "Server key fingerprint {} does not match the pinned {}",
+                fingerprint, pinned
+            ),
+            Trust::KnownServers(path) => path,
+        };
+
+        let mut known = KnownServers::load(path)?;
+        match known.servers.get(&self.server) {
+            Some(recorded) if recorded == fingerprint => Ok(()),
+            Some(recorded) => bail!(
+                "Server key fingerprint {} differs from {} recorded in {}; \
+                 if the server's key was changed on purpose, remove its entry",
+                fingerprint, recorded, path.display()
+            ),
+            None => {
+                warn!("Trusting {} on first use, key fingerprint {}", self.server, fingerprint);
+                known.servers.insert(self.server.clone(), fingerprint.to_string());
+                known.save(path)
+            }
+        }
+    }
+}
+
+impl ServerCertVerifier for FingerprintVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let fingerprint = crypto::spki_fingerprint(&end_entity.0)
+            .map_err(|e| Error::General(e.to_string()))?;
+
+        self.check(&fingerprint)
+            .map(|_| ServerCertVerified::assertion())
+            .map_err(|e| Error::General(e.to_string()))
+    }
+}
+
+impl KnownServers {
+    fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| anyhow!("Invalid known servers file {}: {}", path.display(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(anyhow!("Failed to read {}: {}", path.display(), e)),
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        write_atomic(path, serde_json::to_string_pretty(self)?.as_bytes())
+            .map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e))
+    }
+}
+
+/// Lowercase hex without separators, accepting the colon-separated form
+/// other tools print
+fn normalize_fingerprint(fingerprint: &str) -> Result<String> {
+    let normalized: String = fingerprint.trim()
+        .chars()
+        .filter(|&c| c != ':')
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    if normalized.len() != 64 || !normalized.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Invalid server_fingerprint {:?}, expected a SHA-256 hash in hex", fingerprint);
+    }
+
+    Ok(normalized)
+}
+
+/// Accepts any server; only reachable through `insecure_skip_verify`
+pub struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FINGERPRINT: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn known_servers_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("quicvpn-known-{}-{}", std::process::id(), name));
+        let path = dir.join("known_servers.json");
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn normalizes_colon_separated_and_uppercase_fingerprints() {
+        let colons = FINGERPRINT.to_uppercase().as_bytes()
+            .chunks(2)
+            .map(|pair| std::str::from_utf8(pair).unwrap())
+            .collect::<Vec<_>>()
+            .join(":");
+
+        assert_eq!(normalize_fingerprint(&colons).unwrap(), FINGERPRINT);
+        assert_eq!(normalize_fingerprint(&format!("  {}\n", FINGERPRINT)).unwrap(), FINGERPRINT);
+    }
+
+    #[test]
+    fn rejects_malformed_fingerprints() {
+        assert!(normalize_fingerprint("").is_err());
+        assert!(normalize_fingerprint(&FINGERPRINT[1..]).is_err());
+        assert!(normalize_fingerprint(&format!("{}00", FINGERPRINT)).is_err());
+        assert!(normalize_fingerprint(&FINGERPRINT.replace('a', "g")).is_err());
+    }
+
+    #[test]
+    fn pinned_fingerprint_must_match() {
+        let verification = FingerprintVerification::pinned("vpn.example:4433".to_string(), FINGERPRINT).unwrap();
+
+        assert!(verification.check(FINGERPRINT).is_ok());
+        assert!(verification.check(&FINGERPRINT.replace('0', "1")).is_err());
+    }
+
+    #[test]
+    fn trusts_on_first_use_then_rejects_a_changed_key() {
+        let path = known_servers_path("tofu");
+        let verification = FingerprintVerification::known_servers("vpn.example:4433".to_string(), path.clone());
+
+        // First sighting is recorded
+        verification.check(FINGERPRINT).unwrap();
+        assert_eq!(KnownServers::load(&path).unwrap().servers["vpn.example:4433"], FINGERPRINT);
+
+        // The same key is accepted again, a different one is refused
+        verification.check(FINGERPRINT).unwrap();
+        let other = FINGERPRINT.replace('0', "1");
+        let message = verification.check(&other).unwrap_err().to_string();
+        assert!(message.contains("differs"), "{}", message);
+        assert_eq!(KnownServers::load(&path).unwrap().servers["vpn.example:4433"], FINGERPRINT);
+
+        // Other servers are trusted on their own
+        let second = FingerprintVerification::known_servers("other.example:4433".to_string(), path.clone());
+        second.check(&other).unwrap();
+        assert_eq!(KnownServers::load(&path).unwrap().servers.len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn known_servers_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = known_servers_path("mode");
+        FingerprintVerification::known_servers("vpn.example:4433".to_string(), path.clone())
+            .check(FINGERPRINT)
+            .unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!path.with_file_name("known_servers.json.tmp").exists());
+    }
+}
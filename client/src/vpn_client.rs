@@ -1,30 +1,350 @@
-use anyhow::Result;
-use common::config::ClientConfig;
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use common::config::{ClientConfig, ReconnectConfig};
 use common::crypto;
-use common::protocol::Message;
+use common::datagram::DatagramSender;
+use common::protocol::{self, Capabilities, Message};
+use common::stats::{PeerStats, SessionStats};
 use common::tun_device::TunDevice;
 use quinn::{ClientConfig as QuinnClientConfig, Connection, Endpoint, RecvStream, SendStream};
-use std::net::IpAddr;
+use rand::Rng;
+use rustls::client::ServerCertVerifier;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use tokio::fs;
-use tokio::sync::{mpsc, oneshot};
+use std::time::Instant;
+use tokio::sync::{mpsc, Mutex as AsyncMutex, Notify};
 use tokio::time::{self, Duration};
 use tracing::{debug, error, info, warn};
 
+use crate::control::{StatsReport, StatusReport};
+use crate::routes::RouteManager;
+use crate::trust::{FingerprintVerification, NoCertificateVerification};
+
+/// Protocol features this client can use
+#[cfg(target_os = "linux")]
+const CLIENT_CAPABILITIES: Capabilities = Capabilities::DATAGRAMS
+    .union(Capabilities::RESUMPTION)
+    .union(Capabilities::STATS)
+    .union(Capabilities::RECONNECT_HINT)
+    .union(Capabilities::IPV6)
+    .union(Capabilities::ROUTE_PUSH);
+#[cfg(not(target_os = "linux"))]
+const CLIENT_CAPABILITIES: Capabilities = Capabilities::DATAGRAMS
+    .union(Capabilities::RESUMPTION)
+    .union(Capabilities::STATS)
+    .union(Capabilities::RECONNECT_HINT);
+
+/// Size of the QUIC datagram send and receive buffers
+const DATAGRAM_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// How long a disconnect waits for the server to acknowledge the goodbye
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
 pub struct VpnClient {
     config: ClientConfig,
-    connection: Option<Connection>,
     tun_device: Option<Arc<TunDevice>>,
-    disconnect_tx: Option<oneshot::Sender<()>>,
+    /// Addressing the TUN device was created with, to tell whether a new
+    /// session can keep using it
+    tun_addresses: Option<TunAddresses>,
+    session: Option<SessionHandle>,
+    /// Token from the last `ServerHello`, presented when reconnecting
+    resumption_token: Option<Bytes>,
+    /// Routes installed on the TUN device, kept across reconnects
+    routes: Arc<AsyncMutex<RouteManager>>,
+    handle: ClientHandle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TunAddresses {
+    ip: IpAddr,
+    netmask: IpAddr,
+    mtu: u16,
+    ipv6: Option<(Ipv6Addr, u8)>,
+}
+
+/// Connection state shared with the control socket; unlike a
+/// [`SessionHandle`] it survives reconnects
+#[derive(Clone, Default)]
+pub struct ClientHandle {
+    inner: Arc<HandleInner>,
+}
+
+#[derive(Default)]
+struct HandleInner {
+    state: Mutex<ConnectionState>,
+    stopping: AtomicBool,
+    stop: Notify,
+}
+
+#[derive(Clone, Default)]
+pub enum ConnectionState {
+    #[default]
+    Connecting,
+    Connected(SessionHandle),
+    Reconnecting {
+        attempt: u32,
+        retry_at: Instant,
+    },
+}
+
+impl ClientHandle {
+    pub fn state(&self) -> ConnectionState {
+        self.inner.state.lock().unwrap().clone()
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        *self.inner.state.lock().unwrap() = state;
+    }
+
+    /// Publish a newly connected session. Returns `false`, leaving the state
+    /// alone, if a disconnect was requested while the session was set up.
+    fn set_connected(&self, session: SessionHandle) -> bool {
+        let mut state = self.inner.state.lock().unwrap();
+        if self.is_stopping() {
+            return false;
+        }
+
+        *state = ConnectionState::Connected(session);
+        true
+    }
+
+    /// Disconnect from the server and stop reconnecting
+    pub async fn disconnect(&self) {
+        // Under the state lock, so a session being published either sees
+        // the stop or is disconnected here
+        let state = {
+            let state = self.inner.state.lock().unwrap();
+            self.inner.stopping.store(true, Ordering::SeqCst);
+            state.clone()
+        };
+        self.inner.stop.notify_one();
+
+        if let ConnectionState::Connected(session) = state {
+            session.disconnect("Client requested disconnect").await;
+        }
+    }
+
+    fn is_stopping(&self) -> bool {
+        self.inner.stopping.load(Ordering::SeqCst)
+    }
+
+    async fn stopped(&self) {
+        if !self.is_stopping() {
+            self.inner.stop.notified().await;
+        }
+    }
+}
+
+/// Cheaply clonable handle to a live session, used by the control socket
+#[derive(Clone)]
+pub struct SessionHandle {
+    connection: Connection,
+    control_tx: mpsc::Sender<Message>,
+    datagrams: Arc<DatagramSender>,
+    shared: Arc<SessionShared>,
+    info: Arc<SessionInfo>,
+}
+
+/// Session state updated by the packet tasks
+struct SessionShared {
+    stats: SessionStats,
+    /// Reason given by the server if it ended the session
+    server_reason: Mutex<Option<String>>,
+    /// How long the server asked us to wait before coming back, if it did
+    reconnect_after: Mutex<Option<Duration>>,
+    routes: Arc<AsyncMutex<RouteManager>>,
+}
+
+/// Details of a session fixed by the handshake
+struct SessionInfo {
+    server_addr: SocketAddr,
+    interface: String,
+    assigned_ip: IpAddr,
+    assigned_ipv6: Option<Ipv6Addr>,
+    protocol_version: u16,
+    capabilities: Capabilities,
+    connected_at: Instant,
+}
+
+impl SessionHandle {
+    pub fn status(&self) -> StatusReport {
+        StatusReport {
+            server_addr: self.info.server_addr,
+            interface: self.info.interface.clone(),
+            assigned_ip: self.info.assigned_ip,
+            assigned_ipv6: self.info.assigned_ipv6,
+            protocol_version: self.info.protocol_version,
+            capabilities: self.info.capabilities.to_string(),
+            datagrams: self.datagrams.is_enabled(),
+            uptime_secs: self.info.connected_at.elapsed().as_secs(),
+            rtt_ms: self.connection.rtt().as_millis() as u64,
+            traffic: self.shared.stats.traffic.snapshot(),
+            server: self.shared.stats.peer(),
+        }
+    }
+
+    pub fn stats(&self) -> StatsReport {
+        let traffic = self.shared.stats.traffic.snapshot();
+        let quic = self.connection.stats();
+
+        StatsReport {
+            bytes_sent: traffic.bytes_sent,
+            bytes_received: traffic.bytes_received,
+            packets_sent: traffic.packets_sent,
+            packets_received: traffic.packets_received,
+            rtt_ms: quic.path.rtt.as_millis() as u64,
+            lost_packets: quic.path.lost_packets,
+            congestion_window: quic.path.cwnd,
+            udp_bytes_sent: quic.udp_tx.bytes,
+            udp_bytes_received: quic.udp_rx.bytes,
+            server: self.shared.stats.peer(),
+        }
+    }
+
+    /// Say goodbye to the server and close the connection
+    pub async fn disconnect(&self, reason: &str) {
+        let _ = self.control_tx.send(Message::disconnect(reason)).await;
+
+        // The control writer closes the connection once the goodbye is delivered
+        if time::timeout(DISCONNECT_TIMEOUT, self.connection.closed()).await.is_err() {
+            self.connection.close(0u32.into(), b"Client disconnected");
+        }
+    }
+
+    pub async fn closed(&self) {
+        self.connection.closed().await;
+    }
+
+    /// Reason the server gave for ending the session, if it did
+    pub fn server_reason(&self) -> Option<String> {
+        self.shared.server_reason.lock().unwrap().clone()
+    }
+
+    /// Delay the server asked for before reconnecting, e.g. while it restarts
+    pub fn reconnect_after(&self) -> Option<Duration> {
+        *self.shared.reconnect_after.lock().unwrap()
+    }
 }
 
 impl VpnClient {
     pub fn new(config: ClientConfig) -> Self {
+        let routes = RouteManager::new(config.tunnel_mode, config.server_addr.ip());
+        
         Self {
             config,
-            connection: None,
             tun_device: None,
-            disconnect_tx: None,
+            tun_addresses: None,
+            session: None,
+            resumption_token: None,
+            routes: Arc::new(AsyncMutex::new(routes)),
+            handle: ClientHandle::default(),
+        }
+    }
+
+    /// Handle for observing and controlling the client from other tasks
+    pub fn handle(&self) -> ClientHandle {
+        self.handle.clone()
+    }
+
+    /// Connect and keep the tunnel up, reconnecting whenever the connection
+    /// drops, until the user disconnects or the server ends the session.
+    ///
+    /// The TUN device and its routes stay up while reconnecting, so
+    /// applications only see a pause in traffic.
+    pub async fn run(&mut self) -> Result<()> {
+        let result = self.keep_connected().await;
+        
+        self.routes.lock().await.clear().await;
+        
+        result
+    }
+
+    async fn keep_connected(&mut self) -> Result<()> {
+        self.connect().await?;
+        
+        let mut backoff = Backoff::new(&self.config.reconnect);
+        
+        loop {
+            if self.handle.is_stopping() {
+                self.close_session().await;
+                break;
+            }
+            
+            tokio::select! {
+                result = self.wait_for_disconnect() => result?,
+                _ = self.handle.stopped() => {
+                    self.close_session().await;
+                    break;
+                }
+            }
+            
+            if self.handle.is_stopping() {
+                break;
+            }
+            
+            let server_reason = self.session.as_ref().and_then(|session| session.server_reason());
+            let reconnect_after = self.session.as_ref().and_then(|session| session.reconnect_after());
+            
+            match (server_reason, reconnect_after) {
+                // A server that is restarting says when to come back
+                (Some(reason), Some(delay)) if self.config.reconnect.enabled => {
+                    info!("Server ended the session: {}; reconnecting in {} s", reason, delay.as_secs());
+                    self.handle.set_state(ConnectionState::Reconnecting {
+                        attempt: 0,
+                        retry_at: Instant::now() + delay,
+                    });
+                    
+                    tokio::select! {
+                        _ = time::sleep(delay) => {}
+                        _ = self.handle.stopped() => return Ok(()),
+                    }
+                }
+                (Some(reason), _) => {
+                    info!("Server ended the session: {}", reason);
+                    break;
+                }
+                (None, _) if !self.config.reconnect.enabled => {
+                    warn!("Connection to server lost");
+                    break;
+                }
+                (None, _) => warn!("Connection to server lost, reconnecting"),
+            }
+            
+            loop {
+                let delay = backoff.next_delay().ok_or_else(|| {
+                    anyhow!("Giving up after {} reconnection attempts", backoff.attempt)
+                })?;
+                
+                self.handle.set_state(ConnectionState::Reconnecting {
+                    attempt: backoff.attempt,
+                    retry_at: Instant::now() + delay,
+                });
+                info!("Reconnecting in {} ms (attempt {})", delay.as_millis(), backoff.attempt);
+                
+                tokio::select! {
+                    _ = time::sleep(delay) => {}
+                    _ = self.handle.stopped() => return Ok(()),
+                }
+                
+                match self.connect().await {
+                    Ok(()) => {
+                        backoff.reset();
+                        break;
+                    }
+                    Err(e) => warn!("Reconnection attempt {} failed: {}", backoff.attempt, e),
+                }
+            }
+        }
+        
+        Ok(())
+    }
+
+    /// Say goodbye on the current session, if there is one, for disconnects
+    /// requested before the control socket could see the session
+    async fn close_session(&self) {
+        if let Some(session) = &self.session {
+            session.disconnect("Client requested disconnect").await;
         }
     }
 
@@ -34,25 +354,27 @@ impl VpnClient {
         let mut client_config = QuinnClientConfig::new(Arc::new(client_crypto));
         
         // Configure transport for gaming optimizations
-        client_config.transport = Arc::new({
+        client_config.transport_config(Arc::new({
             let mut transport_config = quinn::TransportConfig::default();
             
             // Optimize for gaming - reduce latency
             transport_config.max_idle_timeout(Some(std::time::Duration::from_secs(30).try_into()?));
             transport_config.keep_alive_interval(Some(std::time::Duration::from_secs(5)));
             
+            // Keep datagrams enabled so packets can bypass stream retransmission
+            transport_config.datagram_receive_buffer_size(Some(DATAGRAM_BUFFER_SIZE));
+            transport_config.datagram_send_buffer_size(DATAGRAM_BUFFER_SIZE);
+            
             if self.config.gaming_optimization {
-                // Higher priority for real-time data
-                transport_config.datagram_receive_buffer_size(None);
                 // Faster recovery from packet loss
                 transport_config.initial_rtt(std::time::Duration::from_millis(100));
             }
             
             transport_config
-        });
+        }));
         
         // Create endpoint
-        let endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
+        let mut endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
         endpoint.set_default_client_config(client_config);
         
         // Connect to server
@@ -72,6 +394,9 @@ impl VpnClient {
                 username: self.config.username.clone(),
                 password: self.config.password.clone(),
                 client_version: env!("CARGO_PKG_VERSION").to_string(),
+                protocol_version: protocol::PROTOCOL_VERSION,
+                capabilities: CLIENT_CAPABILITIES,
+                resumption_token: self.resumption_token.clone(),
             },
         ).await?;
         
@@ -79,47 +404,133 @@ impl VpnClient {
         let server_hello = self.receive_message(&mut recv).await?;
         
         match server_hello {
-            Message::ServerHello { server_version, assigned_ip, subnet_mask, mtu } => {
+            Message::ServerHello {
+                server_version,
+                protocol_version,
+                capabilities,
+                assigned_ip,
+                subnet_mask,
+                mtu,
+                assigned_ipv6,
+                resumption_token,
+            } => {
                 info!("Connected to server version {}", server_version);
+                
+                // The server picks the version; make sure we can still speak it
+                if protocol::negotiate_version(protocol_version) != Some(protocol_version) {
+                    let reason = format!(
+                        "Unsupported protocol version {} (client supports {}-{})",
+                        protocol_version,
+                        protocol::MIN_PROTOCOL_VERSION,
+                        protocol::PROTOCOL_VERSION
+                    );
+                    let _ = self.send_message(&mut send, &Message::disconnect(reason.clone())).await;
+                    connection.close(0u32.into(), b"Unsupported protocol version");
+                    return Err(anyhow::anyhow!(reason));
+                }
+                
+                let capabilities = capabilities.intersection(CLIENT_CAPABILITIES);
+                info!("Negotiated protocol {} (capabilities: {})", protocol_version, capabilities);
                 info!("Assigned IP: {}", assigned_ip);
                 
-                // Create TUN device
-                let tun_device = TunDevice::new(
-                    self.config.interface_name.as_deref(),
-                    assigned_ip,
-                    subnet_mask,
+                self.resumption_token = resumption_token;
+                
+                // Keep the TUN device when the server gave us the same addresses
+                let addresses = TunAddresses {
+                    ip: assigned_ip,
+                    netmask: subnet_mask,
                     mtu,
-                )?;
+                    ipv6: assigned_ipv6,
+                };
+                let tun_device = match &self.tun_device {
+                    Some(tun_device) if self.tun_addresses == Some(addresses) => tun_device.clone(),
+                    _ => {
+                        if self.tun_device.take().is_some() {
+                            warn!("Server assigned different addresses, recreating the TUN device");
+                            self.routes.lock().await.clear().await;
+                        }
+                        
+                        Arc::new(self.create_tun_device(addresses)?)
+                    }
+                };
+                self.tun_addresses = Some(addresses);
+                
+                // Servers that push routes send them right after the hello;
+                // otherwise only the tunnel mode decides what to install
+                {
+                    let mut routes = self.routes.lock().await;
+                    routes.attach(tun_device.name(), assigned_ipv6.is_some());
+                    
+                    if !capabilities.contains(Capabilities::ROUTE_PUSH) {
+                        if let Err(e) = routes.apply(&[]).await {
+                            warn!("Failed to set up routes: {}", e);
+                        }
+                    }
+                }
+                
+                // Prefer unreliable datagrams for packet data, falling back to the
+                // control stream when the server or path does not support them
+                let datagrams = Arc::new(DatagramSender::new(
+                    connection.clone(),
+                    capabilities.contains(Capabilities::DATAGRAMS),
+                ));
+                match datagrams.max_size() {
+                    Some(max_size) => info!("Using QUIC datagrams for packet data (max {} bytes)", max_size),
+                    None => warn!("QUIC datagrams unavailable, sending packets over the control stream"),
+                }
                 
                 // Start packet handling
-                let (disconnect_tx, disconnect_rx) = oneshot::channel();
-                self.start_packet_handling(
+                let shared = Arc::new(SessionShared {
+                    stats: SessionStats::new(),
+                    server_reason: Mutex::new(None),
+                    reconnect_after: Mutex::new(None),
+                    routes: self.routes.clone(),
+                });
+                let control_tx = self.start_packet_handling(
                     connection.clone(),
-                    Arc::new(tun_device.clone()),
+                    tun_device.clone(),
+                    datagrams.clone(),
+                    shared.clone(),
                     send,
                     recv,
-                    disconnect_rx,
                 ).await?;
                 
+                // Report traffic counters so the server can compare them with its own
+                if capabilities.contains(Capabilities::STATS) && self.config.stats_interval_secs > 0 {
+                    self.start_stats_reporting(connection.clone(), control_tx.clone(), shared.clone());
+                }
+                
                 // Send game optimization information if enabled
                 if self.config.gaming_optimization {
-                    let (mut send, _) = connection.open_bi().await?;
-                    
-                    self.send_message(
-                        &mut send,
-                        &Message::GameOptimizationInfo {
-                            game_type: self.config.game_type.clone().unwrap_or_else(|| "default".to_string()),
-                            latency_priority: true,
-                        },
-                    ).await?;
+                    control_tx.send(Message::GameOptimizationInfo {
+                        game_type: self.config.game_type.clone().unwrap_or_else(|| "default".to_string()),
+                        latency_priority: true,
+                    }).await?;
                 }
                 
-                // Store connection and TUN device
-                self.connection = Some(connection);
-                self.tun_device = Some(Arc::new(tun_device));
-                self.disconnect_tx = Some(disconnect_tx);
+                // Store the session and TUN device
+                let session = SessionHandle {
+                    connection,
+                    control_tx,
+                    datagrams,
+                    shared,
+                    info: Arc::new(SessionInfo {
+                        server_addr: self.config.server_addr,
+                        interface: tun_device.name().to_string(),
+                        assigned_ip,
+                        assigned_ipv6: assigned_ipv6.map(|(ip, _)| ip),
+                        protocol_version,
+                        capabilities,
+                        connected_at: Instant::now(),
+                    }),
+                };
+                if !self.handle.set_connected(session.clone()) {
+                    debug!("Disconnect requested while connecting");
+                }
+                self.session = Some(session);
+                self.tun_device = Some(tun_device);
             }
-            Message::Disconnect { reason } => {
+            Message::Disconnect { reason, .. } => {
                 error!("Server rejected connection: {}", reason);
                 return Err(anyhow::anyhow!("Server rejected connection: {}", reason));
             }
@@ -132,68 +543,65 @@ impl VpnClient {
         Ok(())
     }
 
-    pub async fn disconnect(&mut self) -> Result<()> {
-        if let Some(connection) = &self.connection {
-            // Send disconnect message
-            if let Ok((mut send, _)) = connection.open_bi().await {
-                let _ = self.send_message(
-                    &mut send,
-                    &Message::Disconnect {
-                        reason: "Client requested disconnect".to_string(),
-                    },
-                ).await;
-            }
-            
-            // Signal all tasks to stop
-            if let Some(disconnect_tx) = self.disconnect_tx.take() {
-                let _ = disconnect_tx.send(());
-            }
-            
-            // Close connection
-            connection.close(0u32.into(), b"Client disconnected");
-            
-            // Clear state
-            self.connection = None;
-            self.tun_device = None;
+    pub async fn wait_for_disconnect(&self) -> Result<()> {
+        if let Some(session) = &self.session {
+            session.closed().await;
         }
         
         Ok(())
     }
 
-    pub async fn wait_for_disconnect(&self) -> Result<()> {
-        if let Some(connection) = &self.connection {
-            connection.closed().await;
+    fn create_tun_device(&self, addresses: TunAddresses) -> Result<TunDevice> {
+        let tun_device = TunDevice::new(
+            self.config.interface_name.as_deref(),
+            addresses.ip,
+            addresses.netmask,
+            addresses.mtu,
+        )?;
+        
+        if let Some((ipv6, prefix_len)) = addresses.ipv6 {
+            info!("Assigned IPv6: {}/{}", ipv6, prefix_len);
+            
+            if let Err(e) = tun_device.add_ipv6_address(ipv6, prefix_len) {
+                warn!("Failed to configure IPv6, continuing with IPv4 only: {}", e);
+            }
         }
         
-        Ok(())
+        Ok(tun_device)
     }
 
     async fn setup_client_crypto(&self) -> Result<rustls::ClientConfig> {
-        let server_cert = if let Some(cert_path) = &self.config.server_cert_path {
-            // Load server certificate from file
-            Some(fs::read(cert_path).await?)
-        } else {
-            None
+        let server_cert = match &self.config.server_cert_path {
+            Some(cert_path) => Some(crypto::load_certificates(cert_path)?),
+            None => None,
+        };
+        
+        let client_cert = match (&self.config.client_cert_path, &self.config.client_key_path) {
+            (Some(cert_path), Some(key_path)) => Some((crypto::load_certificates(cert_path)?, crypto::load_private_key(key_path)?)),
+            (None, None) => None,
+            _ => bail!("client_cert_path and client_key_path must be set together"),
         };
+        let client_cert = client_cert.as_ref().map(|(chain, key)| (chain.as_slice(), key.as_slice()));
         
         match server_cert {
-            Some(cert) => {
-                // Use provided certificate
-                crypto::load_client_config(&self.config.server_hostname, &cert)
+            Some(roots) => {
+                // Trust the certificates from the file
+                Ok(crypto::load_client_config(&roots, client_cert)?)
             }
             None => {
-                // Use default crypto settings with no certificate verification (insecure)
-                let mut root_store = rustls::RootCertStore::empty();
-                let mut client_config = rustls::ClientConfig::builder()
-                    .with_safe_defaults()
-                    .with_root_certificates(root_store)
-                    .with_no_client_auth();
-                
-                // Allow using QUIC
-                client_config.alpn_protocols = vec![b"quicvpn".to_vec()];
+                // Without a certificate to trust, recognize the server by its key
+                let server = self.config.server_addr.to_string();
+                let verifier: Arc<dyn ServerCertVerifier> = if let Some(fingerprint) = &self.config.server_fingerprint {
+                    Arc::new(FingerprintVerification::pinned(server, fingerprint)?)
+                } else if self.config.insecure_skip_verify {
+                    warn!("Server certificate verification is disabled, anyone on the network path can impersonate the server");
+                    Arc::new(NoCertificateVerification)
+                } else {
+                    Arc::new(FingerprintVerification::known_servers(server, self.config.known_servers_path()))
+                };
                 
-                // Disable certificate verification (for development/testing only)
-                client_config.dangerous().set_certificate_verifier(Arc::new(danger::NoCertificateVerification {}));
+                let mut client_config = crypto::build_client_config(rustls::RootCertStore::empty(), client_cert)?;
+                client_config.dangerous().set_certificate_verifier(verifier);
                 
                 Ok(client_config)
             }
@@ -204,75 +612,121 @@ impl VpnClient {
         &self,
         connection: Connection,
         tun_device: Arc<TunDevice>,
+        datagrams: Arc<DatagramSender>,
+        shared: Arc<SessionShared>,
         send: SendStream,
         recv: RecvStream,
-        mut disconnect_rx: oneshot::Receiver<()>,
-    ) -> Result<()> {
-        let (tun_packet_tx, mut tun_packet_rx) = mpsc::channel(1000);
-        
-        // Start reading from TUN device
-        let tun_read_handle = tun_device.start_reading(tun_packet_tx)?;
+    ) -> Result<mpsc::Sender<Message>> {
+        // Start task that owns the control stream and writes queued messages
+        let (control_tx, mut control_rx) = mpsc::channel::<Message>(1000);
+        let mut send = send;
+        let mut control_writer = tokio::spawn(async move {
+            while let Some(message) = control_rx.recv().await {
+                let goodbye = matches!(message, Message::Disconnect { .. });
+                
+                if let Err(e) = protocol::write_message(&mut send, &message).await {
+                    error!("Failed to send message to server: {}", e);
+                    break;
+                }
+                
+                if goodbye {
+                    // Make sure the server sees the reason before the connection closes
+                    let _ = send.finish().await;
+                    break;
+                }
+            }
+        });
         
         // Start task to forward packets from TUN to server
-        let connection_clone = connection.clone();
-        let tun_to_server = tokio::spawn(async move {
-            while let Some(packet) = tun_packet_rx.recv().await {
-                match connection_clone.open_bi().await {
-                    Ok((mut send, _)) => {
+        let control_tx_clone = control_tx.clone();
+        let mut tun_reader = tun_device.reader();
+        let tun_shared = shared.clone();
+        let mut tun_to_server = tokio::spawn(async move {
+            loop {
+                let packet = match tun_reader.read_packet().await {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        error!("Failed to read from TUN device: {}", e);
+                        break;
+                    }
+                };
+                
+                tun_shared.stats.traffic.record_sent(packet.len());
+                match datagrams.send(packet) {
+                    Ok(None) => {}
+                    Ok(Some(packet)) => {
                         let message = Message::PacketData(packet);
-                        if let Err(e) = send_message_raw(&mut send, &message).await {
-                            error!("Failed to send packet to server: {}", e);
+                        if control_tx_clone.send(message).await.is_err() {
+                            break;
                         }
                     }
                     Err(e) => {
-                        error!("Failed to open stream: {}", e);
+                        error!("Failed to send packet to server: {}", e);
                         break;
                     }
                 }
             }
         });
         
-        // Start task to forward packets from server to TUN
+        // Start task to forward datagrams from server to TUN
+        let connection_clone = connection.clone();
+        let tun_device_clone = tun_device.clone();
+        let datagram_shared = shared.clone();
+        let mut datagram_to_tun = tokio::spawn(async move {
+            loop {
+                match connection_clone.read_datagram().await {
+                    Ok(packet) => {
+                        datagram_shared.stats.traffic.record_received(packet.len());
+                        if let Err(e) = tun_device_clone.write_packet(&packet).await {
+                            error!("Failed to write packet to TUN: {}", e);
+                        }
+                    }
+                    Err(e) => {
+                        debug!("Datagram receive loop finished: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+        
+        // Start task to forward stream messages from server to TUN
         let tun_device_clone = tun_device.clone();
         let mut recv = recv;
-        let server_to_tun = tokio::spawn(async move {
+        let mut server_to_tun = tokio::spawn(async move {
             loop {
-                // Read message length
-                let mut len_buf = [0u8; 4];
-                match recv.read_exact(&mut len_buf).await {
-                    Ok(_) => {
-                        let data_len = u32::from_be_bytes(len_buf) as usize;
-                        
-                        // Read message data
-                        let mut data = vec![0u8; data_len];
-                        match recv.read_exact(&mut data).await {
-                            Ok(_) => {
-                                match Message::from_bytes(&data) {
-                                    Ok(Message::PacketData(packet)) => {
-                                        if let Err(e) = tun_device_clone.write_packet(&packet).await {
-                                            error!("Failed to write packet to TUN: {}", e);
-                                        }
-                                    }
-                                    Ok(Message::Disconnect { reason }) => {
-                                        info!("Server disconnected: {}", reason);
-                                        break;
-                                    }
-                                    Ok(_) => {
-                                        // Ignore other messages
-                                    }
-                                    Err(e) => {
-                                        error!("Failed to parse message from server: {}", e);
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                error!("Failed to read message data from server: {}", e);
-                                break;
-                            }
+                match protocol::read_message(&mut recv).await {
+                    Ok(Message::PacketData(packet)) => {
+                        shared.stats.traffic.record_received(packet.len());
+                        if let Err(e) = tun_device_clone.write_packet(&packet).await {
+                            error!("Failed to write packet to TUN: {}", e);
+                        }
+                    }
+                    Ok(message @ Message::Stats { .. }) => {
+                        if let Some(stats) = PeerStats::from_message(&message) {
+                            debug!(
+                                "Server reports {} bytes sent, {} bytes received, RTT {} ms",
+                                stats.traffic.bytes_sent, stats.traffic.bytes_received, stats.latency_ms
+                            );
+                            shared.stats.record_peer(stats);
                         }
                     }
+                    Ok(Message::RouteUpdate { routes }) => {
+                        info!("Server pushed {} routes", routes.len());
+                        if let Err(e) = shared.routes.lock().await.apply(&routes).await {
+                            warn!("Failed to apply routes from server: {}", e);
+                        }
+                    }
+                    Ok(Message::Disconnect { reason, reconnect_after_secs }) => {
+                        info!("Server disconnected: {}", reason);
+                        *shared.server_reason.lock().unwrap() = Some(reason);
+                        *shared.reconnect_after.lock().unwrap() = reconnect_after_secs.map(|secs| Duration::from_secs(secs.into()));
+                        break;
+                    }
+                    Ok(_) => {
+                        // Ignore other messages
+                    }
                     Err(e) => {
-                        error!("Failed to read message length from server: {}", e);
+                        error!("Failed to read message from server: {}", e);
                         break;
                     }
                 }
@@ -280,105 +734,269 @@ impl VpnClient {
         });
         
         // Start keepalive task
-        let connection_clone = connection.clone();
-        let keepalive = tokio::spawn(async move {
+        let control_tx_clone = control_tx.clone();
+        let mut keepalive = tokio::spawn(async move {
             let mut interval = time::interval(Duration::from_secs(15));
             
             loop {
                 interval.tick().await;
                 
-                match connection_clone.open_bi().await {
-                    Ok((mut send, _)) => {
-                        if let Err(e) = send_message_raw(&mut send, &Message::KeepAlive).await {
-                            error!("Failed to send keepalive: {}", e);
-                            break;
-                        }
-                    }
-                    Err(e) => {
-                        error!("Failed to open stream for keepalive: {}", e);
-                        break;
-                    }
+                if control_tx_clone.send(Message::KeepAlive).await.is_err() {
+                    error!("Failed to send keepalive: control stream closed");
+                    break;
                 }
             }
         });
         
-        // Wait for disconnect signal or tasks to complete
+        // Wait for any task to complete
         tokio::spawn(async move {
             tokio::select! {
-                _ = disconnect_rx => {
-                    debug!("Received disconnect signal");
-                }
-                _ = tun_to_server => {
+                _ = &mut tun_to_server => {
                     debug!("TUN to server task completed");
                 }
-                _ = server_to_tun => {
+                _ = &mut datagram_to_tun => {
+                    debug!("Datagram to TUN task completed");
+                }
+                _ = &mut server_to_tun => {
                     debug!("Server to TUN task completed");
                 }
-                _ = keepalive => {
+                _ = &mut control_writer => {
+                    debug!("Control stream writer completed");
+                }
+                _ = &mut keepalive => {
                     debug!("Keepalive task completed");
                 }
             }
             
-            // Clean up
+            // Clean up; the TUN reader must stop before the next session
+            // starts reading from the same device
+            for task in [&tun_to_server, &datagram_to_tun, &server_to_tun, &control_writer, &keepalive] {
+                task.abort();
+            }
             connection.close(0u32.into(), b"Client disconnected");
         });
         
-        Ok(())
+        Ok(control_tx)
+    }
+
+    /// Send `Stats` to the server every `stats_interval_secs` until the
+    /// connection closes
+    fn start_stats_reporting(
+        &self,
+        connection: Connection,
+        control_tx: mpsc::Sender<Message>,
+        shared: Arc<SessionShared>,
+    ) {
+        let period = Duration::from_secs(self.config.stats_interval_secs);
+        
+        tokio::spawn(async move {
+            let mut interval = time::interval_at(time::Instant::now() + period, period);
+            
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = connection.closed() => break,
+                }
+                
+                let message = shared.stats.traffic.snapshot().to_message(connection.rtt());
+                if control_tx.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
     }
 
     async fn send_message(&self, stream: &mut SendStream, message: &Message) -> Result<()> {
-        send_message_raw(stream, message).await
+        Ok(protocol::write_message(stream, message).await?)
     }
 
     async fn receive_message(&self, stream: &mut RecvStream) -> Result<Message> {
-        // Read message length
-        let mut len_buf = [0u8; 4];
-        stream.read_exact(&mut len_buf).await?;
-        let data_len = u32::from_be_bytes(len_buf) as usize;
-        
-        // Read message data
-        let mut data = vec![0u8; data_len];
-        stream.read_exact(&mut data).await?;
-        
-        let message = Message::from_bytes(&data)?;
-        Ok(message)
+        Ok(protocol::read_message(stream).await?)
     }
 }
 
-async fn send_message_raw(stream: &mut SendStream, message: &Message) -> Result<()> {
-    let data = message.to_bytes()?;
-    let data_len = data.len() as u32;
-    
-    // Write message length
-    stream.write_all(&data_len.to_be_bytes()).await?;
-    
-    // Write message data
-    stream.write_all(&data).await?;
-    
-    Ok(())
+/// Jittered exponential backoff between reconnection attempts
+struct Backoff {
+    initial: Duration,
+    max: Duration,
+    max_attempts: u32,
+    attempt: u32,
+}
+
+impl Backoff {
+    fn new(config: &ReconnectConfig) -> Self {
+        Self {
+            initial: Duration::from_millis(config.initial_delay_ms),
+            max: Duration::from_millis(config.max_delay_ms),
+            max_attempts: config.max_attempts,
+            attempt: 0,
+        }
+    }
+
+    /// Delay before the next attempt, or `None` once attempts are used up
+    fn next_delay(&mut self) -> Option<Duration> {
+        if self.max_attempts != 0 && self.attempt >= self.max_attempts {
+            return None;
+        }
+
+        let delay = self.initial.saturating_mul(1 << self.attempt.min(16)).min(self.max);
+        self.attempt += 1;
+
+        // Randomise so clients dropped together do not reconnect in lockstep
+        Some(delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0)))
+    }
+
+    fn reset(&mut self) {
+        self.attempt = 0;
+    }
 }
 
-// Dangerous certificate verification for development only
-mod danger {
-    use std::sync::Arc;
-    use std::time::SystemTime;
-    use rustls::{Certificate, Error, ServerName};
-    use rustls::client::{ServerCertVerified, ServerCertVerifier};
-    
-    #[derive(Debug)]
-    pub struct NoCertificateVerification {}
-    
-    impl ServerCertVerifier for NoCertificateVerification {
-        fn verify_server_cert(
-            &self,
-            _end_entity: &Certificate,
-            _intermediates: &[Certificate],
-            _server_name: &ServerName,
-            _scts: &mut dyn Iterator<Item = &[u8]>,
-            _ocsp_response: &[u8],
-            _now: SystemTime,
-        ) -> Result<ServerCertVerified, Error> {
-            Ok(ServerCertVerified::assertion())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff(max_attempts: u32) -> Backoff {
+        Backoff::new(&ReconnectConfig {
+            enabled: true,
+            initial_delay_ms: 100,
+            max_delay_ms: 1_000,
+            max_attempts,
+        })
+    }
+
+    /// Check the next delay lies in the jitter range below `full_ms`
+    fn assert_jittered(backoff: &mut Backoff, full_ms: u64) {
+        let delay = backoff.next_delay().unwrap();
+        let full = Duration::from_millis(full_ms);
+        assert!(delay >= full / 2 && delay <= full, "{:?} not within half of {:?}", delay, full);
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        let mut backoff = backoff(0);
+        for full_ms in [100, 200, 400, 800, 1_000, 1_000] {
+            assert_jittered(&mut backoff, full_ms);
+        }
+
+        // Far past the cap the shift must not overflow
+        backoff.attempt = 1_000;
+        assert_jittered(&mut backoff, 1_000);
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let mut delays: Vec<Duration> = (0..1_000)
+            .map(|_| backoff(0).next_delay().unwrap())
+            .collect();
+        for delay in &delays {
+            assert!(*delay >= Duration::from_millis(50) && *delay <= Duration::from_millis(100), "{:?}", delay);
         }
+
+        // Clients dropped together should not all retry at once
+        delays.sort();
+        delays.dedup();
+        assert!(delays.len() > 1);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut backoff = backoff(3);
+        for _ in 0..3 {
+            assert!(backoff.next_delay().is_some());
+        }
+        assert_eq!(backoff.next_delay(), None);
+        assert_eq!(backoff.attempt, 3);
+    }
+
+    #[test]
+    fn reset_starts_over_after_a_session() {
+        let mut backoff = backoff(3);
+        for _ in 0..3 {
+            backoff.next_delay();
+        }
+
+        backoff.reset();
+        assert_eq!(backoff.attempt, 0);
+        assert_jittered(&mut backoff, 100);
+        assert_jittered(&mut backoff, 200);
+    }
+
+    /// Session over a QUIC connection to a local endpoint, with the control
+    /// channel the session writer would read from
+    async fn loopback_session() -> (SessionHandle, mpsc::Receiver<Message>, Endpoint) {
+        let (cert_der, key_der) = crypto::generate_self_signed_cert("localhost").unwrap();
+        let cert_chain = vec![cert_der];
+        let server_crypto = crypto::load_server_config(&cert_chain, &key_der, crypto::ClientAuth::None).unwrap();
+        let server = Endpoint::server(
+            quinn::ServerConfig::with_crypto(Arc::new(server_crypto)),
+            "127.0.0.1:0".parse().unwrap(),
+        )
+        .unwrap();
+
+        let client_crypto = crypto::load_client_config(&cert_chain, None).unwrap();
+        let mut endpoint = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(QuinnClientConfig::new(Arc::new(client_crypto)));
+
+        let server_addr = server.local_addr().unwrap();
+        let connecting = endpoint.connect(server_addr, "localhost").unwrap();
+        let (connection, _) = tokio::join!(connecting, async { server.accept().await.unwrap().await.unwrap() });
+        let connection = connection.unwrap();
+
+        let (control_tx, control_rx) = mpsc::channel(8);
+        let session = SessionHandle {
+            datagrams: Arc::new(DatagramSender::new(connection.clone(), false)),
+            connection,
+            control_tx,
+            shared: Arc::new(SessionShared {
+                stats: SessionStats::new(),
+                server_reason: Mutex::new(None),
+                reconnect_after: Mutex::new(None),
+                routes: Arc::new(AsyncMutex::new(RouteManager::new(Default::default(), server_addr.ip()))),
+            }),
+            info: Arc::new(SessionInfo {
+                server_addr,
+                interface: "tun-test".to_string(),
+                assigned_ip: "10.10.0.2".parse().unwrap(),
+                assigned_ipv6: None,
+                protocol_version: protocol::PROTOCOL_VERSION,
+                capabilities: Capabilities::empty(),
+                connected_at: Instant::now(),
+            }),
+        };
+        (session, control_rx, server)
+    }
+
+    #[tokio::test]
+    async fn disconnect_during_connect_is_not_lost() {
+        let handle = ClientHandle::default();
+        let (session, _control_rx, _server) = loopback_session().await;
+
+        // Requested while the session was still being set up
+        handle.disconnect().await;
+
+        assert!(!handle.set_connected(session));
+        assert!(matches!(handle.state(), ConnectionState::Connecting));
+        assert!(handle.is_stopping());
+        time::timeout(Duration::from_secs(1), handle.stopped()).await.unwrap();
     }
-} 
\ No newline at end of file
+
+    #[tokio::test]
+    async fn disconnect_says_goodbye_on_a_published_session() {
+        let handle = ClientHandle::default();
+        let (session, mut control_rx, _server) = loopback_session().await;
+        assert!(handle.set_connected(session.clone()));
+
+        let disconnect = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.disconnect().await }
+        });
+
+        let goodbye = control_rx.recv().await.unwrap();
+        assert!(matches!(goodbye, Message::Disconnect { .. }));
+
+        // What the control writer does once the goodbye is out
+        session.connection.close(0u32.into(), b"Client disconnected");
+        disconnect.await.unwrap();
+        assert!(handle.is_stopping());
+    }
+}
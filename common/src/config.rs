@@ -1,8 +1,10 @@
 use serde::{Deserialize, Serialize};
-use std::net::{IpAddr, SocketAddr};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::path::PathBuf;
 use std::fs;
 use crate::error::VpnError;
+use crate::protocol::RouteInfo;
 use crate::Result;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -10,13 +12,300 @@ pub struct ServerConfig {
     pub listen_addr: SocketAddr,
     pub cert_path: PathBuf,
     pub key_path: PathBuf,
+    /// Picking up a renewed certificate and warning before it expires
+    #[serde(default)]
+    pub cert_rotation: CertRotationConfig,
     pub vpn_network: IpAddr,
     pub vpn_netmask: IpAddr,
+    /// Server's IPv6 address inside the tunnel; enables dual-stack when set
+    #[serde(default)]
+    pub vpn_network_v6: Option<Ipv6Addr>,
+    #[serde(default = "default_vpn_prefix_len_v6")]
+    pub vpn_prefix_len_v6: u8,
     pub mtu: u16,
     pub log_level: String,
     pub user_db_path: PathBuf,
+    /// Where username-to-address leases are kept across restarts
+    #[serde(default = "default_lease_db_path")]
+    pub lease_db_path: PathBuf,
+    /// Where each user's data usage is kept for enforcing quotas
+    #[serde(default = "default_quota_db_path")]
+    pub quota_db_path: PathBuf,
+    /// Sessions connected at once. Changes take effect after a restart.
     pub max_clients: usize,
     pub gaming_optimization: bool,
+    /// File the server writes its process id to, used to signal reloads
+    #[serde(default)]
+    pub pid_file: Option<PathBuf>,
+    /// Default queue for packets waiting to be sent to each client
+    #[serde(default)]
+    pub client_queue: QueueConfig,
+    /// Per-user overrides of `client_queue`, keyed by username
+    #[serde(default)]
+    pub client_queue_overrides: HashMap<String, QueueConfig>,
+    /// How many sessions one user may have at the same time
+    #[serde(default)]
+    pub session_limit: SessionLimitConfig,
+    /// Cost of the Argon2id hashes used for new and migrated passwords
+    #[serde(default)]
+    pub password_hashing: PasswordHashConfig,
+    /// Throttling of failed logins and of handshakes in general
+    #[serde(default)]
+    pub auth_protection: AuthProtectionConfig,
+    /// Client certificate authentication; unset means passwords only
+    #[serde(default)]
+    pub client_auth: Option<ClientAuthConfig>,
+    /// Where `pki` subcommands keep the CA and the certificates it issued
+    #[serde(default = "default_pki_dir")]
+    pub pki_dir: PathBuf,
+    /// How long a dropped session's addresses are held for the client to
+    /// resume it; 0 disables resumption
+    #[serde(default = "default_resumption_timeout_secs")]
+    pub resumption_timeout_secs: u64,
+    /// Routes pushed to every client; a default route (`0.0.0.0/0` or
+    /// `::/0`) asks clients to send all their traffic through the tunnel
+    #[serde(default)]
+    pub routes: Vec<RouteInfo>,
+    /// Extra routes pushed to individual users, keyed by username
+    #[serde(default)]
+    pub user_routes: HashMap<String, Vec<RouteInfo>>,
+    /// How often each session's traffic counters are sent to the client and
+    /// logged; 0 disables
+    #[serde(default = "default_stats_interval_secs")]
+    pub stats_interval_secs: u64,
+    /// Address to serve Prometheus metrics on, e.g. `127.0.0.1:9100`;
+    /// disabled when unset. The metrics name users, so keep it private.
+    #[serde(default)]
+    pub metrics_listen_addr: Option<SocketAddr>,
+    /// Admin API used by `admin` subcommands and management tools
+    #[serde(default)]
+    pub admin: AdminConfig,
+    /// How long clients get to disconnect on SIGTERM or SIGINT before the
+    /// server closes anyway
+    #[serde(default = "default_shutdown_drain_secs")]
+    pub shutdown_drain_secs: u64,
+    /// Tell clients to reconnect after this many seconds when the server
+    /// shuts down, e.g. when it is only restarting
+    #[serde(default)]
+    pub shutdown_reconnect_after_secs: Option<u32>,
+}
+
+fn default_vpn_prefix_len_v6() -> u8 {
+    64
+}
+
+fn default_lease_db_path() -> PathBuf {
+    "leases.json".into()
+}
+
+fn default_quota_db_path() -> PathBuf {
+    "usage.json".into()
+}
+
+fn default_pki_dir() -> PathBuf {
+    "pki".into()
+}
+
+fn default_resumption_timeout_secs() -> u64 {
+    120
+}
+
+fn default_stats_interval_secs() -> u64 {
+    30
+}
+
+fn default_shutdown_drain_secs() -> u64 {
+    10
+}
+
+/// What to do with a packet when a client's queue is full
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueuePolicy {
+    /// Discard the incoming packet
+    DropNewest,
+    /// Discard the oldest queued packet, keeping the freshest data
+    DropOldest,
+    /// Wait for space, applying back-pressure to the TUN reader
+    Block,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct QueueConfig {
+    pub size: usize,
+    pub policy: QueuePolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            size: 1024,
+            policy: QueuePolicy::DropOldest,
+        }
+    }
+}
+
+/// What to do when a user who is at the session limit logs in again
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionLimitPolicy {
+    /// Refuse the new login
+    RejectNew,
+    /// Disconnect the user's oldest session to make room
+    EvictOldest,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SessionLimitConfig {
+    /// Most sessions per user; 0 means unlimited
+    pub max_per_user: usize,
+    pub policy: SessionLimitPolicy,
+}
+
+impl Default for SessionLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_per_user: 0,
+            policy: SessionLimitPolicy::RejectNew,
+        }
+    }
+}
+
+/// Argon2id cost parameters.
+///
+/// Existing hashes keep the cost they were created with; changes only apply
+/// to passwords hashed afterwards.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PasswordHashConfig {
+    /// Memory used per hash in KiB
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for PasswordHashConfig {
+    fn default() -> Self {
+        // OWASP's recommended minimum for Argon2id
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+/// Brute-force protection. Failed logins are counted per source address
+/// and per username; reaching `max_failures` bans that address or username,
+/// for twice as long with every further ban.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct AuthProtectionConfig {
+    pub max_failures: u32,
+    /// Failures older than this are forgotten
+    pub failure_window_secs: u64,
+    pub ban_secs: u64,
+    pub max_ban_secs: u64,
+    /// Delay before answering a failed login, doubling with each failure
+    pub failure_delay_ms: u64,
+    pub max_failure_delay_ms: u64,
+    /// Handshakes in progress at once, 0 for no limit; further connections
+    /// are refused. Changes take effect after a restart.
+    pub max_concurrent_handshakes: usize,
+    /// Time a client gets for each step of its handshake
+    pub handshake_timeout_secs: u64,
+}
+
+impl Default for AuthProtectionConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            failure_window_secs: 600,
+            ban_secs: 300,
+            max_ban_secs: 24 * 60 * 60,
+            failure_delay_ms: 500,
+            max_failure_delay_ms: 8000,
+            max_concurrent_handshakes: 64,
+            handshake_timeout_secs: 10,
+        }
+    }
+}
+
+/// The server re-reads `cert_path` and `key_path` on SIGHUP and when it
+/// sees them change; new handshakes get the new certificate while
+/// established connections continue.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct CertRotationConfig {
+    /// How often to check the files for changes, 0 to only reload on SIGHUP
+    pub watch_interval_secs: u64,
+    /// Warn daily once the certificate expires within this many days
+    pub expiry_warning_days: u64,
+}
+
+impl Default for CertRotationConfig {
+    fn default() -> Self {
+        Self {
+            watch_interval_secs: 30,
+            expiry_warning_days: 14,
+        }
+    }
+}
+
+/// Mutual TLS: clients present a certificate signed by `ca_path`, and a
+/// name in it picks their user in the user database.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ClientAuthConfig {
+    /// Certificates (PEM or DER) of the CAs that sign client certificates
+    pub ca_path: PathBuf,
+    /// Which name in the certificate is the username
+    #[serde(default)]
+    pub identity: CertIdentity,
+    /// Check the password as well, as a second factor
+    #[serde(default)]
+    pub require_password: bool,
+    /// Let clients without a certificate log in with a password alone
+    #[serde(default)]
+    pub allow_password_only: bool,
+    /// Certificates revoked with `pki revoke`, re-read on reload; defaults
+    /// to `revoked.json` in `pki_dir`
+    #[serde(default)]
+    pub revocation_list_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CertIdentity {
+    /// Common name of the subject
+    #[default]
+    CommonName,
+    /// A DNS name among the subject alternative names
+    DnsName,
+    /// An email address among the subject alternative names
+    Email,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct AdminConfig {
+    /// Unix socket of the admin API; only the server's own user and root
+    /// may use it
+    pub socket_path: PathBuf,
+    /// Address for the HTTP/JSON admin API, e.g. `127.0.0.1:9101`;
+    /// disabled when unset
+    #[serde(default)]
+    pub http_listen_addr: Option<SocketAddr>,
+    /// Bearer token HTTP requests must present; required for the HTTP API
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            socket_path: "admin.sock".into(),
+            http_listen_addr: None,
+            token: None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -24,21 +313,119 @@ pub struct ClientConfig {
     pub server_addr: SocketAddr,
     pub server_hostname: String,
     pub server_cert_path: Option<PathBuf>,
+    /// Certificate chain and key (PEM or DER) presented to servers that use client
+    /// certificate authentication
+    #[serde(default)]
+    pub client_cert_path: Option<PathBuf>,
+    #[serde(default)]
+    pub client_key_path: Option<PathBuf>,
+    /// SHA-256 fingerprint of the server's public key (SPKI) in hex, used
+    /// instead of `server_cert_path` to pin the server
+    #[serde(default)]
+    pub server_fingerprint: Option<String>,
+    /// Where servers trusted on first use are remembered when neither
+    /// `server_cert_path` nor `server_fingerprint` is set
+    #[serde(default)]
+    pub known_servers_path: Option<PathBuf>,
+    /// Accept any server certificate. Anyone on the network path can then
+    /// impersonate the server; only meant for testing.
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+    /// Name of the user; may be left empty when the client certificate
+    /// names it
+    #[serde(default)]
     pub username: String,
+    /// Not needed when the server accepts the client certificate alone
+    #[serde(default)]
     pub password: String,
     pub log_level: String,
     pub interface_name: Option<String>,
     pub gaming_optimization: bool,
     pub game_type: Option<String>,
+    /// Unix socket used by `status`, `stats` and `disconnect` to reach the
+    /// running client; defaults to a file in the user's runtime directory
+    #[serde(default)]
+    pub control_socket: Option<PathBuf>,
+    /// What to do when the connection to the server drops
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    /// Which of the routes pushed by the server to install
+    #[serde(default)]
+    pub tunnel_mode: TunnelMode,
+    /// How often traffic counters are sent to the server; 0 disables
+    #[serde(default = "default_stats_interval_secs")]
+    pub stats_interval_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelMode {
+    /// Install the routes the server pushes, including a default route
+    #[default]
+    Auto,
+    /// Send all traffic through the tunnel whatever the server pushes
+    Full,
+    /// Only install the server's routes to specific networks, never a
+    /// default route
+    Split,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ReconnectConfig {
+    pub enabled: bool,
+    /// Delay before the first attempt, doubled after every failure
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// Give up after this many failed attempts in a row; 0 retries forever
+    pub max_attempts: u32,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            initial_delay_ms: 500,
+            max_delay_ms: 30_000,
+            max_attempts: 0,
+        }
+    }
 }
 
 impl ServerConfig {
+    /// Revocation list written by `pki revoke` and checked on handshake
+    pub fn revocation_list_path(&self) -> PathBuf {
+        self.client_auth.as_ref()
+            .and_then(|client_auth| client_auth.revocation_list_path.clone())
+            .unwrap_or_else(|| self.pki_dir.join("revoked.json"))
+    }
+
+    /// Queue settings for the given user
+    pub fn queue_config_for(&self, username: &str) -> &QueueConfig {
+        self.client_queue_overrides.get(username).unwrap_or(&self.client_queue)
+    }
+
+    /// Routes pushed to the given user, leaving out IPv6 routes if the
+    /// session has no IPv6 address to send them from
+    pub fn routes_for(&self, username: &str, ipv6: bool) -> Vec<RouteInfo> {
+        let user_routes = self.user_routes.get(username).into_iter().flatten();
+        
+        self.routes.iter()
+            .chain(user_routes)
+            .filter(|route| ipv6 || route.destination.is_ipv4())
+            .cloned()
+            .collect()
+    }
+
     pub fn load(path: &str) -> Result<Self> {
         let content = fs::read_to_string(path)
             .map_err(|e| VpnError::Config(format!("Failed to read config file: {}", e)))?;
         
-        serde_json::from_str(&content)
-            .map_err(|e| VpnError::Config(format!("Failed to parse config: {}", e)))
+        let config: Self = serde_json::from_str(&content)
+            .map_err(|e| VpnError::Config(format!("Failed to parse config: {}", e)))?;
+        
+        config.validate_routes()?;
+        
+        Ok(config)
     }
 
     pub fn save(&self, path: &str) -> Result<()> {
@@ -48,9 +435,62 @@ impl ServerConfig {
         fs::write(path, content)
             .map_err(|e| VpnError::Config(format!("Failed to write config file: {}", e)))
     }
+
+    fn validate_routes(&self) -> Result<()> {
+        let user_routes = self.user_routes.values().flatten();
+        
+        for route in self.routes.iter().chain(user_routes) {
+            if route.prefix_len().is_none() {
+                return Err(VpnError::Config(format!(
+                    "Invalid route {}: netmask {} is not a valid prefix for this address",
+                    route.destination, route.netmask
+                )));
+            }
+            
+            if let Some(gateway) = route.gateway {
+                if gateway.is_ipv4() != route.destination.is_ipv4() {
+                    return Err(VpnError::Config(format!(
+                        "Invalid route {}: gateway {} is of a different address family",
+                        route.destination, gateway
+                    )));
+                }
+            }
+        }
+        
+        Ok(())
+    }
 }
 
 impl ClientConfig {
+    /// Path of the control socket of the running client
+    pub fn control_socket_path(&self) -> PathBuf {
+        if let Some(path) = &self.control_socket {
+            return path.clone();
+        }
+
+        let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir);
+
+        runtime_dir.join("quicvpn-client.sock")
+    }
+
+    /// Path of the known-servers file, by default in the user's config
+    /// directory
+    pub fn known_servers_path(&self) -> PathBuf {
+        if let Some(path) = &self.known_servers_path {
+            return path.clone();
+        }
+
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+            .unwrap_or_default();
+
+        config_dir.join("quicvpn").join("known_servers.json")
+    }
+
     pub fn load(path: &str) -> Result<Self> {
         let content = fs::read_to_string(path)
             .map_err(|e| VpnError::Config(format!("Failed to read config file: {}", e)))?;
@@ -1,8 +1,25 @@
-use rcgen::{Certificate, CertificateParams, DnType, KeyPair, PKCS_ECDSA_P256_SHA256};
-use rustls::{Certificate as RustlsCert, PrivateKey, ServerConfig};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair, KeyUsagePurpose, SanType, PKCS_ECDSA_P256_SHA256,
+};
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, UnparsedPublicKey, VerificationAlgorithm};
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, NoClientAuth, ResolvesServerCert,
+    WantsServerCert,
+};
+use rustls::sign::{CertifiedKey, SigningKey};
+use rustls::{Certificate as RustlsCert, ConfigBuilder, PrivateKey, RootCertStore, ServerConfig, SignatureScheme};
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::SystemTime;
+use time::OffsetDateTime;
 
 use crate::error::VpnError;
+use crate::x509::{self, CertificateInfo};
 use crate::Result;
 
 pub fn generate_self_signed_cert(hostname: &str) -> Result<(Vec<u8>, Vec<u8>)> {
@@ -18,15 +35,221 @@ pub fn generate_self_signed_cert(hostname: &str) -> Result<(Vec<u8>, Vec<u8>)> {
     Ok((cert_der, key_der))
 }
 
-pub fn load_server_config(cert_der: &[u8], key_der: &[u8]) -> Result<ServerConfig> {
-    let cert = RustlsCert(cert_der.to_vec());
+/// Private certificate authority issuing the server's certificate and
+/// one client certificate per user
+pub struct CertificateAuthority {
+    cert: Certificate,
+    cert_der: Vec<u8>,
+}
+
+/// Certificate and key issued by a `CertificateAuthority`
+pub struct IssuedCertificate {
+    pub cert_der: Vec<u8>,
+    pub key_der: Vec<u8>,
+    /// Serial number as `x509::format_serial` writes it
+    pub serial: String,
+    pub not_after: SystemTime,
+}
+
+impl CertificateAuthority {
+    /// Create a CA named `name`, valid for `days`
+    pub fn generate(name: &str, days: u32) -> Result<Self> {
+        let mut params = ca_params(name);
+        (params.not_before, params.not_after) = validity(days);
+        
+        let cert = Certificate::from_params(params).map_err(|e| VpnError::Certificate(e.to_string()))?;
+        let cert_der = cert.serialize_der().map_err(|e| VpnError::Certificate(e.to_string()))?;
+        
+        Ok(Self { cert, cert_der })
+    }
+    
+    /// Load a CA made by `generate` from its DER certificate and PKCS#8 key
+    pub fn load(cert_der: &[u8], key_der: &[u8]) -> Result<Self> {
+        let name = CertificateInfo::parse(cert_der)?.common_name
+            .ok_or_else(|| VpnError::Certificate("CA certificate has no common name".to_string()))?;
+        let key_pair = KeyPair::from_der(key_der)
+            .map_err(|e| VpnError::Certificate(format!("Invalid CA key: {}", e)))?;
+        
+        // Signing only needs the CA's name and key, so the parameters it was
+        // created with can be rebuilt from them
+        let mut params = ca_params(&name);
+        params.alg = key_pair.compatible_algs().next()
+            .ok_or_else(|| VpnError::Certificate("Unsupported CA key type".to_string()))?;
+        params.key_pair = Some(key_pair);
+        
+        let cert = Certificate::from_params(params).map_err(|e| VpnError::Certificate(e.to_string()))?;
+        
+        Ok(Self { cert, cert_der: cert_der.to_vec() })
+    }
+    
+    pub fn cert_der(&self) -> &[u8] {
+        &self.cert_der
+    }
+    
+    pub fn key_der(&self) -> Vec<u8> {
+        self.cert.serialize_private_key_der()
+    }
+    
+    /// Certificate for a server reached under `names`, each a DNS name or
+    /// an IP address
+    pub fn issue_server(&self, names: &[String], days: u32) -> Result<IssuedCertificate> {
+        let first = names.first()
+            .ok_or_else(|| VpnError::Certificate("A server certificate needs at least one name".to_string()))?;
+        
+        let mut params = CertificateParams::default();
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, first.as_str());
+        params.subject_alt_names = names.iter()
+            .map(|name| match name.parse::<IpAddr>() {
+                Ok(ip) => SanType::IpAddress(ip),
+                Err(_) => SanType::DnsName(name.clone()),
+            })
+            .collect();
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        
+        self.issue(params, days)
+    }
+    
+    /// Certificate for a user, who is named by its common name
+    pub fn issue_client(&self, username: &str, days: u32) -> Result<IssuedCertificate> {
+        let mut params = CertificateParams::default();
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, username);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        
+        self.issue(params, days)
+    }
+    
+    fn issue(&self, mut params: CertificateParams, days: u32) -> Result<IssuedCertificate> {
+        // Positive and never zero, as serial numbers must be
+        let serial = u64::from_be_bytes(random_bytes()?) >> 1 | 1;
+        let not_after;
+        
+        params.serial_number = Some(serial);
+        (params.not_before, not_after) = validity(days);
+        params.not_after = not_after;
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.use_authority_key_identifier_extension = true;
+        
+        let cert = Certificate::from_params(params).map_err(|e| VpnError::Certificate(e.to_string()))?;
+        let cert_der = cert.serialize_der_with_signer(&self.cert)
+            .map_err(|e| VpnError::Certificate(e.to_string()))?;
+        
+        Ok(IssuedCertificate {
+            cert_der,
+            key_der: cert.serialize_private_key_der(),
+            serial: x509::format_serial(&serial.to_be_bytes()),
+            not_after: not_after.into(),
+        })
+    }
+}
+
+fn ca_params(name: &str) -> CertificateParams {
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, name);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    
+    params
+}
+
+/// Validity from now for `days`, backdated an hour for clocks running late
+fn validity(days: u32) -> (OffsetDateTime, OffsetDateTime) {
+    let now = OffsetDateTime::now_utc();
+    (now - time::Duration::hours(1), now + time::Duration::days(days.into()))
+}
+
+/// Whether the server asks clients for a certificate
+pub enum ClientAuth<'a> {
+    /// Clients only log in with a password
+    None,
+    /// Clients may present a certificate signed by one of these CAs
+    Optional(&'a [Vec<u8>]),
+    /// Clients must present a certificate signed by one of these CAs
+    Required(&'a [Vec<u8>]),
+}
+
+/// Load the certificates of a PEM or DER file, in file order; a chain is
+/// expected leaf first, followed by its intermediates
+pub fn load_certificates(path: &Path) -> Result<Vec<Vec<u8>>> {
+    let content = read_file(path)?;
+    
+    let certs = if is_pem(&content) {
+        let items = rustls_pemfile::read_all(&mut BufReader::new(content.as_slice()))
+            .map_err(|e| file_error(path, "parse PEM", e))?;
+        let certs: Vec<Vec<u8>> = items.into_iter()
+            .filter_map(|item| match item {
+                rustls_pemfile::Item::X509Certificate(der) => Some(der),
+                _ => None,
+            })
+            .collect();
+        if certs.is_empty() {
+            return Err(file_error(path, "find a certificate", "no CERTIFICATE block in PEM file"));
+        }
+        certs
+    } else {
+        vec![content]
+    };
+    
+    for (index, cert) in certs.iter().enumerate() {
+        CertificateInfo::parse(cert)
+            .map_err(|e| file_error(path, &format!("parse certificate #{}", index + 1), e))?;
+    }
+    
+    Ok(certs)
+}
+
+/// Load a private key from a PEM or DER file; PKCS#8, SEC1 (EC) and
+/// PKCS#1 (RSA) keys are accepted
+pub fn load_private_key(path: &Path) -> Result<Vec<u8>> {
+    let content = read_file(path)?;
+    
+    let key = if is_pem(&content) {
+        let items = rustls_pemfile::read_all(&mut BufReader::new(content.as_slice()))
+            .map_err(|e| file_error(path, "parse PEM", e))?;
+        items.into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::PKCS8Key(der)
+                | rustls_pemfile::Item::ECKey(der)
+                | rustls_pemfile::Item::RSAKey(der) => Some(der),
+                _ => None,
+            })
+            .ok_or_else(|| file_error(
+                path,
+                "find a private key",
+                "no PRIVATE KEY, EC PRIVATE KEY or RSA PRIVATE KEY block in PEM file",
+            ))?
+    } else {
+        content
+    };
+    
+    rustls::sign::any_supported_type(&PrivateKey(key.clone()))
+        .map_err(|_| file_error(path, "load private key", "not a supported PKCS#8, SEC1 or PKCS#1 key"))?;
+    
+    Ok(key)
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| file_error(path, "read", e))
+}
+
+fn is_pem(content: &[u8]) -> bool {
+    content.windows(10).any(|window| window == b"-----BEGIN")
+}
+
+fn file_error(path: &Path, step: &str, error: impl std::fmt::Display) -> VpnError {
+    VpnError::Certificate(format!("{}: failed to {}: {}", path.display(), step, error))
+}
+
+/// Server config presenting `cert_chain` (leaf first) with its key
+pub fn load_server_config(cert_chain: &[Vec<u8>], key_der: &[u8], client_auth: ClientAuth) -> Result<ServerConfig> {
+    let certs = cert_chain.iter().cloned().map(RustlsCert).collect();
     let key = PrivateKey(key_der.to_vec());
     
-    let mut server_config = ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(vec![cert], key)
-        .map_err(|e| VpnError::Tls(e))?;
+    let mut server_config = server_config_builder(client_auth)?
+        .with_single_cert(certs, key)
+        .map_err(VpnError::Tls)?;
     
     // Configure QUIC-specific parameters
     server_config.alpn_protocols = vec![b"quicvpn".to_vec()];
@@ -34,17 +257,128 @@ pub fn load_server_config(cert_der: &[u8], key_der: &[u8]) -> Result<ServerConfi
     Ok(server_config)
 }
 
-pub fn load_client_config(server_name: &str, cert_der: &[u8]) -> Result<rustls::ClientConfig> {
-    let mut root_cert_store = rustls::RootCertStore::empty();
-    root_cert_store.add(&RustlsCert(cert_der.to_vec()))
-        .map_err(|e| VpnError::Certificate(e.to_string()))?;
+/// Server config asking `cert_resolver` for the certificate of every new
+/// handshake, so it can be replaced while the server runs
+pub fn build_server_config(
+    cert_resolver: Arc<dyn ResolvesServerCert>,
+    client_auth: ClientAuth,
+) -> Result<ServerConfig> {
+    let mut server_config = server_config_builder(client_auth)?.with_cert_resolver(cert_resolver);
+    
+    server_config.alpn_protocols = vec![b"quicvpn".to_vec()];
+    
+    Ok(server_config)
+}
+
+fn server_config_builder(client_auth: ClientAuth) -> Result<ConfigBuilder<ServerConfig, WantsServerCert>> {
+    let client_cert_verifier = match client_auth {
+        ClientAuth::None => NoClientAuth::boxed(),
+        ClientAuth::Optional(cas) => AllowAnyAnonymousOrAuthenticatedClient::new(root_store(cas)?).boxed(),
+        ClientAuth::Required(cas) => AllowAnyAuthenticatedClient::new(root_store(cas)?).boxed(),
+    };
+    
+    Ok(ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_cert_verifier))
+}
+
+/// Certificate chain and key ready to present, after checking that the key
+/// belongs to the first certificate
+pub fn certified_key(cert_chain: &[Vec<u8>], key_der: &[u8]) -> Result<CertifiedKey> {
+    let leaf = cert_chain.first()
+        .ok_or_else(|| VpnError::Certificate("Empty certificate chain".to_string()))?;
+    let key = rustls::sign::any_supported_type(&PrivateKey(key_der.to_vec()))
+        .map_err(|_| VpnError::Certificate("Unsupported private key".to_string()))?;
+    check_key_matches(leaf, key.as_ref())?;
+    
+    Ok(CertifiedKey::new(cert_chain.iter().cloned().map(RustlsCert).collect(), key))
+}
+
+/// Sign with `key` and verify the signature with the certificate's public
+/// key; catches a certificate replaced without its key
+fn check_key_matches(cert_der: &[u8], key: &dyn SigningKey) -> Result<()> {
+    const MESSAGE: &[u8] = b"quicvpn key check";
+    let schemes = [
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        SignatureScheme::ED25519,
+        SignatureScheme::RSA_PSS_SHA256,
+    ];
+    
+    let signer = key.choose_scheme(&schemes)
+        .ok_or_else(|| VpnError::Certificate("Unsupported private key type".to_string()))?;
+    let algorithm: &'static dyn VerificationAlgorithm = match signer.scheme() {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &signature::ECDSA_P256_SHA256_ASN1,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &signature::ECDSA_P384_SHA384_ASN1,
+        SignatureScheme::ED25519 => &signature::ED25519,
+        _ => &signature::RSA_PSS_2048_8192_SHA256,
+    };
+    let signed = signer.sign(MESSAGE).map_err(VpnError::Tls)?;
     
-    let mut client_config = rustls::ClientConfig::builder()
+    let info = CertificateInfo::parse(cert_der)?;
+    UnparsedPublicKey::new(algorithm, x509::public_key_bits(&info.public_key)?)
+        .verify(MESSAGE, &signed)
+        .map_err(|_| VpnError::Certificate("Private key does not match the certificate".to_string()))
+}
+
+/// Client config trusting the certificates in `roots`; `client_cert` is the
+/// certificate chain and key to present for mutual TLS
+pub fn load_client_config(
+    roots: &[Vec<u8>],
+    client_cert: Option<(&[Vec<u8>], &[u8])>,
+) -> Result<rustls::ClientConfig> {
+    build_client_config(root_store(roots)?, client_cert)
+}
+
+/// Client config trusting `roots`, presenting `client_cert` if given
+pub fn build_client_config(
+    roots: RootCertStore,
+    client_cert: Option<(&[Vec<u8>], &[u8])>,
+) -> Result<rustls::ClientConfig> {
+    let builder = rustls::ClientConfig::builder()
         .with_safe_defaults()
-        .with_root_certificates(root_cert_store)
-        .with_no_client_auth();
+        .with_root_certificates(roots);
+    
+    let mut client_config = match client_cert {
+        Some((cert_chain, key_der)) => builder
+            .with_client_auth_cert(
+                cert_chain.iter().cloned().map(RustlsCert).collect(),
+                PrivateKey(key_der.to_vec()),
+            )
+            .map_err(|e| VpnError::Certificate(format!("Invalid client certificate or key: {}", e)))?,
+        None => builder.with_no_client_auth(),
+    };
     
     client_config.alpn_protocols = vec![b"quicvpn".to_vec()];
     
     Ok(client_config)
-} 
\ No newline at end of file
+}
+
+fn root_store(certs: &[Vec<u8>]) -> Result<RootCertStore> {
+    let mut root_cert_store = RootCertStore::empty();
+    for cert in certs {
+        root_cert_store.add(&RustlsCert(cert.clone()))
+            .map_err(|e| VpnError::Certificate(format!("Invalid trusted certificate: {}", e)))?;
+    }
+    
+    Ok(root_cert_store)
+}
+
+/// SHA-256 fingerprint of a certificate's public key (SPKI) in lowercase
+/// hex; unlike a fingerprint of the whole certificate it survives renewals
+/// that keep the key
+pub fn spki_fingerprint(cert_der: &[u8]) -> Result<String> {
+    let info = CertificateInfo::parse(cert_der)?;
+    let hash = digest::digest(&digest::SHA256, &info.public_key);
+    
+    Ok(hash.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Fill an array from the system's secure random number generator
+pub fn random_bytes<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    SystemRandom::new().fill(&mut bytes)
+        .map_err(|_| VpnError::Unknown("System random number generator failed".to_string()))?;
+    
+    Ok(bytes)
+}
\ No newline at end of file
@@ -0,0 +1,87 @@
+use bytes::Bytes;
+use quinn::{Connection, SendDatagramError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{debug, warn};
+
+use crate::error::VpnError;
+use crate::Result;
+
+/// Sends tunneled packets as unreliable QUIC DATAGRAM frames, handing
+/// packets back to the caller whenever they have to travel over a stream
+/// instead (peer without datagram support, or packet above the path limit).
+#[derive(Debug)]
+pub struct DatagramSender {
+    connection: Connection,
+    enabled: AtomicBool,
+}
+
+impl DatagramSender {
+    /// `negotiated` is whether both peers agreed on datagrams during the
+    /// handshake; the transport must also support them on this connection.
+    pub fn new(connection: Connection, negotiated: bool) -> Self {
+        // `max_datagram_size` is `None` when the peer did not advertise the
+        // datagram transport parameter or datagrams are disabled locally
+        let enabled = negotiated && connection.max_datagram_size().is_some();
+
+        Self {
+            connection,
+            enabled: AtomicBool::new(enabled),
+        }
+    }
+
+    /// Whether packets are currently sent as datagrams
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Largest packet that currently fits into a single datagram
+    pub fn max_size(&self) -> Option<usize> {
+        if self.is_enabled() {
+            self.connection.max_datagram_size()
+        } else {
+            None
+        }
+    }
+
+    /// Try to send a packet as a datagram.
+    ///
+    /// Returns `Ok(None)` when the packet was handed to QUIC, or
+    /// `Ok(Some(packet))` when it must be sent over the stream fallback.
+    pub fn send(&self, packet: Bytes) -> Result<Option<Bytes>> {
+        if !self.is_enabled() {
+            return Ok(Some(packet));
+        }
+
+        match self.connection.max_datagram_size() {
+            Some(max_size) if packet.len() > max_size => {
+                debug!("Packet of {} bytes exceeds datagram limit of {} bytes", packet.len(), max_size);
+                return Ok(Some(packet));
+            }
+            Some(_) => {}
+            None => {
+                self.disable("peer no longer accepts datagrams");
+                return Ok(Some(packet));
+            }
+        }
+
+        match self.connection.send_datagram(packet.clone()) {
+            Ok(()) => Ok(None),
+            Err(SendDatagramError::TooLarge) => Ok(Some(packet)),
+            Err(SendDatagramError::UnsupportedByPeer) => {
+                self.disable("peer does not support datagrams");
+                Ok(Some(packet))
+            }
+            Err(SendDatagramError::Disabled) => {
+                self.disable("datagrams disabled locally");
+                Ok(Some(packet))
+            }
+            Err(SendDatagramError::ConnectionLost(e)) => Err(VpnError::Quinn(e)),
+        }
+    }
+
+    fn disable(&self, reason: &str) {
+        if self.enabled.swap(false, Ordering::Relaxed) {
+            warn!("Falling back to stream transport: {}", reason);
+        }
+    }
+}
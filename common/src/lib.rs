@@ -1,8 +1,12 @@
 pub mod crypto;
+pub mod datagram;
 pub mod protocol;
+pub mod stats;
+pub mod storage;
 pub mod tun_device;
 pub mod config;
 pub mod error;
+pub mod x509;
 
 pub use error::VpnError;
 pub type Result<T> = std::result::Result<T, VpnError>; 
\ No newline at end of file
@@ -1,23 +1,70 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use quinn::{ReadExactError, RecvStream, SendStream};
 use serde::{Deserialize, Serialize};
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+use crate::error::VpnError;
+use crate::Result;
+
+/// Version of the binary wire framing
+pub const WIRE_VERSION: u8 = 1;
+
+/// Protocol version spoken by this build
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Oldest protocol version this build still accepts from a peer
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Frame header: version (1) + type tag (1) + payload length (4)
+pub const HEADER_LEN: usize = 6;
+
+/// Upper bound on a single frame payload, to reject corrupt length fields
+pub const MAX_PAYLOAD_LEN: usize = 1024 * 1024;
+
+mod tag {
+    pub const CLIENT_HELLO: u8 = 0x01;
+    pub const SERVER_HELLO: u8 = 0x02;
+    pub const PACKET_DATA: u8 = 0x03;
+    pub const KEEP_ALIVE: u8 = 0x04;
+    pub const DISCONNECT: u8 = 0x05;
+    pub const GAME_OPTIMIZATION_INFO: u8 = 0x06;
+    pub const ROUTE_UPDATE: u8 = 0x07;
+    pub const STATS: u8 = 0x08;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "json-debug", derive(Serialize, Deserialize))]
 pub enum Message {
     ClientHello {
         username: String,
         password: String,
         client_version: String,
+        protocol_version: u16,
+        capabilities: Capabilities,
+        /// Token from a previous `ServerHello`, asking for that session back
+        resumption_token: Option<Bytes>,
     },
     ServerHello {
         server_version: String,
+        protocol_version: u16,
+        capabilities: Capabilities,
         assigned_ip: IpAddr,
         subnet_mask: IpAddr,
         mtu: u16,
+        /// IPv6 address and prefix length, when dual-stack was negotiated
+        assigned_ipv6: Option<(Ipv6Addr, u8)>,
+        /// Token the client can present to resume this session after a
+        /// dropped connection
+        resumption_token: Option<Bytes>,
     },
-    PacketData(Vec<u8>),
+    PacketData(Bytes),
     KeepAlive,
     Disconnect {
         reason: String,
+        /// Seconds after which the client may reconnect, e.g. when the
+        /// server is restarting; only sent to clients offering
+        /// `RECONNECT_HINT`
+        reconnect_after_secs: Option<u32>,
     },
     GameOptimizationInfo {
         game_type: String,
@@ -35,19 +82,542 @@ pub enum Message {
     },
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Optional protocol features, offered in `ClientHello` and narrowed to the
+/// mutually supported set in `ServerHello`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(transparent)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const DATAGRAMS: Self = Self(1 << 0);
+    pub const COMPRESSION: Self = Self(1 << 1);
+    pub const IPV6: Self = Self(1 << 2);
+    pub const ROUTE_PUSH: Self = Self(1 << 3);
+    pub const STATS: Self = Self(1 << 4);
+    pub const RESUMPTION: Self = Self(1 << 5);
+    pub const RECONNECT_HINT: Self = Self(1 << 6);
+
+    const NAMES: [(Self, &'static str); 7] = [
+        (Self::DATAGRAMS, "datagrams"),
+        (Self::COMPRESSION, "compression"),
+        (Self::IPV6, "ipv6"),
+        (Self::ROUTE_PUSH, "route-push"),
+        (Self::STATS, "stats"),
+        (Self::RESUMPTION, "resumption"),
+        (Self::RECONNECT_HINT, "reconnect-hint"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Build from raw bits, keeping bits unknown to this build so they can
+    /// be dropped by intersecting with the local set
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Self) {
+        self.0 &= !other.0;
+    }
+}
+
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = Self::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect();
+
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(","))
+        }
+    }
+}
+
+/// Pick the protocol version both sides speak, or `None` if the peer is too
+/// old for this build
+pub fn negotiate_version(peer_version: u16) -> Option<u16> {
+    if peer_version < MIN_PROTOCOL_VERSION {
+        None
+    } else {
+        Some(peer_version.min(PROTOCOL_VERSION))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct RouteInfo {
     pub destination: IpAddr,
     pub netmask: IpAddr,
     pub gateway: Option<IpAddr>,
 }
 
+impl RouteInfo {
+    /// Prefix length of `netmask`, or `None` if it is not a contiguous mask
+    /// of the same address family as `destination`
+    pub fn prefix_len(&self) -> Option<u8> {
+        let (bits, width) = match (self.destination, self.netmask) {
+            (IpAddr::V4(_), IpAddr::V4(mask)) => (u32::from(mask) as u128, 32),
+            (IpAddr::V6(_), IpAddr::V6(mask)) => (u128::from(mask), 128),
+            _ => return None,
+        };
+
+        let prefix_len = bits.count_ones();
+        let expected = u128::MAX.checked_shl(width - prefix_len).unwrap_or(0)
+            & u128::MAX.checked_shr(128 - width).unwrap_or(0);
+
+        (bits == expected).then_some(prefix_len as u8)
+    }
+
+    /// Whether the route covers the whole address family, i.e. a full tunnel
+    pub fn is_default(&self) -> bool {
+        self.prefix_len() == Some(0)
+    }
+}
+
 impl Message {
-    pub fn to_bytes(&self) -> Result<Vec<u8>, serde_json::Error> {
-        serde_json::to_vec(self)
+    /// `Disconnect` without a reconnect hint
+    pub fn disconnect(reason: impl Into<String>) -> Self {
+        Message::Disconnect {
+            reason: reason.into(),
+            reconnect_after_secs: None,
+        }
+    }
+
+    /// Encode the message as a complete binary frame
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let mut buf = BytesMut::with_capacity(HEADER_LEN + self.payload_len_hint());
+        self.encode(&mut buf)?;
+        Ok(buf.freeze())
+    }
+
+    /// Decode a complete binary frame produced by [`Message::to_bytes`].
+    ///
+    /// `PacketData` payloads are sliced out of `frame` without copying.
+    pub fn from_bytes(mut frame: Bytes) -> Result<Self> {
+        if frame.len() < HEADER_LEN {
+            return Err(VpnError::Protocol(format!("Frame too short: {} bytes", frame.len())));
+        }
+
+        let (tag, payload_len) = decode_header(&frame[..HEADER_LEN])?;
+        frame.advance(HEADER_LEN);
+
+        if frame.len() != payload_len {
+            return Err(VpnError::Protocol(format!(
+                "Frame length mismatch: header says {} bytes, got {}",
+                payload_len,
+                frame.len()
+            )));
+        }
+
+        Self::decode_payload(tag, frame)
+    }
+
+    /// Encode the message as a binary frame appended to `buf`
+    pub fn encode(&self, buf: &mut BytesMut) -> Result<()> {
+        let start = buf.len();
+        buf.put_u8(WIRE_VERSION);
+        buf.put_u8(self.tag());
+        buf.put_u32(0);
+
+        match self {
+            Message::ClientHello {
+                username,
+                password,
+                client_version,
+                protocol_version,
+                capabilities,
+                resumption_token,
+            } => {
+                // Version and capabilities lead so they stay readable by any peer
+                buf.put_u16(*protocol_version);
+                buf.put_u32(capabilities.bits());
+                put_str(buf, username)?;
+                put_str(buf, password)?;
+                put_str(buf, client_version)?;
+                put_trailing_token(buf, resumption_token)?;
+            }
+            Message::ServerHello {
+                server_version,
+                protocol_version,
+                capabilities,
+                assigned_ip,
+                subnet_mask,
+                mtu,
+                assigned_ipv6,
+                resumption_token,
+            } => {
+                buf.put_u16(*protocol_version);
+                buf.put_u32(capabilities.bits());
+                put_str(buf, server_version)?;
+                put_ip(buf, assigned_ip);
+                put_ip(buf, subnet_mask);
+                buf.put_u16(*mtu);
+                put_ipv6_assignment(buf, *capabilities, assigned_ipv6)?;
+                put_trailing_token(buf, resumption_token)?;
+            }
+            Message::PacketData(packet) => {
+                buf.put_slice(packet);
+            }
+            Message::KeepAlive => {}
+            Message::Disconnect { reason, reconnect_after_secs } => {
+                put_str(buf, reason)?;
+                if let Some(secs) = reconnect_after_secs {
+                    buf.put_u32(*secs);
+                }
+            }
+            Message::GameOptimizationInfo { game_type, latency_priority } => {
+                put_str(buf, game_type)?;
+                buf.put_u8(*latency_priority as u8);
+            }
+            Message::RouteUpdate { routes } => {
+                let count = u16::try_from(routes.len())
+                    .map_err(|_| VpnError::Protocol(format!("Too many routes: {}", routes.len())))?;
+                buf.put_u16(count);
+                for route in routes {
+                    put_ip(buf, &route.destination);
+                    put_ip(buf, &route.netmask);
+                    match &route.gateway {
+                        Some(gateway) => {
+                            buf.put_u8(1);
+                            put_ip(buf, gateway);
+                        }
+                        None => buf.put_u8(0),
+                    }
+                }
+            }
+            Message::Stats { bytes_sent, bytes_received, packets_sent, packets_received, latency_ms } => {
+                buf.put_u64(*bytes_sent);
+                buf.put_u64(*bytes_received);
+                buf.put_u64(*packets_sent);
+                buf.put_u64(*packets_received);
+                buf.put_u32(*latency_ms);
+            }
+        }
+
+        let payload_len = buf.len() - start - HEADER_LEN;
+        if payload_len > MAX_PAYLOAD_LEN {
+            buf.truncate(start);
+            return Err(VpnError::Protocol(format!("Message too large: {} bytes", payload_len)));
+        }
+        buf[start + 2..start + HEADER_LEN].copy_from_slice(&(payload_len as u32).to_be_bytes());
+
+        Ok(())
+    }
+
+    /// Decode a message body given its type tag
+    pub fn decode_payload(tag: u8, mut payload: Bytes) -> Result<Self> {
+        let message = match tag {
+            tag::CLIENT_HELLO => {
+                let protocol_version = get_u16(&mut payload)?;
+                let capabilities = Capabilities::from_bits(get_u32(&mut payload)?);
+                Message::ClientHello {
+                    username: get_str(&mut payload)?,
+                    password: get_str(&mut payload)?,
+                    client_version: get_str(&mut payload)?,
+                    protocol_version,
+                    capabilities,
+                    resumption_token: get_trailing_token(&mut payload)?,
+                }
+            }
+            tag::SERVER_HELLO => {
+                let protocol_version = get_u16(&mut payload)?;
+                let capabilities = Capabilities::from_bits(get_u32(&mut payload)?);
+                Message::ServerHello {
+                    server_version: get_str(&mut payload)?,
+                    protocol_version,
+                    capabilities,
+                    assigned_ip: get_ip(&mut payload)?,
+                    subnet_mask: get_ip(&mut payload)?,
+                    mtu: get_u16(&mut payload)?,
+                    assigned_ipv6: get_ipv6_assignment(&mut payload, capabilities)?,
+                    resumption_token: get_trailing_token(&mut payload)?,
+                }
+            }
+            tag::PACKET_DATA => Message::PacketData(std::mem::take(&mut payload)),
+            tag::KEEP_ALIVE => Message::KeepAlive,
+            tag::DISCONNECT => Message::Disconnect {
+                reason: get_str(&mut payload)?,
+                reconnect_after_secs: if payload.has_remaining() {
+                    Some(get_u32(&mut payload)?)
+                } else {
+                    None
+                },
+            },
+            tag::GAME_OPTIMIZATION_INFO => Message::GameOptimizationInfo {
+                game_type: get_str(&mut payload)?,
+                latency_priority: get_u8(&mut payload)? != 0,
+            },
+            tag::ROUTE_UPDATE => {
+                let count = get_u16(&mut payload)?;
+                let mut routes = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let destination = get_ip(&mut payload)?;
+                    let netmask = get_ip(&mut payload)?;
+                    let gateway = match get_u8(&mut payload)? {
+                        0 => None,
+                        _ => Some(get_ip(&mut payload)?),
+                    };
+                    routes.push(RouteInfo { destination, netmask, gateway });
+                }
+                Message::RouteUpdate { routes }
+            }
+            tag::STATS => Message::Stats {
+                bytes_sent: get_u64(&mut payload)?,
+                bytes_received: get_u64(&mut payload)?,
+                packets_sent: get_u64(&mut payload)?,
+                packets_received: get_u64(&mut payload)?,
+                latency_ms: get_u32(&mut payload)?,
+            },
+            other => {
+                return Err(VpnError::Protocol(format!("Unknown message type: {:#04x}", other)));
+            }
+        };
+
+        if payload.has_remaining() {
+            return Err(VpnError::Protocol(format!(
+                "{} trailing bytes after message type {:#04x}",
+                payload.remaining(),
+                tag
+            )));
+        }
+
+        Ok(message)
+    }
+
+    /// Encode the message as JSON, for debugging and logging only
+    #[cfg(feature = "json-debug")]
+    pub fn to_json(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_json::Error> {
-        serde_json::from_slice(bytes)
+    /// Decode a message from its JSON debug form
+    #[cfg(feature = "json-debug")]
+    pub fn from_json(bytes: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(bytes)?)
     }
-} 
\ No newline at end of file
+
+    fn tag(&self) -> u8 {
+        match self {
+            Message::ClientHello { .. } => tag::CLIENT_HELLO,
+            Message::ServerHello { .. } => tag::SERVER_HELLO,
+            Message::PacketData(_) => tag::PACKET_DATA,
+            Message::KeepAlive => tag::KEEP_ALIVE,
+            Message::Disconnect { .. } => tag::DISCONNECT,
+            Message::GameOptimizationInfo { .. } => tag::GAME_OPTIMIZATION_INFO,
+            Message::RouteUpdate { .. } => tag::ROUTE_UPDATE,
+            Message::Stats { .. } => tag::STATS,
+        }
+    }
+
+    fn payload_len_hint(&self) -> usize {
+        match self {
+            Message::PacketData(packet) => packet.len(),
+            _ => 64,
+        }
+    }
+}
+
+/// Parse a frame header, returning the type tag and payload length
+pub fn decode_header(header: &[u8]) -> Result<(u8, usize)> {
+    if header.len() < HEADER_LEN {
+        return Err(VpnError::Protocol("Truncated frame header".to_string()));
+    }
+
+    if header[0] != WIRE_VERSION {
+        return Err(VpnError::Protocol(format!("Unsupported wire version: {}", header[0])));
+    }
+
+    let payload_len = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
+    if payload_len > MAX_PAYLOAD_LEN {
+        return Err(VpnError::Protocol(format!("Frame payload too large: {} bytes", payload_len)));
+    }
+
+    Ok((header[1], payload_len))
+}
+
+/// Write a single framed message to a QUIC stream
+pub async fn write_message(stream: &mut SendStream, message: &Message) -> Result<()> {
+    let frame = message.to_bytes()?;
+    stream.write_all(&frame).await.map_err(|e| VpnError::Io(e.into()))
+}
+
+/// Read a single framed message from a QUIC stream
+pub async fn read_message(stream: &mut RecvStream) -> Result<Message> {
+    let mut header = [0u8; HEADER_LEN];
+    stream.read_exact(&mut header).await.map_err(read_error)?;
+    let (tag, payload_len) = decode_header(&header)?;
+
+    let mut payload = BytesMut::zeroed(payload_len);
+    stream.read_exact(&mut payload).await.map_err(read_error)?;
+
+    Message::decode_payload(tag, payload.freeze())
+}
+
+fn read_error(e: ReadExactError) -> VpnError {
+    match e {
+        ReadExactError::FinishedEarly => VpnError::ConnectionClosed,
+        ReadExactError::ReadError(e) => VpnError::Io(e.into()),
+    }
+}
+
+fn put_str(buf: &mut BytesMut, value: &str) -> Result<()> {
+    let len = u16::try_from(value.len())
+        .map_err(|_| VpnError::Protocol(format!("String field too long: {} bytes", value.len())))?;
+    buf.put_u16(len);
+    buf.put_slice(value.as_bytes());
+    Ok(())
+}
+
+/// Resumption tokens are a trailing field that is left out entirely when
+/// absent. They are only sent once `RESUMPTION` has been negotiated, so
+/// peers that predate it never see the extra bytes.
+fn put_trailing_token(buf: &mut BytesMut, token: &Option<Bytes>) -> Result<()> {
+    if let Some(token) = token {
+        let len = u16::try_from(token.len())
+            .map_err(|_| VpnError::Protocol(format!("Resumption token too long: {} bytes", token.len())))?;
+        buf.put_u16(len);
+        buf.put_slice(token);
+    }
+    Ok(())
+}
+
+/// The IPv6 assignment is only present once `IPV6` has been negotiated,
+/// so peers that predate it get the `ServerHello` they expect
+fn put_ipv6_assignment(
+    buf: &mut BytesMut,
+    capabilities: Capabilities,
+    assigned_ipv6: &Option<(Ipv6Addr, u8)>,
+) -> Result<()> {
+    if !capabilities.contains(Capabilities::IPV6) {
+        if assigned_ipv6.is_some() {
+            return Err(VpnError::Protocol("IPv6 address assigned without the IPv6 capability".to_string()));
+        }
+        return Ok(());
+    }
+
+    match assigned_ipv6 {
+        Some((ip, prefix_len)) => {
+            buf.put_u8(1);
+            buf.put_slice(&ip.octets());
+            buf.put_u8(*prefix_len);
+        }
+        None => buf.put_u8(0),
+    }
+    Ok(())
+}
+
+fn put_ip(buf: &mut BytesMut, ip: &IpAddr) {
+    match ip {
+        IpAddr::V4(ip) => {
+            buf.put_u8(4);
+            buf.put_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.put_u8(6);
+            buf.put_slice(&ip.octets());
+        }
+    }
+}
+
+fn ensure(payload: &Bytes, len: usize) -> Result<()> {
+    if payload.remaining() < len {
+        return Err(VpnError::Protocol("Truncated message payload".to_string()));
+    }
+    Ok(())
+}
+
+fn get_u8(payload: &mut Bytes) -> Result<u8> {
+    ensure(payload, 1)?;
+    Ok(payload.get_u8())
+}
+
+fn get_u16(payload: &mut Bytes) -> Result<u16> {
+    ensure(payload, 2)?;
+    Ok(payload.get_u16())
+}
+
+fn get_u32(payload: &mut Bytes) -> Result<u32> {
+    ensure(payload, 4)?;
+    Ok(payload.get_u32())
+}
+
+fn get_u64(payload: &mut Bytes) -> Result<u64> {
+    ensure(payload, 8)?;
+    Ok(payload.get_u64())
+}
+
+fn get_str(payload: &mut Bytes) -> Result<String> {
+    let len = get_u16(payload)? as usize;
+    ensure(payload, len)?;
+    let raw = payload.split_to(len);
+    String::from_utf8(raw.to_vec())
+        .map_err(|_| VpnError::Protocol("Invalid UTF-8 in string field".to_string()))
+}
+
+fn get_trailing_token(payload: &mut Bytes) -> Result<Option<Bytes>> {
+    if !payload.has_remaining() {
+        return Ok(None);
+    }
+
+    let len = get_u16(payload)? as usize;
+    ensure(payload, len)?;
+    Ok(Some(payload.split_to(len)))
+}
+
+fn get_ipv6_assignment(payload: &mut Bytes, capabilities: Capabilities) -> Result<Option<(Ipv6Addr, u8)>> {
+    if !capabilities.contains(Capabilities::IPV6) {
+        return Ok(None);
+    }
+
+    match get_u8(payload)? {
+        0 => Ok(None),
+        _ => {
+            ensure(payload, 16)?;
+            let mut octets = [0u8; 16];
+            payload.copy_to_slice(&mut octets);
+            Ok(Some((Ipv6Addr::from(octets), get_u8(payload)?)))
+        }
+    }
+}
+
+fn get_ip(payload: &mut Bytes) -> Result<IpAddr> {
+    match get_u8(payload)? {
+        4 => {
+            ensure(payload, 4)?;
+            let mut octets = [0u8; 4];
+            payload.copy_to_slice(&mut octets);
+            Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        6 => {
+            ensure(payload, 16)?;
+            let mut octets = [0u8; 16];
+            payload.copy_to_slice(&mut octets);
+            Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        family => Err(VpnError::Protocol(format!("Unknown address family: {}", family))),
+    }
+}
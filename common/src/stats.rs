@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::protocol::Message;
+
+/// Packet and byte counters of one tunnel session, updated by the packet tasks
+#[derive(Debug, Default)]
+pub struct TrafficCounters {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    packets_sent: AtomicU64,
+    packets_received: AtomicU64,
+}
+
+/// Point-in-time copy of [`TrafficCounters`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrafficSnapshot {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+}
+
+impl TrafficCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a packet sent into the tunnel
+    pub fn record_sent(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a packet received from the tunnel
+    pub fn record_received(&self, len: usize) {
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        TrafficSnapshot {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+        }
+    }
+}
+impl TrafficSnapshot {
+    /// `Stats` message reporting these counters and the measured round-trip time
+    pub fn to_message(self, rtt: Duration) -> Message {
+        Message::Stats {
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            packets_sent: self.packets_sent,
+            packets_received: self.packets_received,
+            latency_ms: u32::try_from(rtt.as_millis()).unwrap_or(u32::MAX),
+        }
+    }
+}
+
+/// Counters and latency the other end of a session last reported, from its
+/// point of view
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerStats {
+    pub traffic: TrafficSnapshot,
+    pub latency_ms: u32,
+}
+
+impl PeerStats {
+    /// Read a peer's `Stats` message, or `None` for any other message
+    pub fn from_message(message: &Message) -> Option<Self> {
+        match *message {
+            Message::Stats {
+                bytes_sent,
+                bytes_received,
+                packets_sent,
+                packets_received,
+                latency_ms,
+            } => Some(Self {
+                traffic: TrafficSnapshot {
+                    bytes_sent,
+                    bytes_received,
+                    packets_sent,
+                    packets_received,
+                },
+                latency_ms,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Statistics of one session: local counters plus the peer's last report
+#[derive(Debug, Default)]
+pub struct SessionStats {
+    pub traffic: TrafficCounters,
+    peer: Mutex<Option<PeerStats>>,
+}
+
+impl SessionStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_peer(&self, stats: PeerStats) {
+        *self.peer.lock().unwrap() = Some(stats);
+    }
+
+    /// What the peer last reported, if it has sent any `Stats` yet
+    pub fn peer(&self) -> Option<PeerStats> {
+        *self.peer.lock().unwrap()
+    }
+}
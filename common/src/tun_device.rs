@@ -1,54 +1,72 @@
+use bytes::{Bytes, BytesMut};
+#[cfg(unix)]
+use std::fs::File;
+#[cfg(unix)]
 use std::io::{Read, Write};
-use tun::platform::Device;
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+#[cfg(unix)]
+use tokio::io::unix::AsyncFd;
+#[cfg(unix)]
 use tun::Configuration;
+#[cfg(unix)]
+use tun::Device as _;
 use crate::error::VpnError;
 use crate::Result;
-use std::net::IpAddr;
-use std::sync::{Arc, Mutex};
-use tokio::sync::mpsc;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Arc;
 
+/// Non-blocking descriptor of the device, shared by both halves
+#[cfg(unix)]
+type Handle = Arc<AsyncFd<File>>;
+
+/// No TUN support on other platforms yet, so no device can exist
+#[cfg(not(unix))]
+type Handle = Arc<Unsupported>;
+
+#[cfg(not(unix))]
+enum Unsupported {}
+
+/// TUN interface driven by tokio through a non-blocking file descriptor.
+///
+/// Reads and writes use independent readiness, so a task blocked waiting for
+/// incoming packets never delays writes from other tasks.
 pub struct TunDevice {
-    device: Arc<Mutex<Device>>,
+    fd: Handle,
+    name: String,
     mtu: u16,
 }
 
-impl TunDevice {
-    #[cfg(target_os = "windows")]
-    pub fn new(name: Option<&str>, ip: IpAddr, netmask: IpAddr, mtu: u16) -> Result<Self> {
-        let mut config = Configuration::default();
-        
-        if let Some(name) = name {
-            config.name(name);
-        }
-        
-        config.address(ip)
-            .netmask(netmask)
-            .mtu(mtu as i32)
-            .up();
-
-        let device = tun::create(&config)
-            .map_err(|e| VpnError::Tun(format!("Failed to create TUN device: {}", e)))?;
+/// Read half of a [`TunDevice`], owning a reusable receive buffer
+#[cfg_attr(not(unix), allow(dead_code))]
+pub struct TunReader {
+    fd: Handle,
+    buffer: BytesMut,
+    packet_size: usize,
+}
 
-        Ok(Self {
-            device: Arc::new(Mutex::new(device)),
-            mtu,
-        })
-    }
+/// Write half of a [`TunDevice`]; cheap to clone and share between tasks
+#[derive(Clone)]
+pub struct TunWriter {
+    fd: Handle,
+}
 
-    #[cfg(target_os = "linux")]
+impl TunDevice {
+    #[cfg(unix)]
     pub fn new(name: Option<&str>, ip: IpAddr, netmask: IpAddr, mtu: u16) -> Result<Self> {
         let mut config = Configuration::default();
-        
+
         if let Some(name) = name {
             config.name(name);
         }
-        
+
         config.address(ip)
             .netmask(netmask)
             .mtu(mtu as i32)
             .up();
 
-        // Use persistent TUN on Linux
+        // Raw IP packets without the extra packet information header
+        #[cfg(target_os = "linux")]
         config.platform(|config| {
             config.packet_information(false);
         });
@@ -56,53 +74,150 @@ impl TunDevice {
         let device = tun::create(&config)
             .map_err(|e| VpnError::Tun(format!("Failed to create TUN device: {}", e)))?;
 
+        let name = device.name().to_string();
+        device.set_nonblock()
+            .map_err(|e| VpnError::Tun(format!("Failed to make TUN device non-blocking: {}", e)))?;
+
+        // Take ownership of the descriptor so both halves can use it directly
+        let file = unsafe { File::from_raw_fd(device.into_raw_fd()) };
+        let fd = AsyncFd::new(file)
+            .map_err(|e| VpnError::Tun(format!("Failed to register TUN device: {}", e)))?;
+
         Ok(Self {
-            device: Arc::new(Mutex::new(device)),
+            fd: Arc::new(fd),
+            name,
             mtu,
         })
     }
 
+    #[cfg(not(unix))]
+    pub fn new(_name: Option<&str>, _ip: IpAddr, _netmask: IpAddr, _mtu: u16) -> Result<Self> {
+        Err(VpnError::Tun("TUN devices are not supported on this platform yet".to_string()))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn mtu(&self) -> u16 {
         self.mtu
     }
 
-    pub fn start_reading(
-        &self, 
-        packet_sender: mpsc::Sender<Vec<u8>>
-    ) -> Result<tokio::task::JoinHandle<Result<()>>> {
-        let device = self.device.clone();
-        let mtu = self.mtu;
-
-        let handle = tokio::task::spawn_blocking(move || -> Result<()> {
-            let mut buffer = vec![0u8; mtu as usize];
-            let mut device = device.lock().unwrap();
-
-            loop {
-                match device.read(&mut buffer) {
-                    Ok(n) => {
-                        if n > 0 {
-                            let packet = buffer[..n].to_vec();
-                            if let Err(e) = packet_sender.blocking_send(packet) {
-                                return Err(VpnError::Tun(format!("Failed to send packet: {}", e)));
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        return Err(VpnError::Io(e));
-                    }
+    /// Add an IPv6 address to the interface.
+    ///
+    /// The `tun` crate only configures IPv4, so this goes through `ip` on Linux.
+    pub fn add_ipv6_address(&self, address: Ipv6Addr, prefix_len: u8) -> Result<()> {
+        // Linux disables IPv6 on links with an MTU below the IPv6 minimum
+        if self.mtu < 1280 {
+            return Err(VpnError::Tun(format!(
+                "MTU {} is below the IPv6 minimum of 1280",
+                self.mtu
+            )));
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            let status = std::process::Command::new("ip")
+                .args(["-6", "addr", "add", &format!("{}/{}", address, prefix_len), "dev", &self.name])
+                .status()
+                .map_err(|e| VpnError::Tun(format!("Failed to run ip: {}", e)))?;
+
+            if !status.success() {
+                return Err(VpnError::Tun(format!(
+                    "Failed to add {}/{} to {}: ip exited with {}",
+                    address, prefix_len, self.name, status
+                )));
+            }
+
+            Ok(())
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            Err(VpnError::Tun(format!(
+                "Cannot add {}/{} to {}: IPv6 tunnels are only supported on Linux",
+                address, prefix_len, self.name
+            )))
+        }
+    }
+
+    /// Create a read half with its own receive buffer
+    pub fn reader(&self) -> TunReader {
+        let packet_size = self.mtu as usize;
+
+        TunReader {
+            fd: self.fd.clone(),
+            buffer: BytesMut::with_capacity(packet_size * 16),
+            packet_size,
+        }
+    }
+
+    /// Create a write half
+    pub fn writer(&self) -> TunWriter {
+        TunWriter {
+            fd: self.fd.clone(),
+        }
+    }
+
+    pub fn split(&self) -> (TunReader, TunWriter) {
+        (self.reader(), self.writer())
+    }
+
+    pub async fn write_packet(&self, packet: &[u8]) -> Result<usize> {
+        self.writer().write_packet(packet).await
+    }
+}
+
+impl TunReader {
+    /// Read the next packet from the device.
+    ///
+    /// Packets are split off a shared buffer, so the allocation is reused
+    /// once earlier packets have been dropped.
+    #[cfg(unix)]
+    pub async fn read_packet(&mut self) -> Result<Bytes> {
+        loop {
+            self.buffer.reserve(self.packet_size);
+            self.buffer.resize(self.packet_size, 0);
+
+            let mut guard = self.fd.readable().await?;
+            let buffer = &mut self.buffer;
+            match guard.try_io(|fd| {
+                let mut file: &File = fd.get_ref();
+                file.read(buffer)
+            }) {
+                Ok(Ok(n)) => {
+                    self.buffer.truncate(n);
+                    return Ok(self.buffer.split().freeze());
                 }
+                Ok(Err(e)) => return Err(VpnError::Io(e)),
+                Err(_would_block) => continue,
             }
-        });
+        }
+    }
 
-        Ok(handle)
+    #[cfg(not(unix))]
+    pub async fn read_packet(&mut self) -> Result<Bytes> {
+        match *self.fd {}
     }
+}
 
+impl TunWriter {
+    #[cfg(unix)]
     pub async fn write_packet(&self, packet: &[u8]) -> Result<usize> {
-        let device = self.device.clone();
-        
-        tokio::task::spawn_blocking(move || -> Result<usize> {
-            let mut device = device.lock().unwrap();
-            device.write(packet).map_err(VpnError::Io)
-        }).await.map_err(|e| VpnError::Tun(format!("Task join error: {}", e)))?
+        loop {
+            let mut guard = self.fd.writable().await?;
+            match guard.try_io(|fd| {
+                let mut file: &File = fd.get_ref();
+                file.write(packet)
+            }) {
+                Ok(result) => return result.map_err(VpnError::Io),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub async fn write_packet(&self, _packet: &[u8]) -> Result<usize> {
+        match *self.fd {}
     }
-} 
\ No newline at end of file
+}
@@ -0,0 +1,158 @@
+//! Just enough X.509 parsing to read who a certificate belongs to and
+//! until when; chains and signatures are verified by rustls
+
+use std::time::SystemTime;
+use yasna::models::ObjectIdentifier;
+use yasna::tags::TAG_UTCTIME;
+use yasna::{ASN1Result, BERReader, BERReaderSeq, Tag};
+
+use crate::error::VpnError;
+use crate::Result;
+
+const OID_COMMON_NAME: &[u64] = &[2, 5, 4, 3];
+const OID_SUBJECT_ALT_NAME: &[u64] = &[2, 5, 29, 17];
+
+/// Serial number, expiry and public key of a certificate and the names it
+/// was issued to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateInfo {
+    /// Serial number as `format_serial` writes it
+    pub serial: String,
+    /// End of the validity period
+    pub not_after: SystemTime,
+    /// Common name of the subject
+    pub common_name: Option<String>,
+    /// DNS names among the subject alternative names
+    pub dns_names: Vec<String>,
+    /// Email addresses among the subject alternative names
+    pub emails: Vec<String>,
+    /// DER SubjectPublicKeyInfo
+    pub public_key: Vec<u8>,
+}
+
+impl CertificateInfo {
+    pub fn parse(cert_der: &[u8]) -> Result<Self> {
+        yasna::parse_der(cert_der, |reader| {
+            reader.read_sequence(|reader| {
+                let info = reader.next().read_sequence(read_tbs_certificate)?;
+                // Signature algorithm and value
+                reader.next().read_der()?;
+                reader.next().read_der()?;
+
+                Ok(info)
+            })
+        })
+        .map_err(|e| VpnError::Certificate(format!("Malformed certificate: {}", e)))
+    }
+}
+
+/// Serial number in lowercase hex without leading zeros, from its
+/// big-endian bytes
+pub fn format_serial(bytes: &[u8]) -> String {
+    let start = bytes.iter().position(|&byte| byte != 0).unwrap_or(bytes.len());
+    let serial: String = bytes[start..].iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    if serial.is_empty() {
+        "0".to_string()
+    } else {
+        serial
+    }
+}
+
+/// The key itself from a DER SubjectPublicKeyInfo, without its algorithm
+pub(crate) fn public_key_bits(spki_der: &[u8]) -> Result<Vec<u8>> {
+    yasna::parse_der(spki_der, |reader| {
+        reader.read_sequence(|reader| {
+            reader.next().read_der()?;
+            Ok(reader.next().read_bitvec_bytes()?.0)
+        })
+    })
+    .map_err(|e| VpnError::Certificate(format!("Malformed public key: {}", e)))
+}
+
+fn read_tbs_certificate(reader: &mut BERReaderSeq) -> ASN1Result<CertificateInfo> {
+    reader.read_optional(|reader| reader.read_tagged(Tag::context(0), |reader| reader.read_der()))?;
+    let serial = format_serial(reader.next().read_tagged_der()?.value());
+
+    // Signature algorithm and issuer
+    for _ in 0..2 {
+        reader.next().read_der()?;
+    }
+
+    let not_after = reader.next().read_sequence(|reader| {
+        reader.next().read_der()?;
+        read_time(reader.next())
+    })?;
+
+    let mut info = CertificateInfo {
+        serial,
+        not_after,
+        common_name: None,
+        dns_names: Vec::new(),
+        emails: Vec::new(),
+        public_key: Vec::new(),
+    };
+
+    reader.next().read_sequence_of(|reader| {
+        reader.read_set_of(|reader| {
+            reader.read_sequence(|reader| {
+                let oid = reader.next().read_oid()?;
+                let value = reader.next().read_tagged_der()?;
+
+                if oid == ObjectIdentifier::from_slice(OID_COMMON_NAME) {
+                    info.common_name = Some(String::from_utf8_lossy(value.value()).into_owned());
+                }
+                Ok(())
+            })
+        })
+    })?;
+
+    // Subject public key, then the optional unique ids and extensions
+    info.public_key = reader.next().read_der()?;
+    while let Some(field) = reader.read_optional(|reader| reader.read_tagged_der())? {
+        if field.tag() == Tag::context(3) {
+            yasna::parse_der(field.value(), |reader| read_extensions(reader, &mut info))?;
+        }
+    }
+
+    Ok(info)
+}
+
+/// UTCTime for years before 2050, GeneralizedTime after
+fn read_time(reader: BERReader) -> ASN1Result<SystemTime> {
+    let time = if reader.lookahead_tag()? == TAG_UTCTIME {
+        *reader.read_utctime()?.datetime()
+    } else {
+        *reader.read_generalized_time()?.datetime()
+    };
+
+    Ok(time.into())
+}
+
+fn read_extensions(reader: yasna::BERReader, info: &mut CertificateInfo) -> ASN1Result<()> {
+    reader.read_sequence_of(|reader| {
+        reader.read_sequence(|reader| {
+            let oid = reader.next().read_oid()?;
+            reader.read_default(false, |reader| reader.read_bool())?;
+            let value = reader.next().read_bytes()?;
+
+            if oid == ObjectIdentifier::from_slice(OID_SUBJECT_ALT_NAME) {
+                yasna::parse_der(&value, |reader| {
+                    reader.read_sequence_of(|reader| {
+                        let name = reader.read_tagged_der()?;
+                        let text = || String::from_utf8_lossy(name.value()).into_owned();
+
+                        // rfc822Name and dNSName, both IA5String
+                        if name.tag() == Tag::context(1) {
+                            info.emails.push(text());
+                        } else if name.tag() == Tag::context(2) {
+                            info.dns_names.push(text());
+                        }
+                        Ok(())
+                    })
+                })?;
+            }
+            Ok(())
+        })
+    })
+}
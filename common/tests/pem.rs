@@ -0,0 +1,126 @@
+use common::crypto::{self, CertificateAuthority, ClientAuth};
+use rustls::{ClientConnection, ServerConnection, ServerName};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Write `content` to a file of this test run in the temp directory
+fn temp_file(name: &str, content: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("quicvpn-pem-{}-{}", std::process::id(), name));
+    std::fs::write(&path, content).unwrap();
+    path
+}
+
+fn pem(tag: &str, der: &[u8]) -> String {
+    pem::encode(&pem::Pem {
+        tag: tag.to_string(),
+        contents: der.to_vec(),
+    })
+}
+
+fn error_message(result: common::Result<impl std::fmt::Debug>) -> String {
+    result.unwrap_err().to_string()
+}
+
+#[test]
+fn loads_pem_chain_in_file_order() {
+    let ca = CertificateAuthority::generate("Test CA", 30).unwrap();
+    let server = ca.issue_server(&["vpn.example".to_string()], 30).unwrap();
+
+    let chain = format!("{}{}", pem("CERTIFICATE", &server.cert_der), pem("CERTIFICATE", ca.cert_der()));
+    let path = temp_file("chain.crt", chain.as_bytes());
+
+    assert_eq!(crypto::load_certificates(&path).unwrap(), [server.cert_der.clone(), ca.cert_der().to_vec()]);
+}
+
+#[test]
+fn loads_der_certificate_and_keys() {
+    let ca = CertificateAuthority::generate("Test CA", 30).unwrap();
+    let server = ca.issue_server(&["vpn.example".to_string()], 30).unwrap();
+
+    let cert_path = temp_file("server.der", &server.cert_der);
+    let key_path = temp_file("server-der.key", &server.key_der);
+
+    assert_eq!(crypto::load_certificates(&cert_path).unwrap(), vec![server.cert_der]);
+    assert_eq!(crypto::load_private_key(&key_path).unwrap(), server.key_der);
+}
+
+#[test]
+fn loads_pkcs8_sec1_and_rsa_keys() {
+    let ca = CertificateAuthority::generate("Test CA", 30).unwrap();
+    let server = ca.issue_server(&["vpn.example".to_string()], 30).unwrap();
+    let pkcs8 = temp_file("pkcs8.key", pem("PRIVATE KEY", &server.key_der).as_bytes());
+    assert_eq!(crypto::load_private_key(&pkcs8).unwrap(), server.key_der);
+
+    let data = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+    assert!(crypto::load_private_key(&data.join("ec_sec1.key")).is_ok());
+    assert!(crypto::load_private_key(&data.join("rsa_pkcs1.key")).is_ok());
+}
+
+#[test]
+fn server_presents_loaded_chain() {
+    let ca = CertificateAuthority::generate("Test CA", 30).unwrap();
+    let server = ca.issue_server(&["vpn.example".to_string()], 30).unwrap();
+
+    let chain = format!("{}{}", pem("CERTIFICATE", &server.cert_der), pem("CERTIFICATE", ca.cert_der()));
+    let cert_path = temp_file("presented.crt", chain.as_bytes());
+    let key_path = temp_file("presented.key", pem("PRIVATE KEY", &server.key_der).as_bytes());
+
+    let cert_chain = crypto::load_certificates(&cert_path).unwrap();
+    let key = crypto::load_private_key(&key_path).unwrap();
+    let server_config = crypto::load_server_config(&cert_chain, &key, ClientAuth::None).unwrap();
+    let client_config = crypto::load_client_config(&[ca.cert_der().to_vec()], None).unwrap();
+
+    let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+    let mut client = ClientConnection::new(Arc::new(client_config), ServerName::try_from("vpn.example").unwrap()).unwrap();
+    while client.is_handshaking() || server.is_handshaking() {
+        let mut buffer = Vec::new();
+        client.write_tls(&mut buffer).unwrap();
+        server.read_tls(&mut buffer.as_slice()).unwrap();
+        server.process_new_packets().unwrap();
+
+        let mut buffer = Vec::new();
+        server.write_tls(&mut buffer).unwrap();
+        client.read_tls(&mut buffer.as_slice()).unwrap();
+        client.process_new_packets().unwrap();
+    }
+
+    assert_eq!(client.peer_certificates().unwrap().len(), 2);
+}
+
+#[test]
+fn errors_name_file_and_step() {
+    let missing = std::env::temp_dir().join("quicvpn-pem-does-not-exist.crt");
+    let message = error_message(crypto::load_certificates(&missing));
+    assert!(message.contains(&missing.display().to_string()), "{}", message);
+    assert!(message.contains("failed to read"), "{}", message);
+
+    let key_only = temp_file("key-only.pem", pem("PRIVATE KEY", b"key").as_bytes());
+    let message = error_message(crypto::load_certificates(&key_only));
+    assert!(message.contains(&key_only.display().to_string()), "{}", message);
+    assert!(message.contains("failed to find a certificate"), "{}", message);
+
+    let garbage = temp_file("garbage.der", b"not a certificate");
+    let message = error_message(crypto::load_certificates(&garbage));
+    assert!(message.contains("failed to parse certificate #1"), "{}", message);
+
+    let cert_only = temp_file("cert-only.pem", pem("CERTIFICATE", b"cert").as_bytes());
+    let message = error_message(crypto::load_private_key(&cert_only));
+    assert!(message.contains("failed to find a private key"), "{}", message);
+
+    let bad_key = temp_file("bad.key", pem("PRIVATE KEY", b"not a key").as_bytes());
+    let message = error_message(crypto::load_private_key(&bad_key));
+    assert!(message.contains(&bad_key.display().to_string()), "{}", message);
+    assert!(message.contains("failed to load private key"), "{}", message);
+}
+
+#[test]
+fn certified_key_needs_the_matching_key() {
+    let ca = CertificateAuthority::generate("Test CA", 30).unwrap();
+    let first = ca.issue_server(&["vpn.example".to_string()], 30).unwrap();
+    let second = ca.issue_server(&["vpn.example".to_string()], 30).unwrap();
+
+    assert!(crypto::certified_key(&[first.cert_der.clone(), ca.cert_der().to_vec()], &first.key_der).is_ok());
+
+    let message = error_message(crypto::certified_key(&[first.cert_der], &second.key_der).map(|_| ()));
+    assert!(message.contains("does not match"), "{}", message);
+}
@@ -0,0 +1,95 @@
+use common::crypto::{self, CertificateAuthority, ClientAuth};
+use common::x509::CertificateInfo;
+use rustls::{ClientConnection, ServerConnection, ServerName};
+use std::sync::Arc;
+
+/// Run a TLS handshake between the two configs in memory
+fn handshake(server: rustls::ServerConfig, client: rustls::ClientConfig, server_name: &str) -> Result<ServerConnection, rustls::Error> {
+    let mut server = ServerConnection::new(Arc::new(server))?;
+    let mut client = ClientConnection::new(Arc::new(client), ServerName::try_from(server_name).unwrap())?;
+
+    while client.is_handshaking() || server.is_handshaking() {
+        let mut buffer = Vec::new();
+        client.write_tls(&mut buffer).unwrap();
+        server.read_tls(&mut buffer.as_slice()).unwrap();
+        server.process_new_packets()?;
+
+        let mut buffer = Vec::new();
+        server.write_tls(&mut buffer).unwrap();
+        client.read_tls(&mut buffer.as_slice()).unwrap();
+        client.process_new_packets()?;
+    }
+
+    Ok(server)
+}
+
+#[test]
+fn issued_certificates_authenticate_both_sides() {
+    let ca = CertificateAuthority::generate("Test CA", 30).unwrap();
+    let roots = vec![ca.cert_der().to_vec()];
+    let server = ca.issue_server(&["vpn.example".to_string(), "192.0.2.1".to_string()], 30).unwrap();
+    let client = ca.issue_client("gamer", 30).unwrap();
+
+    let server_config = crypto::load_server_config(std::slice::from_ref(&server.cert_der), &server.key_der, ClientAuth::Required(&roots)).unwrap();
+    let client_config = crypto::load_client_config(&roots, Some((std::slice::from_ref(&client.cert_der), &client.key_der))).unwrap();
+
+    let connection = handshake(server_config, client_config, "vpn.example").unwrap();
+    let presented = connection.peer_certificates().unwrap();
+    let info = CertificateInfo::parse(&presented[0].0).unwrap();
+    assert_eq!(info.common_name.as_deref(), Some("gamer"));
+    assert_eq!(info.serial, client.serial);
+}
+
+#[test]
+fn reloaded_ca_keeps_issuing_trusted_certificates() {
+    let ca = CertificateAuthority::generate("Test CA", 30).unwrap();
+    let roots = vec![ca.cert_der().to_vec()];
+    let reloaded = CertificateAuthority::load(ca.cert_der(), &ca.key_der()).unwrap();
+    let server = reloaded.issue_server(&["vpn.example".to_string()], 30).unwrap();
+
+    let server_config = crypto::load_server_config(std::slice::from_ref(&server.cert_der), &server.key_der, ClientAuth::None).unwrap();
+    let client_config = crypto::load_client_config(&roots, None).unwrap();
+
+    assert!(handshake(server_config, client_config, "vpn.example").is_ok());
+}
+
+#[test]
+fn rejects_clients_without_certificate_when_required() {
+    let ca = CertificateAuthority::generate("Test CA", 30).unwrap();
+    let roots = vec![ca.cert_der().to_vec()];
+    let server = ca.issue_server(&["vpn.example".to_string()], 30).unwrap();
+
+    let required = crypto::load_server_config(std::slice::from_ref(&server.cert_der), &server.key_der, ClientAuth::Required(&roots)).unwrap();
+    let client_config = crypto::load_client_config(&roots, None).unwrap();
+    assert!(handshake(required, client_config.clone(), "vpn.example").is_err());
+
+    let optional = crypto::load_server_config(std::slice::from_ref(&server.cert_der), &server.key_der, ClientAuth::Optional(&roots)).unwrap();
+    assert!(handshake(optional, client_config, "vpn.example").is_ok());
+}
+
+#[test]
+fn rejects_certificates_of_other_cas() {
+    let ca = CertificateAuthority::generate("Test CA", 30).unwrap();
+    let roots = vec![ca.cert_der().to_vec()];
+    let other = CertificateAuthority::generate("Other CA", 30).unwrap();
+    let server = ca.issue_server(&["vpn.example".to_string()], 30).unwrap();
+    let client = other.issue_client("gamer", 30).unwrap();
+
+    let server_config = crypto::load_server_config(std::slice::from_ref(&server.cert_der), &server.key_der, ClientAuth::Required(&roots)).unwrap();
+    let client_config = crypto::load_client_config(&roots, Some((std::slice::from_ref(&client.cert_der), &client.key_der))).unwrap();
+
+    assert!(handshake(server_config, client_config, "vpn.example").is_err());
+}
+
+#[test]
+fn fingerprints_identify_the_key() {
+    let ca = CertificateAuthority::generate("Test CA", 30).unwrap();
+    let first = ca.issue_server(&["vpn.example".to_string()], 30).unwrap();
+    let second = ca.issue_server(&["vpn.example".to_string()], 30).unwrap();
+
+    let fingerprint = crypto::spki_fingerprint(&first.cert_der).unwrap();
+    assert_eq!(fingerprint.len(), 64);
+    assert!(fingerprint.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()));
+    assert_eq!(fingerprint, crypto::spki_fingerprint(&first.cert_der).unwrap());
+    assert_ne!(fingerprint, crypto::spki_fingerprint(&second.cert_der).unwrap());
+}
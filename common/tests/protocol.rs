@@ -0,0 +1,370 @@
+use bytes::Bytes;
+use common::protocol::{
+    decode_header, negotiate_version, Capabilities, Message, RouteInfo, HEADER_LEN,
+    MAX_PAYLOAD_LEN, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, WIRE_VERSION,
+};
+use common::stats::{PeerStats, TrafficCounters, TrafficSnapshot};
+use std::net::IpAddr;
+use std::time::Duration;
+
+fn all_variants() -> Vec<Message> {
+    vec![
+        Message::ClientHello {
+            username: "gamer".to_string(),
+            password: "pässwörd".to_string(),
+            client_version: "0.1.0".to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::DATAGRAMS.union(Capabilities::STATS),
+            resumption_token: None,
+        },
+        Message::ClientHello {
+            username: "gamer".to_string(),
+            password: "secret".to_string(),
+            client_version: "0.1.0".to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::RESUMPTION,
+            resumption_token: Some(Bytes::from_static(&[7; 32])),
+        },
+        Message::ServerHello {
+            server_version: "0.1.0".to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::empty(),
+            assigned_ip: "10.10.0.2".parse().unwrap(),
+            subnet_mask: "255.255.255.0".parse().unwrap(),
+            mtu: 1400,
+            assigned_ipv6: None,
+            resumption_token: None,
+        },
+        Message::ServerHello {
+            server_version: "0.1.0".to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::IPV6,
+            assigned_ip: "10.10.0.3".parse().unwrap(),
+            subnet_mask: "255.255.255.0".parse().unwrap(),
+            mtu: 1280,
+            assigned_ipv6: Some(("fd00:10:10::3".parse().unwrap(), 64)),
+            resumption_token: Some(Bytes::from_static(b"token")),
+        },
+        Message::ServerHello {
+            server_version: "0.1.0".to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::IPV6.union(Capabilities::RESUMPTION),
+            assigned_ip: "10.10.0.4".parse().unwrap(),
+            subnet_mask: "255.255.255.0".parse().unwrap(),
+            mtu: 1280,
+            assigned_ipv6: None,
+            resumption_token: Some(Bytes::from_static(b"token")),
+        },
+        Message::PacketData(Bytes::from_static(&[0x45, 0x00, 0x00, 0x14, 0xde, 0xad, 0xbe, 0xef])),
+        Message::PacketData(Bytes::new()),
+        Message::KeepAlive,
+        Message::disconnect("Authentication failed"),
+        Message::Disconnect {
+            reason: "Server shutting down".to_string(),
+            reconnect_after_secs: Some(10),
+        },
+        Message::GameOptimizationInfo {
+            game_type: "fps".to_string(),
+            latency_priority: true,
+        },
+        Message::RouteUpdate {
+            routes: vec![
+                RouteInfo {
+                    destination: "192.168.1.0".parse().unwrap(),
+                    netmask: "255.255.255.0".parse().unwrap(),
+                    gateway: None,
+                },
+                RouteInfo {
+                    destination: "fd00::".parse().unwrap(),
+                    netmask: "ffff:ffff:ffff:ffff::".parse().unwrap(),
+                    gateway: Some("fd00::1".parse::<IpAddr>().unwrap()),
+                },
+            ],
+        },
+        Message::RouteUpdate { routes: vec![] },
+        Message::Stats {
+            bytes_sent: u64::MAX,
+            bytes_received: 1,
+            packets_sent: 42,
+            packets_received: 7,
+            latency_ms: 23,
+        },
+    ]
+}
+
+#[test]
+fn round_trip_every_variant() {
+    for message in all_variants() {
+        let frame = message.to_bytes().unwrap();
+        assert_eq!(frame[0], WIRE_VERSION);
+
+        let (_, payload_len) = decode_header(&frame[..HEADER_LEN]).unwrap();
+        assert_eq!(payload_len, frame.len() - HEADER_LEN);
+
+        let decoded = Message::from_bytes(frame).unwrap();
+        assert_eq!(decoded, message);
+    }
+}
+
+#[test]
+fn packet_data_is_raw_payload() {
+    let packet = Bytes::from(vec![0xab; 1400]);
+    let frame = Message::PacketData(packet.clone()).to_bytes().unwrap();
+
+    assert_eq!(frame.len(), HEADER_LEN + packet.len());
+    assert_eq!(&frame[HEADER_LEN..], &packet[..]);
+}
+
+#[test]
+fn packet_data_round_trips_up_to_the_payload_limit() {
+    for len in [0, 1, 1400, MAX_PAYLOAD_LEN] {
+        let packet = Bytes::from((0..len).map(|i| i as u8).collect::<Vec<_>>());
+        let frame = Message::PacketData(packet.clone()).to_bytes().unwrap();
+
+        assert_eq!(Message::from_bytes(frame).unwrap(), Message::PacketData(packet));
+    }
+}
+
+#[test]
+fn packet_data_rejects_short_and_trailing_input() {
+    let frame = Message::PacketData(Bytes::from_static(&[0x45; 20])).to_bytes().unwrap();
+
+    // Cut inside the header
+    assert!(Message::from_bytes(frame.slice(..HEADER_LEN - 1)).is_err());
+    // Header announces more payload than follows
+    assert!(Message::from_bytes(frame.slice(..frame.len() - 1)).is_err());
+
+    // Bytes after the announced payload
+    let mut trailing = frame.to_vec();
+    trailing.push(0);
+    assert!(Message::from_bytes(Bytes::from(trailing)).is_err());
+}
+
+#[test]
+fn rejects_oversized_payload_length() {
+    let mut frame = Message::PacketData(Bytes::new()).to_bytes().unwrap().to_vec();
+    frame[2..HEADER_LEN].copy_from_slice(&(MAX_PAYLOAD_LEN as u32 + 1).to_be_bytes());
+
+    assert!(decode_header(&frame).is_err());
+    assert!(Message::from_bytes(Bytes::from(frame)).is_err());
+}
+
+#[test]
+fn rejects_unknown_version() {
+    let mut frame = Message::KeepAlive.to_bytes().unwrap().to_vec();
+    frame[0] = WIRE_VERSION + 1;
+
+    assert!(Message::from_bytes(Bytes::from(frame)).is_err());
+}
+
+#[test]
+fn rejects_unknown_type() {
+    let mut frame = Message::KeepAlive.to_bytes().unwrap().to_vec();
+    frame[1] = 0xff;
+
+    assert!(Message::from_bytes(Bytes::from(frame)).is_err());
+}
+
+#[test]
+fn rejects_truncated_payload() {
+    let frame = Message::disconnect("bye").to_bytes().unwrap();
+    let mut truncated = frame.slice(..frame.len() - 1).to_vec();
+    let payload_len = (truncated.len() - HEADER_LEN) as u32;
+    truncated[2..HEADER_LEN].copy_from_slice(&payload_len.to_be_bytes());
+
+    assert!(Message::from_bytes(Bytes::from(truncated)).is_err());
+}
+
+#[test]
+fn rejects_trailing_bytes() {
+    let mut frame = Message::KeepAlive.to_bytes().unwrap().to_vec();
+    frame.push(0);
+    frame[2..HEADER_LEN].copy_from_slice(&1u32.to_be_bytes());
+
+    assert!(Message::from_bytes(Bytes::from(frame)).is_err());
+}
+
+#[test]
+fn hello_keeps_unknown_capability_bits() {
+    let message = Message::ClientHello {
+        username: "gamer".to_string(),
+        password: "secret".to_string(),
+        client_version: "9.9.9".to_string(),
+        protocol_version: PROTOCOL_VERSION + 1,
+        capabilities: Capabilities::from_bits(1 << 31).union(Capabilities::DATAGRAMS),
+        resumption_token: None,
+    };
+
+    let decoded = Message::from_bytes(message.to_bytes().unwrap()).unwrap();
+    match decoded {
+        Message::ClientHello { protocol_version, capabilities, .. } => {
+            assert_eq!(protocol_version, PROTOCOL_VERSION + 1);
+            assert_eq!(
+                capabilities.intersection(Capabilities::DATAGRAMS),
+                Capabilities::DATAGRAMS
+            );
+            assert_eq!(capabilities.bits() & (1 << 31), 1 << 31);
+        }
+        other => panic!("unexpected message: {:?}", other),
+    }
+}
+
+#[test]
+fn hello_without_token_has_no_trailing_field() {
+    let hello = |resumption_token| Message::ClientHello {
+        username: "gamer".to_string(),
+        password: "secret".to_string(),
+        client_version: "0.1.0".to_string(),
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: Capabilities::empty(),
+        resumption_token,
+    };
+
+    let plain = hello(None).to_bytes().unwrap();
+    let with_token = hello(Some(Bytes::from_static(&[1; 32]))).to_bytes().unwrap();
+
+    // Older peers see exactly the frame they always did
+    assert_eq!(with_token.len(), plain.len() + 2 + 32);
+    assert_eq!(&with_token[HEADER_LEN..plain.len()], &plain[HEADER_LEN..]);
+}
+
+#[test]
+fn server_hello_without_ipv6_keeps_pre_ipv6_layout() {
+    // ServerHello as peers from before IPv6 support encode it
+    let mut legacy = Vec::new();
+    legacy.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    legacy.extend_from_slice(&Capabilities::RESUMPTION.bits().to_be_bytes());
+    legacy.extend_from_slice(&[0, 5]);
+    legacy.extend_from_slice(b"0.1.0");
+    legacy.extend_from_slice(&[4, 10, 10, 0, 2]);
+    legacy.extend_from_slice(&[4, 255, 255, 255, 0]);
+    legacy.extend_from_slice(&1400u16.to_be_bytes());
+    legacy.extend_from_slice(&[0, 5]);
+    legacy.extend_from_slice(b"token");
+
+    let message = Message::ServerHello {
+        server_version: "0.1.0".to_string(),
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: Capabilities::RESUMPTION,
+        assigned_ip: "10.10.0.2".parse().unwrap(),
+        subnet_mask: "255.255.255.0".parse().unwrap(),
+        mtu: 1400,
+        assigned_ipv6: None,
+        resumption_token: Some(Bytes::from_static(b"token")),
+    };
+    let encoded = message.to_bytes().unwrap();
+    assert_eq!(&encoded[HEADER_LEN..], legacy.as_slice());
+
+    let decoded = Message::decode_payload(encoded[1], Bytes::from(legacy)).unwrap();
+    assert_eq!(decoded, message);
+}
+
+#[test]
+fn ipv6_address_needs_ipv6_capability() {
+    let message = Message::ServerHello {
+        server_version: "0.1.0".to_string(),
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: Capabilities::empty(),
+        assigned_ip: "10.10.0.2".parse().unwrap(),
+        subnet_mask: "255.255.255.0".parse().unwrap(),
+        mtu: 1400,
+        assigned_ipv6: Some(("fd00:10:10::2".parse().unwrap(), 64)),
+        resumption_token: None,
+    };
+
+    assert!(message.to_bytes().is_err());
+}
+
+#[test]
+fn version_negotiation() {
+    assert_eq!(negotiate_version(PROTOCOL_VERSION), Some(PROTOCOL_VERSION));
+    assert_eq!(negotiate_version(PROTOCOL_VERSION + 1), Some(PROTOCOL_VERSION));
+    if MIN_PROTOCOL_VERSION > 0 {
+        assert_eq!(negotiate_version(MIN_PROTOCOL_VERSION - 1), None);
+    }
+}
+
+#[test]
+fn capabilities_display() {
+    assert_eq!(Capabilities::empty().to_string(), "none");
+    assert_eq!(
+        Capabilities::DATAGRAMS.union(Capabilities::IPV6).to_string(),
+        "datagrams,ipv6"
+    );
+}
+
+#[test]
+fn route_prefix_len() {
+    let route = |destination: &str, netmask: &str| RouteInfo {
+        destination: destination.parse().unwrap(),
+        netmask: netmask.parse().unwrap(),
+        gateway: None,
+    };
+
+    assert_eq!(route("10.0.0.0", "255.0.0.0").prefix_len(), Some(8));
+    assert_eq!(route("192.168.1.7", "255.255.255.255").prefix_len(), Some(32));
+    assert_eq!(route("fd00::", "ffff:ffff:ffff:ffff::").prefix_len(), Some(64));
+    assert_eq!(route("10.0.0.0", "255.0.255.0").prefix_len(), None);
+    assert_eq!(route("10.0.0.0", "ffff::").prefix_len(), None);
+
+    assert!(route("0.0.0.0", "0.0.0.0").is_default());
+    assert!(route("::", "::").is_default());
+    assert!(!route("10.0.0.0", "255.0.0.0").is_default());
+}
+
+#[test]
+fn stats_message_carries_counters() {
+    let counters = TrafficCounters::new();
+    counters.record_sent(1200);
+    counters.record_sent(800);
+    counters.record_received(60);
+
+    let message = counters.snapshot().to_message(Duration::from_micros(12_900));
+    let decoded = Message::from_bytes(message.to_bytes().unwrap()).unwrap();
+    let stats = PeerStats::from_message(&decoded).unwrap();
+
+    assert_eq!(stats.traffic, counters.snapshot());
+    assert_eq!(stats.traffic.packets_sent, 2);
+    assert_eq!(stats.latency_ms, 12);
+    assert_eq!(PeerStats::from_message(&Message::KeepAlive), None);
+}
+
+#[test]
+fn stats_round_trip_through_snapshot() {
+    let snapshot = TrafficSnapshot {
+        bytes_sent: u64::MAX,
+        bytes_received: 0,
+        packets_sent: 1 << 40,
+        packets_received: 3,
+    };
+
+    for (rtt, latency_ms) in [(Duration::ZERO, 0), (Duration::from_secs(u64::MAX), u32::MAX)] {
+        let frame = snapshot.to_message(rtt).to_bytes().unwrap();
+        assert_eq!(frame.len(), HEADER_LEN + 4 * 8 + 4);
+
+        let stats = PeerStats::from_message(&Message::from_bytes(frame).unwrap()).unwrap();
+        assert_eq!(stats, PeerStats { traffic: snapshot, latency_ms });
+    }
+}
+
+#[test]
+fn stats_rejects_short_and_trailing_payloads() {
+    let frame = TrafficSnapshot::default().to_message(Duration::ZERO).to_bytes().unwrap();
+    let payload = frame.slice(HEADER_LEN..);
+
+    let short = payload.slice(..payload.len() - 1);
+    assert!(Message::decode_payload(frame[1], short).is_err());
+
+    let mut trailing = payload.to_vec();
+    trailing.push(0);
+    assert!(Message::decode_payload(frame[1], Bytes::from(trailing)).is_err());
+}
+
+#[cfg(feature = "json-debug")]
+#[test]
+fn json_debug_round_trip() {
+    for message in all_variants() {
+        let json = message.to_json().unwrap();
+        assert_eq!(Message::from_json(&json).unwrap(), message);
+    }
+}
@@ -0,0 +1,38 @@
+use common::storage::write_atomic;
+use std::fs;
+use std::path::PathBuf;
+
+fn temp_path(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("quicvpn-storage-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.join("state.json")
+}
+
+#[test]
+fn replaces_contents_without_leaving_a_temporary_file() {
+    let path = temp_path("replace");
+
+    write_atomic(&path, b"first").unwrap();
+    write_atomic(&path, b"second").unwrap();
+
+    assert_eq!(fs::read(&path).unwrap(), b"second");
+    assert!(!path.with_file_name("state.json.tmp").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn stale_temporary_file_does_not_loosen_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = temp_path("stale");
+    let tmp_path = path.with_file_name("state.json.tmp");
+    fs::write(&tmp_path, b"left over by a crash").unwrap();
+    fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o644)).unwrap();
+
+    write_atomic(&path, b"secret").unwrap();
+
+    assert_eq!(fs::read(&path).unwrap(), b"secret");
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    assert!(!tmp_path.exists());
+}
@@ -0,0 +1,61 @@
+use common::x509::CertificateInfo;
+use rcgen::{Certificate, CertificateParams, DnType, SanType};
+use std::time::SystemTime;
+
+fn certificate(common_name: Option<&str>, subject_alt_names: Vec<SanType>) -> Vec<u8> {
+    let mut params = CertificateParams::default();
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    if let Some(common_name) = common_name {
+        params.distinguished_name.push(DnType::OrganizationName, "QUIC VPN");
+        params.distinguished_name.push(DnType::CommonName, common_name);
+    }
+    params.subject_alt_names = subject_alt_names;
+
+    Certificate::from_params(params).unwrap().serialize_der().unwrap()
+}
+
+#[test]
+fn reads_common_name_and_subject_alt_names() {
+    let der = certificate(
+        Some("gamer"),
+        vec![
+            SanType::DnsName("gamer.vpn.example".to_string()),
+            SanType::Rfc822Name("gamer@example.com".to_string()),
+            SanType::DnsName("laptop.vpn.example".to_string()),
+        ],
+    );
+
+    let info = CertificateInfo::parse(&der).unwrap();
+    assert_eq!(info.common_name.as_deref(), Some("gamer"));
+    assert_eq!(info.dns_names, ["gamer.vpn.example", "laptop.vpn.example"]);
+    assert_eq!(info.emails, ["gamer@example.com"]);
+}
+
+#[test]
+fn certificate_without_names() {
+    let info = CertificateInfo::parse(&certificate(None, Vec::new())).unwrap();
+    assert_eq!(info.common_name, None);
+    assert!(info.dns_names.is_empty());
+    assert!(info.emails.is_empty());
+}
+
+#[test]
+fn rejects_garbage() {
+    assert!(CertificateInfo::parse(b"not a certificate").is_err());
+
+    let mut der = certificate(Some("gamer"), Vec::new());
+    der.truncate(der.len() / 2);
+    assert!(CertificateInfo::parse(&der).is_err());
+}
+
+#[test]
+fn reads_expiry_before_and_after_2050() {
+    for not_after in [rcgen::date_time_ymd(2031, 5, 17), rcgen::date_time_ymd(2061, 1, 2)] {
+        let mut params = CertificateParams::default();
+        params.not_after = not_after;
+        let der = Certificate::from_params(params).unwrap().serialize_der().unwrap();
+
+        let info = CertificateInfo::parse(&der).unwrap();
+        assert_eq!(info.not_after, SystemTime::from(not_after));
+    }
+}
@@ -0,0 +1,501 @@
+use anyhow::{anyhow, bail, Result};
+use clap::Subcommand;
+use common::config::ServerConfig;
+use common::stats::TrafficSnapshot;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::client_manager::ClientManager;
+use crate::http;
+use crate::reload::Reloader;
+
+/// Longest request line accepted on the admin socket
+const MAX_REQUEST_LEN: u64 = 4096;
+
+/// How long an HTTP client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reason sent to clients disconnected through the admin API
+const KICK_REASON: &str = "Disconnected by administrator";
+
+/// Request sent to the running server, one JSON object per line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminRequest {
+    Sessions,
+    Kick { address: IpAddr },
+    KickUser { username: String },
+    Reload,
+}
+
+/// Reply from the running server, one JSON object per line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum AdminResponse {
+    Sessions { sessions: Vec<SessionReport> },
+    Disconnected { sessions: usize },
+    Reloaded { users: usize, disconnected: usize },
+    Error { message: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionReport {
+    pub username: String,
+    pub assigned_ip: IpAddr,
+    pub assigned_ipv6: Option<Ipv6Addr>,
+    pub remote_addr: SocketAddr,
+    pub connected_secs: u64,
+    /// Tunnel traffic, sent is towards the client
+    pub traffic: TrafficSnapshot,
+    pub rtt_ms: u64,
+}
+
+/// Inspect and control a running server
+#[derive(Subcommand, Debug)]
+pub enum AdminCommand {
+    /// Show connected sessions
+    Sessions,
+
+    /// Disconnect the session holding an address
+    Kick {
+        address: IpAddr,
+    },
+
+    /// Disconnect every session of a user
+    KickUser {
+        username: String,
+    },
+
+    /// Reload users and configuration
+    Reload,
+}
+
+/// Carries out admin requests, whichever transport they arrive on
+#[derive(Clone)]
+pub struct AdminApi {
+    client_manager: ClientManager,
+    reloader: Reloader,
+}
+
+impl AdminApi {
+    pub fn new(client_manager: ClientManager, reloader: Reloader) -> Self {
+        Self {
+            client_manager,
+            reloader,
+        }
+    }
+
+    pub async fn handle(&self, request: AdminRequest) -> AdminResponse {
+        match request {
+            AdminRequest::Sessions => {
+                let mut sessions: Vec<SessionReport> = self.client_manager.sessions()
+                    .into_iter()
+                    .map(|session| SessionReport {
+                        username: session.username,
+                        assigned_ip: session.assigned_ip,
+                        assigned_ipv6: session.assigned_ipv6,
+                        remote_addr: session.remote_addr,
+                        connected_secs: session.connected_for.as_secs(),
+                        traffic: session.traffic,
+                        rtt_ms: session.rtt.as_millis() as u64,
+                    })
+                    .collect();
+                sessions.sort_by(|a, b| (&a.username, a.assigned_ip).cmp(&(&b.username, b.assigned_ip)));
+
+                AdminResponse::Sessions { sessions }
+            }
+            AdminRequest::Kick { address } => {
+                if self.client_manager.disconnect_address(address, KICK_REASON) {
+                    AdminResponse::Disconnected { sessions: 1 }
+                } else {
+                    AdminResponse::Error {
+                        message: format!("No session has address {}", address),
+                    }
+                }
+            }
+            AdminRequest::KickUser { username } => {
+                match self.client_manager.disconnect_user(&username, KICK_REASON) {
+                    0 => AdminResponse::Error {
+                        message: format!("User {} has no sessions", username),
+                    },
+                    sessions => AdminResponse::Disconnected { sessions },
+                }
+            }
+            AdminRequest::Reload => match self.reloader.reload().await {
+                Ok(summary) => {
+                    info!(
+                        "Reloaded {} users through the admin API, disconnected {} sessions that lost access",
+                        summary.users, summary.disconnected
+                    );
+                    AdminResponse::Reloaded {
+                        users: summary.users,
+                        disconnected: summary.disconnected,
+                    }
+                }
+                Err(e) => AdminResponse::Error {
+                    message: e.to_string(),
+                },
+            },
+        }
+    }
+
+    /// Listen on the Unix socket at `path` until the returned guard is dropped
+    pub fn serve_socket(&self, path: &Path) -> Result<SocketGuard> {
+        // A server that crashed leaves its socket behind
+        if path.exists() {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                bail!("Admin socket {} is in use by another server", path.display());
+            }
+            std::fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)
+            .map_err(|e| anyhow!("Failed to bind admin socket {}: {}", path.display(), e))?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        info!("Admin socket listening on {}", path.display());
+
+        let api = self.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("Failed to accept admin connection: {}", e);
+                        continue;
+                    }
+                };
+
+                let api = api.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = api.handle_socket(stream).await {
+                        debug!("Admin connection failed: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(SocketGuard {
+            path: path.to_path_buf(),
+            task,
+        })
+    }
+
+    /// Serve the HTTP/JSON API on `addr` in the background
+    pub async fn serve_http(&self, addr: SocketAddr, token: String) -> Result<()> {
+        if !addr.ip().is_loopback() {
+            warn!("Admin HTTP API listens on {}, which is not a loopback address", addr);
+        }
+
+        let listener = TcpListener::bind(addr).await
+            .map_err(|e| anyhow!("Failed to bind admin HTTP listener {}: {}", addr, e))?;
+        info!("Admin HTTP API listening on http://{}", addr);
+
+        let api = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("Failed to accept admin HTTP connection: {}", e);
+                        continue;
+                    }
+                };
+
+                let api = api.clone();
+                let token = token.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = api.handle_http(stream, &token).await {
+                        debug!("Admin HTTP request failed: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn handle_socket(&self, stream: UnixStream) -> Result<()> {
+        // The socket is private to the server's user, but check the peer
+        // in case its directory is shared
+        let peer = stream.peer_cred()?;
+        let own_uid = unsafe { libc::geteuid() };
+        if peer.uid() != 0 && peer.uid() != own_uid {
+            warn!("Rejected admin connection from uid {}", peer.uid());
+            return Ok(());
+        }
+
+        let (reader, mut writer) = stream.into_split();
+
+        let mut line = String::new();
+        BufReader::new(reader.take(MAX_REQUEST_LEN)).read_line(&mut line).await?;
+
+        let response = match serde_json::from_str::<AdminRequest>(&line) {
+            Ok(request) => self.handle(request).await,
+            Err(e) => AdminResponse::Error {
+                message: format!("Invalid request: {}", e),
+            },
+        };
+
+        let mut reply = serde_json::to_string(&response)?;
+        reply.push('\n');
+        writer.write_all(reply.as_bytes()).await?;
+        writer.shutdown().await?;
+
+        Ok(())
+    }
+
+    async fn handle_http(&self, mut stream: TcpStream, token: &str) -> Result<()> {
+        let request = match tokio::time::timeout(REQUEST_TIMEOUT, http::read_request(&mut stream)).await
+            .map_err(|_| anyhow!("Timed out reading request"))?
+        {
+            Ok(request) => request,
+            Err(e) => {
+                return json_response(stream, e.status, &AdminResponse::Error {
+                    message: e.to_string(),
+                }).await;
+            }
+        };
+
+        if !authorized(&request, token) {
+            warn!("Rejected unauthenticated admin HTTP request for {}", request.path);
+            return json_response(stream, 401, &AdminResponse::Error {
+                message: "Missing or invalid bearer token".to_string(),
+            }).await;
+        }
+
+        let admin_request = match route(&request.method, &request.path) {
+            Ok(admin_request) => admin_request,
+            Err((status, message)) => {
+                return json_response(stream, status, &AdminResponse::Error { message }).await;
+            }
+        };
+
+        // Kicks fail when nothing matches; anything else failing is ours
+        let not_found = matches!(admin_request, AdminRequest::Kick { .. } | AdminRequest::KickUser { .. });
+        let response = self.handle(admin_request).await;
+        let status = match &response {
+            AdminResponse::Error { .. } if not_found => 404,
+            AdminResponse::Error { .. } => 500,
+            _ => 200,
+        };
+
+        json_response(stream, status, &response).await
+    }
+}
+
+/// Removes the admin socket when the server stops
+pub struct SocketGuard {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl Drop for SocketGuard {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Send one request to the running server and wait for its reply
+pub async fn request(path: &Path, request: &AdminRequest) -> Result<AdminResponse> {
+    let stream = UnixStream::connect(path).await
+        .map_err(|e| anyhow!("Server is not running (admin socket {}: {})", path.display(), e))?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+
+    let mut response = String::new();
+    BufReader::new(reader).read_line(&mut response).await?;
+    if response.is_empty() {
+        bail!("Server closed the admin connection without replying");
+    }
+
+    Ok(serde_json::from_str(&response)?)
+}
+
+/// Run an admin command against the server named in the config
+pub async fn run(config: &ServerConfig, command: AdminCommand) -> Result<()> {
+    let request = match command {
+        AdminCommand::Sessions => AdminRequest::Sessions,
+        AdminCommand::Kick { address } => AdminRequest::Kick { address },
+        AdminCommand::KickUser { username } => AdminRequest::KickUser { username },
+        AdminCommand::Reload => AdminRequest::Reload,
+    };
+
+    match self::request(&config.admin.socket_path, &request).await? {
+        AdminResponse::Sessions { sessions } => {
+            if sessions.is_empty() {
+                println!("No sessions");
+            }
+
+            for session in sessions {
+                let ipv6 = session.assigned_ipv6.map(|ip| ip.to_string()).unwrap_or_default();
+                println!(
+                    "{:<24} {:<15} {:<24} {:<22} {:>7}s {:>12} B out {:>12} B in {:>5} ms",
+                    session.username,
+                    session.assigned_ip,
+                    ipv6,
+                    session.remote_addr,
+                    session.connected_secs,
+                    session.traffic.bytes_sent,
+                    session.traffic.bytes_received,
+                    session.rtt_ms
+                );
+            }
+        }
+        AdminResponse::Disconnected { sessions } => println!("Disconnected {} session(s)", sessions),
+        AdminResponse::Reloaded { users, disconnected } => {
+            println!("Reloaded {} users, disconnected {} session(s)", users, disconnected)
+        }
+        AdminResponse::Error { message } => bail!(message),
+    }
+
+    Ok(())
+}
+
+async fn json_response(stream: TcpStream, status: u16, response: &AdminResponse) -> Result<()> {
+    let body = serde_json::to_vec(response)?;
+    http::write_response(stream, status, "application/json", &body).await
+}
+
+/// Whether the request carries `token` as its bearer token
+fn authorized(request: &http::Request, token: &str) -> bool {
+    request.header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|presented| constant_time_eq(presented.trim().as_bytes(), token.as_bytes()))
+}
+
+/// The admin request an HTTP endpoint stands for, or the status and message
+/// to reject it with
+fn route(method: &str, path: &str) -> std::result::Result<AdminRequest, (u16, String)> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("GET", ["sessions"]) => Ok(AdminRequest::Sessions),
+        ("DELETE", ["sessions", address]) => match address.parse() {
+            Ok(address) => Ok(AdminRequest::Kick { address }),
+            Err(_) => Err((400, format!("Invalid address: {}", address))),
+        },
+        ("DELETE", ["users", username, "sessions"]) => match percent_decode(username) {
+            Ok(username) => Ok(AdminRequest::KickUser { username }),
+            Err(e) => Err((400, e.to_string())),
+        },
+        ("POST", ["reload"]) => Ok(AdminRequest::Reload),
+        _ => Err((404, format!("No endpoint {} {}", method, path))),
+    }
+}
+
+/// Compare secrets without leaking where they differ through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Decode `%XX` escapes in a path segment
+fn percent_decode(segment: &str) -> Result<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            // `from_str_radix` alone would also take a sign, as in "%+1"
+            let hex = segment.get(i + 1..i + 3)
+                .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                .ok_or_else(|| anyhow!("Invalid escape in {}", segment))?;
+            decoded.push(u8::from_str_radix(hex, 16)?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| anyhow!("Invalid UTF-8 in {}", segment))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn request_with(authorization: Option<&str>) -> http::Request {
+        let mut raw = String::from("GET /sessions HTTP/1.1\r\nHost: localhost\r\n");
+        if let Some(authorization) = authorization {
+            raw.push_str(&format!("Authorization: {}\r\n", authorization));
+        }
+        raw.push_str("\r\n");
+
+        http::read_request(raw.as_bytes()).await.unwrap()
+    }
+
+    #[test]
+    fn percent_decode_handles_escapes() {
+        assert_eq!(percent_decode("alice").unwrap(), "alice");
+        assert_eq!(percent_decode("first%20last").unwrap(), "first last");
+        assert_eq!(percent_decode("a%2Fb%2fc").unwrap(), "a/b/c");
+        assert_eq!(percent_decode("j%C3%B6rg").unwrap(), "jörg");
+        assert_eq!(percent_decode("").unwrap(), "");
+
+        for invalid in ["%", "%2", "abc%", "%zz", "%+1", "%-1", "%%20", "%c3"] {
+            assert!(percent_decode(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_secrets() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"secret", b""));
+    }
+
+    #[tokio::test]
+    async fn bearer_token_must_match() {
+        assert!(authorized(&request_with(Some("Bearer s3cret")).await, "s3cret"));
+        assert!(authorized(&request_with(Some("Bearer  s3cret ")).await, "s3cret"));
+
+        assert!(!authorized(&request_with(None).await, "s3cret"));
+        assert!(!authorized(&request_with(Some("Bearer wrong")).await, "s3cret"));
+        assert!(!authorized(&request_with(Some("Bearer s3cre")).await, "s3cret"));
+        assert!(!authorized(&request_with(Some("Basic s3cret")).await, "s3cret"));
+        assert!(!authorized(&request_with(Some("s3cret")).await, "s3cret"));
+    }
+
+    #[test]
+    fn routes_endpoints_to_requests() {
+        assert!(matches!(route("GET", "/sessions"), Ok(AdminRequest::Sessions)));
+        assert!(matches!(route("GET", "/sessions/"), Ok(AdminRequest::Sessions)));
+        assert!(matches!(route("POST", "/reload"), Ok(AdminRequest::Reload)));
+        assert!(matches!(
+            route("DELETE", "/sessions/10.10.0.2"),
+            Ok(AdminRequest::Kick { address }) if address == IpAddr::from([10, 10, 0, 2])
+        ));
+        assert!(matches!(
+            route("DELETE", "/users/first%20last/sessions"),
+            Ok(AdminRequest::KickUser { username }) if username == "first last"
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_input_and_unknown_endpoints() {
+        let status = |method, path| route(method, path).unwrap_err().0;
+
+        assert_eq!(status("DELETE", "/sessions/not-an-address"), 400);
+        assert_eq!(status("DELETE", "/users/bad%zzescape/sessions"), 400);
+        assert_eq!(status("DELETE", "/users/%ff/sessions"), 400);
+
+        assert_eq!(status("POST", "/sessions"), 404);
+        assert_eq!(status("GET", "/reload"), 404);
+        assert_eq!(status("GET", "/"), 404);
+        assert_eq!(status("DELETE", "/users/alice"), 404);
+        assert_eq!(status("DELETE", "/sessions/10.10.0.2/extra"), 404);
+    }
+}
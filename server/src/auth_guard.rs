@@ -0,0 +1,290 @@
+use common::config::AuthProtectionConfig;
+use dashmap::DashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+// Tokio's clock, so tests can move time forward
+use tokio::time::Instant;
+use tracing::warn;
+
+/// How often expired failure records are dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Brute-force protection for logins.
+///
+/// Log lines about failures and bans put the source address last as
+/// `ip=<address>`, so fail2ban can match them with a simple pattern.
+#[derive(Clone)]
+pub struct AuthGuard {
+    config: Arc<RwLock<AuthProtectionConfig>>,
+    addresses: Arc<DashMap<IpAddr, FailureRecord>>,
+    usernames: Arc<DashMap<String, FailureRecord>>,
+    handshakes: Arc<Semaphore>,
+    last_prune: Arc<Mutex<Instant>>,
+}
+
+#[derive(Default)]
+struct FailureRecord {
+    failures: u32,
+    last_failure: Option<Instant>,
+    banned_until: Option<Instant>,
+    /// Bans so far, each twice as long as the one before
+    bans: u32,
+}
+
+impl AuthGuard {
+    pub fn new(config: AuthProtectionConfig) -> Self {
+        Self {
+            handshakes: Arc::new(Semaphore::new(match config.max_concurrent_handshakes {
+                0 => Semaphore::MAX_PERMITS,
+                max => max,
+            })),
+            config: Arc::new(RwLock::new(config)),
+            addresses: Arc::new(DashMap::new()),
+            usernames: Arc::new(DashMap::new()),
+            last_prune: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Pick up changed limits; the handshake cap is fixed at startup
+    pub fn set_config(&self, config: AuthProtectionConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.read().unwrap().handshake_timeout_secs)
+    }
+
+    /// Claim a handshake slot, or `None` if too many are in progress
+    pub fn try_start_handshake(&self) -> Option<OwnedSemaphorePermit> {
+        self.handshakes.clone().try_acquire_owned().ok()
+    }
+
+    /// Time left on the ban of a source address, if it is banned
+    pub fn address_banned(&self, ip: IpAddr) -> Option<Duration> {
+        ban_remaining(&self.addresses, &ip)
+    }
+
+    /// Time left on the ban of a username, if it is banned
+    pub fn username_banned(&self, username: &str) -> Option<Duration> {
+        ban_remaining(&self.usernames, username)
+    }
+
+    /// Count a failed login, banning the address or username once it
+    /// failed too often; returns how long to wait before answering
+    pub fn record_failure(&self, ip: IpAddr, username: &str) -> Duration {
+        let config = self.config.read().unwrap().clone();
+        self.prune(&config);
+
+        let (address_failures, address_ban) = fail(&self.addresses, ip, &config);
+        let (username_failures, username_ban) = fail(&self.usernames, username.to_string(), &config);
+
+        warn!(user = ?username, failures = address_failures, ip = %ip, "Authentication failed");
+        if let Some(ban) = address_ban {
+            warn!(ban_secs = ban.as_secs(), ip = %ip, "Banned address after repeated failed logins");
+        }
+        if let Some(ban) = username_ban {
+            warn!(
+                user = ?username,
+                ban_secs = ban.as_secs(),
+                ip = %ip,
+                "Banned username after repeated failed logins"
+            );
+        }
+
+        let failures = address_failures.max(username_failures);
+        let delay = config.failure_delay_ms.saturating_mul(1 << failures.saturating_sub(1).min(16));
+        Duration::from_millis(delay.min(config.max_failure_delay_ms))
+    }
+
+    /// Forget the failures of an address and username after a good login;
+    /// earlier bans still count towards the length of the next one
+    pub fn record_success(&self, ip: IpAddr, username: &str) {
+        if let Some(mut record) = self.addresses.get_mut(&ip) {
+            record.failures = 0;
+        }
+        if let Some(mut record) = self.usernames.get_mut(username) {
+            record.failures = 0;
+        }
+    }
+
+    fn prune(&self, config: &AuthProtectionConfig) {
+        {
+            let mut last_prune = self.last_prune.lock().unwrap();
+            if last_prune.elapsed() < PRUNE_INTERVAL {
+                return;
+            }
+            *last_prune = Instant::now();
+        }
+
+        // Bans are remembered for as long as the longest ban lasts, so
+        // repeat offenders keep getting longer ones
+        let window = Duration::from_secs(config.failure_window_secs.max(config.max_ban_secs));
+        let now = Instant::now();
+        let expired = |record: &FailureRecord| {
+            let banned = record.banned_until.is_some_and(|until| until > now);
+            let recent = record.last_failure.is_some_and(|at| now.duration_since(at) < window);
+            !banned && !recent
+        };
+
+        self.addresses.retain(|_, record| !expired(record));
+        self.usernames.retain(|_, record| !expired(record));
+    }
+}
+
+fn ban_remaining<K, Q>(records: &DashMap<K, FailureRecord>, key: &Q) -> Option<Duration>
+where
+    K: Eq + Hash + std::borrow::Borrow<Q>,
+    Q: Eq + Hash + ?Sized,
+{
+    let until = records.get(key)?.banned_until?;
+    until.checked_duration_since(Instant::now()).filter(|remaining| !remaining.is_zero())
+}
+
+/// Add a failure to `key`'s record, returning its failure count and the
+/// length of the ban it earned, if any
+fn fail<K: Eq + Hash>(
+    records: &DashMap<K, FailureRecord>,
+    key: K,
+    config: &AuthProtectionConfig,
+) -> (u32, Option<Duration>) {
+    let now = Instant::now();
+    let window = Duration::from_secs(config.failure_window_secs);
+    let mut record = records.entry(key).or_default();
+
+    if record.last_failure.is_some_and(|at| now.duration_since(at) > window) {
+        record.failures = 0;
+    }
+    record.failures += 1;
+    record.last_failure = Some(now);
+
+    let failures = record.failures;
+    if config.max_failures == 0 || failures < config.max_failures {
+        return (failures, None);
+    }
+
+    let ban_secs = config.ban_secs.saturating_mul(1 << record.bans.min(16)).min(config.max_ban_secs);
+    let ban = Duration::from_secs(ban_secs);
+    record.banned_until = Some(now + ban);
+    record.bans += 1;
+    record.failures = 0;
+
+    (failures, Some(ban))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::advance;
+
+    fn guard(max_failures: u32) -> AuthGuard {
+        AuthGuard::new(AuthProtectionConfig {
+            max_failures,
+            failure_window_secs: 600,
+            ban_secs: 300,
+            max_ban_secs: 1000,
+            failure_delay_ms: 500,
+            max_failure_delay_ms: 4000,
+            ..AuthProtectionConfig::default()
+        })
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    /// Fail until the address is banned, returning the ban
+    fn ban(guard: &AuthGuard, ip: IpAddr, username: &str) -> Duration {
+        for _ in 0..3 {
+            guard.record_failure(ip, username);
+        }
+        guard.address_banned(ip).unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failure_delay_doubles_up_to_the_cap() {
+        let guard = guard(0);
+
+        let delays: Vec<u64> = (0..6)
+            .map(|_| guard.record_failure(ip(1), "alice").as_millis() as u64)
+            .collect();
+        assert_eq!(delays, [500, 1000, 2000, 4000, 4000, 4000]);
+        assert_eq!(guard.address_banned(ip(1)), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counts_addresses_and_usernames_separately() {
+        let guard = guard(3);
+
+        guard.record_failure(ip(1), "alice");
+        guard.record_failure(ip(2), "alice");
+        assert_eq!(guard.address_banned(ip(1)), None);
+        assert_eq!(guard.username_banned("alice"), None);
+
+        // The third failure bans the username, not either address
+        guard.record_failure(ip(3), "alice");
+        assert_eq!(guard.username_banned("alice"), Some(Duration::from_secs(300)));
+        assert_eq!(guard.address_banned(ip(3)), None);
+        assert_eq!(guard.username_banned("bob"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bans_grow_with_each_repeat_up_to_the_cap() {
+        let guard = guard(3);
+
+        assert_eq!(ban(&guard, ip(1), "a"), Duration::from_secs(300));
+        advance(Duration::from_secs(299)).await;
+        assert_eq!(guard.address_banned(ip(1)), Some(Duration::from_secs(1)));
+        advance(Duration::from_secs(1)).await;
+        assert_eq!(guard.address_banned(ip(1)), None);
+
+        assert_eq!(ban(&guard, ip(1), "b"), Duration::from_secs(600));
+        advance(Duration::from_secs(600)).await;
+        assert_eq!(ban(&guard, ip(1), "c"), Duration::from_secs(1000));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failures_decay_after_the_window() {
+        let guard = guard(3);
+
+        guard.record_failure(ip(1), "alice");
+        guard.record_failure(ip(1), "alice");
+        advance(Duration::from_secs(601)).await;
+
+        // The old failures no longer count towards a ban
+        guard.record_failure(ip(1), "alice");
+        assert_eq!(guard.address_banned(ip(1)), None);
+        assert_eq!(guard.record_failure(ip(1), "alice"), Duration::from_millis(1000));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn success_clears_failures_but_not_ban_history() {
+        let guard = guard(3);
+
+        ban(&guard, ip(1), "alice");
+        advance(Duration::from_secs(300)).await;
+
+        guard.record_failure(ip(1), "alice");
+        guard.record_success(ip(1), "alice");
+        guard.record_failure(ip(1), "alice");
+        guard.record_failure(ip(1), "alice");
+        assert_eq!(guard.address_banned(ip(1)), None);
+
+        assert_eq!(ban(&guard, ip(1), "alice"), Duration::from_secs(600));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ban_history_is_forgotten_after_the_longest_ban() {
+        let guard = guard(3);
+
+        ban(&guard, ip(1), "a");
+        advance(Duration::from_secs(1000)).await;
+
+        // Pruning runs on the next failure and drops the old record
+        guard.record_failure(ip(2), "b");
+        assert!(!guard.addresses.contains_key(&ip(1)));
+        assert_eq!(ban(&guard, ip(1), "c"), Duration::from_secs(300));
+    }
+}
@@ -0,0 +1,361 @@
+use anyhow::{anyhow, Result};
+use common::config::{CertRotationConfig, ServerConfig};
+use common::crypto;
+use common::x509::CertificateInfo;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use time::OffsetDateTime;
+use tracing::{error, info, warn};
+
+/// How often the expiry is checked when the files are not watched
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How often a certificate close to expiry is warned about
+const EXPIRY_WARNING_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The server's certificate, handed to every new TLS handshake.
+///
+/// Replacing it only affects handshakes that start afterwards; established
+/// QUIC connections keep the keys they negotiated.
+#[derive(Clone)]
+pub struct ServerCertificate {
+    config: Arc<RwLock<CertRotationConfig>>,
+    current: Arc<RwLock<Arc<CertifiedKey>>>,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    /// Files the current certificate was read from, or that failed to load
+    /// last, so a broken pair is not retried until it changes again
+    files: Option<FileState>,
+    /// Files seen changed on the last check; they are read once they stay
+    /// the same for a whole interval, so a half-written pair is skipped
+    pending: Option<FileState>,
+    not_after: SystemTime,
+    last_expiry_warning: Option<Instant>,
+}
+
+/// Modification time and size of the certificate and key files
+#[derive(Clone, PartialEq, Eq)]
+struct FileState([(Option<SystemTime>, u64); 2]);
+
+/// A certificate and key read from disk, not yet in use
+pub struct LoadedCertificate {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    certified_key: CertifiedKey,
+    info: CertificateInfo,
+    fingerprint: String,
+    files: Option<FileState>,
+}
+
+impl ServerCertificate {
+    pub fn load(config: &ServerConfig) -> Result<Self> {
+        let loaded = Self::read(&config.cert_path, &config.key_path)?;
+        log_loaded(&loaded);
+
+        let certificate = Self {
+            config: Arc::new(RwLock::new(config.cert_rotation.clone())),
+            current: Arc::new(RwLock::new(Arc::new(loaded.certified_key))),
+            state: Arc::new(Mutex::new(State {
+                cert_path: loaded.cert_path,
+                key_path: loaded.key_path,
+                files: loaded.files,
+                pending: None,
+                not_after: loaded.info.not_after,
+                last_expiry_warning: None,
+            })),
+        };
+        certificate.check_expiry();
+
+        Ok(certificate)
+    }
+
+    /// Read and check a certificate and key without using them yet, so a
+    /// reload can fail before changing anything
+    pub fn read(cert_path: &Path, key_path: &Path) -> Result<LoadedCertificate> {
+        let files = file_state(cert_path, key_path);
+        let cert_chain = crypto::load_certificates(cert_path)?;
+        let key = crypto::load_private_key(key_path)?;
+        let certified_key = crypto::certified_key(&cert_chain, &key)
+            .map_err(|e| anyhow!("{} and {}: {}", cert_path.display(), key_path.display(), e))?;
+
+        Ok(LoadedCertificate {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            certified_key,
+            info: CertificateInfo::parse(&cert_chain[0])?,
+            fingerprint: crypto::spki_fingerprint(&cert_chain[0])?,
+            files,
+        })
+    }
+
+    /// Present `loaded` to new handshakes, watching the files it came from
+    pub fn install(&self, loaded: LoadedCertificate) {
+        log_loaded(&loaded);
+        *self.current.write().unwrap() = Arc::new(loaded.certified_key);
+
+        {
+            let mut state = self.state.lock().unwrap();
+            state.cert_path = loaded.cert_path;
+            state.key_path = loaded.key_path;
+            state.files = loaded.files;
+            state.pending = None;
+            state.not_after = loaded.info.not_after;
+            state.last_expiry_warning = None;
+        }
+        self.check_expiry();
+    }
+
+    /// Pick up changed rotation settings
+    pub fn set_config(&self, config: CertRotationConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    /// Check the files for changes every `watch_interval_secs` and the
+    /// expiry at least hourly
+    pub fn spawn_watcher(&self) {
+        let certificate = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let watch_interval = certificate.config.read().unwrap().watch_interval_secs;
+                let period = match watch_interval {
+                    0 => EXPIRY_CHECK_INTERVAL,
+                    secs => Duration::from_secs(secs).min(EXPIRY_CHECK_INTERVAL),
+                };
+                tokio::time::sleep(period).await;
+
+                if watch_interval > 0 {
+                    certificate.reload_if_changed();
+                }
+                certificate.check_expiry();
+            }
+        });
+    }
+
+    fn reload_if_changed(&self) {
+        let (cert_path, key_path) = {
+            let mut state = self.state.lock().unwrap();
+            let files = file_state(&state.cert_path, &state.key_path);
+            if files == state.files {
+                state.pending = None;
+                return;
+            }
+            if files != state.pending {
+                state.pending = files;
+                return;
+            }
+
+            state.files = files;
+            (state.cert_path.clone(), state.key_path.clone())
+        };
+
+        info!("Certificate files changed, reloading {}", cert_path.display());
+        match Self::read(&cert_path, &key_path) {
+            Ok(loaded) => self.install(loaded),
+            Err(e) => error!("Keeping the current certificate: {}", e),
+        }
+    }
+
+    fn check_expiry(&self) {
+        let warning_days = self.config.read().unwrap().expiry_warning_days;
+        let mut state = self.state.lock().unwrap();
+
+        let days_left = days_until(state.not_after);
+        if days_left >= warning_days as i64 {
+            return;
+        }
+        if state.last_expiry_warning.is_some_and(|warned| warned.elapsed() < EXPIRY_WARNING_INTERVAL) {
+            return;
+        }
+
+        state.last_expiry_warning = Some(Instant::now());
+        if days_left < 0 {
+            error!(
+                "Server certificate {} expired on {}, clients will refuse to connect",
+                state.cert_path.display(),
+                OffsetDateTime::from(state.not_after).date()
+            );
+        } else {
+            warn!(
+                "Server certificate {} expires on {} (in {} days), renew it",
+                state.cert_path.display(),
+                OffsetDateTime::from(state.not_after).date(),
+                days_left
+            );
+        }
+    }
+}
+
+impl ResolvesServerCert for ServerCertificate {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn log_loaded(loaded: &LoadedCertificate) {
+    info!(
+        "Server certificate {} expires on {} (in {} days), key fingerprint (SPKI SHA-256): {}",
+        loaded.info.serial,
+        OffsetDateTime::from(loaded.info.not_after).date(),
+        days_until(loaded.info.not_after),
+        loaded.fingerprint
+    );
+}
+
+/// Whole days until `time`, negative once it has passed
+fn days_until(time: SystemTime) -> i64 {
+    match time.duration_since(SystemTime::now()) {
+        Ok(left) => (left.as_secs() / 86_400) as i64,
+        Err(e) => -((e.duration().as_secs() / 86_400) as i64) - 1,
+    }
+}
+
+/// `None` if either file cannot be read, which counts as a change once it
+/// can be again
+fn file_state(cert_path: &Path, key_path: &Path) -> Option<FileState> {
+    let stat = |path: &Path| {
+        std::fs::metadata(path).ok().map(|metadata| (metadata.modified().ok(), metadata.len()))
+    };
+
+    Some(FileState([stat(cert_path)?, stat(key_path)?]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::crypto::{CertificateAuthority, IssuedCertificate};
+    use std::fs::File;
+    use std::time::UNIX_EPOCH;
+
+    /// Certificate and key files in a directory of their own
+    struct Files {
+        cert_path: PathBuf,
+        key_path: PathBuf,
+    }
+
+    impl Files {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("quicvpn-cert-{}-{}", std::process::id(), name));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            Self {
+                cert_path: dir.join("server.crt"),
+                key_path: dir.join("server.key"),
+            }
+        }
+
+        /// Write a certificate and key, stamped `version` seconds after the
+        /// epoch so each write looks changed whatever the clock resolution
+        fn write(&self, cert: &IssuedCertificate, key: &IssuedCertificate, version: u64) {
+            let modified = UNIX_EPOCH + Duration::from_secs(version);
+            for (path, contents) in [(&self.cert_path, &cert.cert_der), (&self.key_path, &key.key_der)] {
+                std::fs::write(path, contents).unwrap();
+                File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+            }
+        }
+
+        fn load(&self) -> ServerCertificate {
+            let config: ServerConfig = serde_json::from_value(serde_json::json!({
+                "listen_addr": "0.0.0.0:4433",
+                "cert_path": self.cert_path,
+                "key_path": self.key_path,
+                "vpn_network": "10.10.0.1",
+                "vpn_netmask": "255.255.255.0",
+                "mtu": 1400,
+                "log_level": "info",
+                "user_db_path": "users.json",
+                "max_clients": 10,
+                "gaming_optimization": true,
+            }))
+            .unwrap();
+
+            ServerCertificate::load(&config).unwrap()
+        }
+    }
+
+    fn issue(ca: &CertificateAuthority) -> IssuedCertificate {
+        ca.issue_server(&["vpn.example".to_string()], 30).unwrap()
+    }
+
+    fn presented(certificate: &ServerCertificate) -> Vec<u8> {
+        certificate.current.read().unwrap().cert[0].0.clone()
+    }
+
+    #[test]
+    fn reloads_a_changed_pair_once_it_is_stable() {
+        let ca = CertificateAuthority::generate("Test CA", 30).unwrap();
+        let (first, second) = (issue(&ca), issue(&ca));
+        let files = Files::new("rotate");
+        files.write(&first, &first, 1);
+        let certificate = files.load();
+
+        // Unchanged files are left alone
+        certificate.reload_if_changed();
+        assert_eq!(presented(&certificate), first.cert_der);
+
+        // A change is only picked up once it held still for a check
+        files.write(&second, &second, 2);
+        certificate.reload_if_changed();
+        assert_eq!(presented(&certificate), first.cert_der);
+        certificate.reload_if_changed();
+        assert_eq!(presented(&certificate), second.cert_der);
+    }
+
+    #[test]
+    fn waits_while_the_pair_is_still_being_written() {
+        let ca = CertificateAuthority::generate("Test CA", 30).unwrap();
+        let (first, second) = (issue(&ca), issue(&ca));
+        let files = Files::new("pending");
+        files.write(&first, &first, 1);
+        let certificate = files.load();
+
+        // The certificate is written first, the key a moment later
+        files.write(&second, &first, 2);
+        certificate.reload_if_changed();
+        files.write(&second, &second, 3);
+        certificate.reload_if_changed();
+        assert_eq!(presented(&certificate), first.cert_der);
+
+        certificate.reload_if_changed();
+        assert_eq!(presented(&certificate), second.cert_der);
+    }
+
+    #[test]
+    fn keeps_the_current_certificate_when_the_pair_does_not_match() {
+        let ca = CertificateAuthority::generate("Test CA", 30).unwrap();
+        let (first, second) = (issue(&ca), issue(&ca));
+        let files = Files::new("mismatch");
+        files.write(&first, &first, 1);
+        let certificate = files.load();
+
+        files.write(&second, &first, 2);
+        certificate.reload_if_changed();
+        certificate.reload_if_changed();
+        assert_eq!(presented(&certificate), first.cert_der);
+        assert!(ServerCertificate::read(&files.cert_path, &files.key_path).is_err());
+
+        // Fixing the key gets the new certificate in use
+        files.write(&second, &second, 3);
+        certificate.reload_if_changed();
+        certificate.reload_if_changed();
+        assert_eq!(presented(&certificate), second.cert_der);
+    }
+
+    #[test]
+    fn days_until_counts_whole_days() {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(86_400);
+
+        assert_eq!(days_until(now + day * 10 + Duration::from_secs(3600)), 10);
+        assert_eq!(days_until(now + Duration::from_secs(3600)), 0);
+        assert_eq!(days_until(now - Duration::from_secs(1)), -1);
+        assert_eq!(days_until(now - day * 2 - Duration::from_secs(3600)), -3);
+    }
+}
@@ -1,36 +1,105 @@
-use anyhow::Result;
-use common::protocol::Message;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use common::crypto;
+use common::datagram::DatagramSender;
+use common::protocol::{self, Capabilities, Message, RouteInfo};
 use common::tun_device::TunDevice;
-use common::config::ServerConfig;
+use common::x509::CertificateInfo;
+use common::config::{CertIdentity, ServerConfig};
+use common::stats::{PeerStats, SessionStats, TrafficSnapshot};
 use dashmap::DashMap;
 use quinn::{Connection, RecvStream, SendStream};
-use std::collections::HashMap;
-use std::net::IpAddr;
-use std::sync::Arc;
-use tokio::sync::mpsc;
-use tokio::task::JoinHandle;
-use tracing::{error, info, warn};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, error, info, trace, warn};
 
+use crate::auth_guard::AuthGuard;
 use crate::ip_allocator::IpAllocator;
+use crate::metrics::{HandshakeResult, Metrics};
+use crate::packet_queue::PacketQueue;
+use crate::quota::{QuotaStore, UsageCounter};
+use crate::rate_limit::RateLimiter;
+use crate::revocation::RevocationList;
+use crate::session_limit::{Reservation, UserSessions};
 use crate::user_db::UserDatabase;
 
-#[derive(Debug)]
 struct ClientInfo {
     username: String,
     assigned_ip: IpAddr,
+    assigned_ipv6: Option<Ipv6Addr>,
+    capabilities: Capabilities,
     connection: Connection,
-    task_handle: JoinHandle<()>,
+    /// Packets from the TUN device waiting to be sent to this client
+    queue: Arc<PacketQueue>,
+    /// Token the client can use to resume this session
+    resumption_token: Option<Bytes>,
+    /// Control messages waiting to be written to the client's stream
+    control_tx: mpsc::Sender<Message>,
+    /// Routes last pushed to the client
+    routes: Vec<RouteInfo>,
+    stats: Arc<SessionStats>,
+    connected_at: Instant,
+    /// Serial of the client certificate the session logged in with
+    certificate_serial: Option<String>,
+}
+
+/// How long a client gets to act on a server-sent `Disconnect` before the
+/// connection is closed under it
+const DISCONNECT_GRACE: Duration = Duration::from_secs(2);
+
+/// How often quotas are checked and changed limits picked up
+const LIMIT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often quota usage is written to disk
+const USAGE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Point-in-time view of a connected client
+pub struct SessionSummary {
+    pub username: String,
+    pub assigned_ip: IpAddr,
+    pub assigned_ipv6: Option<Ipv6Addr>,
+    pub remote_addr: SocketAddr,
+    pub connected_for: Duration,
+    pub traffic: TrafficSnapshot,
+    pub rtt: Duration,
+    pub lost_packets: u64,
+    pub congestion_window: u64,
+}
+
+/// Addresses of a session whose connection dropped, held until the client
+/// resumes it or the resumption window closes
+struct ParkedSession {
+    username: String,
+    assigned_ip: IpAddr,
+    assigned_ipv6: Option<Ipv6Addr>,
 }
 
 #[derive(Clone)]
 pub struct ClientManager {
-    config: ServerConfig,
+    /// Current configuration, replaced when it is reloaded
+    config: Arc<RwLock<Arc<ServerConfig>>>,
     user_db: UserDatabase,
     ip_allocator: IpAllocator,
+    ip_allocator_v6: Option<IpAllocator>,
     clients: Arc<DashMap<IpAddr, ClientInfo>>,
+    /// Maps each client's IPv6 address to its key in `clients`
+    ipv6_routes: Arc<DashMap<Ipv6Addr, IpAddr>>,
+    /// Sessions of each user, counted against the session limit
+    user_sessions: UserSessions,
+    /// Dropped sessions waiting to be resumed, keyed by resumption token
+    parked: Arc<DashMap<Bytes, ParkedSession>>,
     tun_device: Arc<TunDevice>,
-    packet_tx: mpsc::Sender<(Vec<u8>, IpAddr)>,
-    packet_rx: Arc<mpsc::Receiver<(Vec<u8>, IpAddr)>>,
+    metrics: Metrics,
+    quotas: QuotaStore,
+    auth_guard: AuthGuard,
+    /// Client certificates that may no longer log in
+    revocations: Arc<RwLock<RevocationList>>,
+    /// Bandwidth buckets of each user who connected since the server started
+    rate_limiters: Arc<DashMap<String, Arc<RateLimiter>>>,
+    /// Set once the server starts shutting down
+    shutdown: Arc<watch::Sender<bool>>,
 }
 
 impl ClientManager {
@@ -38,6 +107,10 @@ impl ClientManager {
         config: ServerConfig,
         user_db: UserDatabase,
         ip_allocator: IpAllocator,
+        ip_allocator_v6: Option<IpAllocator>,
+        metrics: Metrics,
+        quotas: QuotaStore,
+        auth_guard: AuthGuard,
     ) -> Self {
         // Create TUN device for server
         let tun_device = TunDevice::new(
@@ -47,99 +120,476 @@ impl ClientManager {
             config.mtu,
         ).expect("Failed to create TUN device");
 
-        let (packet_tx, packet_rx) = mpsc::channel(1000);
+        if let Some(address) = config.vpn_network_v6 {
+            tun_device.add_ipv6_address(address, config.vpn_prefix_len_v6)
+                .expect("Failed to configure IPv6 on TUN device");
+        }
 
         let instance = Self {
-            config,
+            config: Arc::new(RwLock::new(Arc::new(config))),
             user_db,
             ip_allocator,
+            ip_allocator_v6,
             clients: Arc::new(DashMap::new()),
+            ipv6_routes: Arc::new(DashMap::new()),
+            user_sessions: UserSessions::new(),
+            parked: Arc::new(DashMap::new()),
             tun_device: Arc::new(tun_device),
-            packet_tx,
-            packet_rx: Arc::new(packet_rx),
+            metrics,
+            quotas,
+            auth_guard,
+            revocations: Arc::new(RwLock::new(RevocationList::default())),
+            rate_limiters: Arc::new(DashMap::new()),
+            shutdown: Arc::new(watch::channel(false).0),
         };
 
         // Start packet forwarder
         instance.start_packet_forwarder();
+        instance.start_limit_enforcement();
 
         instance
     }
 
     pub async fn handle_connection(&self, connection: Connection) -> Result<()> {
-        // Open bidirectional stream for control messages
-        let (mut send, mut recv) = connection.open_bi().await?;
+        let result = self.handshake(connection).await;
+        
+        if result.is_err() {
+            self.metrics.record_handshake(HandshakeResult::Error);
+        }
+        
+        result
+    }
+
+    /// User named by the client's certificate and the certificate's serial,
+    /// or `None` if it presented none; a username the client claims must be
+    /// one of the names in it
+    fn certificate_user(&self, connection: &Connection, claimed: &str) -> Result<Option<(String, String)>> {
+        let Some(client_auth) = self.config().client_auth.clone() else {
+            return Ok(None);
+        };
+        let Some(certificate) = connection.peer_identity()
+            .and_then(|identity| identity.downcast::<Vec<rustls::Certificate>>().ok())
+            .and_then(|chain| chain.into_iter().next())
+        else {
+            return Ok(None);
+        };
+        
+        let info = CertificateInfo::parse(&certificate.0)?;
+        if self.revocations.read().unwrap().is_revoked(&info.serial) {
+            return Err(anyhow!("certificate {} has been revoked", info.serial));
+        }
+        
+        let names = match client_auth.identity {
+            CertIdentity::CommonName => info.common_name.into_iter().collect(),
+            CertIdentity::DnsName => info.dns_names,
+            CertIdentity::Email => info.emails,
+        };
+        
+        if !claimed.is_empty() {
+            if !names.iter().any(|name| name == claimed) {
+                return Err(anyhow!("certificate was not issued to {}", claimed));
+            }
+            return Ok(Some((claimed.to_string(), info.serial)));
+        }
+        
+        names.into_iter()
+            .find(|name| self.user_db.contains(name))
+            .map(|name| Some((name, info.serial)))
+            .ok_or_else(|| anyhow!("certificate names no known user"))
+    }
+
+    /// Connected clients and their QUIC path statistics
+    pub fn sessions(&self) -> Vec<SessionSummary> {
+        self.clients.iter()
+            .map(|client| {
+                let stats = client.connection.stats();
+                
+                SessionSummary {
+                    username: client.username.clone(),
+                    assigned_ip: client.assigned_ip,
+                    assigned_ipv6: client.assigned_ipv6,
+                    remote_addr: client.connection.remote_address(),
+                    connected_for: client.connected_at.elapsed(),
+                    traffic: client.stats.traffic.snapshot(),
+                    rtt: stats.path.rtt,
+                    lost_packets: stats.path.lost_packets,
+                    congestion_window: stats.path.cwnd,
+                }
+            })
+            .collect()
+    }
+
+    /// End the session holding `address`, which may be either of its
+    /// addresses; returns whether there was one
+    pub fn disconnect_address(&self, address: IpAddr, reason: &str) -> bool {
+        let client_ip = match address {
+            IpAddr::V6(ip) => self.ipv6_routes.get(&ip).map(|client_ip| *client_ip).unwrap_or(address),
+            IpAddr::V4(_) => address,
+        };
+        
+        self.disconnect_where(reason, None, |client| client.assigned_ip == client_ip) == 1
+    }
+
+    /// End every session of a user, returning how many there were
+    pub fn disconnect_user(&self, username: &str, reason: &str) -> usize {
+        self.disconnect_where(reason, None, |client| client.username == username)
+    }
+
+    /// Stop taking new sessions, disconnect every client and wait up to
+    /// `drain` for their sessions to end; returns how many were left
+    pub async fn shutdown(&self, reconnect_after_secs: Option<u32>, drain: Duration) -> usize {
+        self.shutdown.send_replace(true);
+        
+        let count = self.disconnect_where("Server shutting down", reconnect_after_secs, |_| true);
+        info!("Waiting up to {}s for {} clients to disconnect", drain.as_secs(), count);
+        
+        let deadline = tokio::time::Instant::now() + drain;
+        while !self.clients.is_empty() && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        
+        self.quotas.save_in_background().await;
+        
+        self.clients.len()
+    }
 
-        // Receive client hello message
-        let client_hello = self.receive_message(&mut recv).await?;
+    fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Tell matching clients why they are being disconnected, then close
+    /// their connections once they had a moment to read it. Their sessions
+    /// cannot be resumed.
+    fn disconnect_where<F>(&self, reason: &str, reconnect_after_secs: Option<u32>, matches: F) -> usize
+    where
+        F: Fn(&ClientInfo) -> bool,
+    {
+        let mut count = 0;
+        
+        for mut client in self.clients.iter_mut() {
+            if !matches(&client) {
+                continue;
+            }
+            
+            info!("Disconnecting {} ({}): {}", client.assigned_ip, client.username, reason);
+            client.resumption_token = None;
+            let _ = client.control_tx.try_send(Message::Disconnect {
+                reason: reason.to_string(),
+                reconnect_after_secs: reconnect_after_secs
+                    .filter(|_| client.capabilities.contains(Capabilities::RECONNECT_HINT)),
+            });
+            
+            let connection = client.connection.clone();
+            let close_reason = reason.to_string();
+            tokio::spawn(async move {
+                let _ = tokio::time::timeout(DISCONNECT_GRACE, connection.closed()).await;
+                connection.close(0u32.into(), close_reason.as_bytes());
+            });
+            
+            count += 1;
+        }
+        
+        count
+    }
+
+    /// Addresses in use and capacity of each address pool, by family
+    pub fn pool_usage(&self) -> Vec<(&'static str, usize, u128)> {
+        let mut pools = Vec::new();
+        
+        let (used, capacity) = self.ip_allocator.usage();
+        pools.push(("ipv4", used, capacity));
+        
+        if let Some(allocator) = &self.ip_allocator_v6 {
+            let (used, capacity) = allocator.usage();
+            pools.push(("ipv6", used, capacity));
+        }
+        
+        pools
+    }
+
+    async fn handshake(&self, connection: Connection) -> Result<()> {
+        let remote_ip = connection.remote_address().ip();
+        
+        // Accept the bidirectional control stream opened by the client and
+        // receive its hello, giving up on clients that stall
+        let opened = tokio::time::timeout(self.auth_guard.handshake_timeout(), async {
+            let (send, mut recv) = connection.accept_bi().await?;
+            let client_hello = self.receive_message(&mut recv).await?;
+            
+            Ok::<_, anyhow::Error>((send, recv, client_hello))
+        }).await;
+        
+        let (mut send, recv, client_hello) = match opened {
+            Ok(opened) => opened?,
+            Err(_) => {
+                warn!(ip = %remote_ip, "Handshake timed out");
+                self.metrics.record_handshake(HandshakeResult::Timeout);
+                connection.close(0u32.into(), b"Handshake timed out");
+                
+                return Ok(());
+            }
+        };
 
         match client_hello {
-            Message::ClientHello { username, password, client_version } => {
-                info!("Client hello from user: {}, version: {}", username, client_version);
+            Message::ClientHello {
+                username,
+                password,
+                client_version,
+                protocol_version,
+                capabilities,
+                resumption_token,
+            } => {
+                info!(
+                    "Client hello from user: {}, version: {}, protocol: {}",
+                    username, client_version, protocol_version
+                );
 
-                // Authenticate user
-                if !self.user_db.authenticate(&username, &password) {
+                if self.is_shutting_down() {
+                    self.metrics.record_handshake(HandshakeResult::ShuttingDown);
+                    let reconnect_after_secs = self.config().shutdown_reconnect_after_secs
+                        .filter(|_| capabilities.contains(Capabilities::RECONNECT_HINT));
                     self.send_message(
                         &mut send,
                         &Message::Disconnect {
-                            reason: "Authentication failed".to_string(),
+                            reason: "Server shutting down".to_string(),
+                            reconnect_after_secs,
                         },
                     ).await?;
                     
                     return Ok(());
                 }
 
-                // Allocate IP address
-                let assigned_ip = if let Some(ip) = self.ip_allocator.allocate_ip() {
-                    ip
-                } else {
+                // Reject incompatible clients before doing any other work
+                let protocol_version = match protocol::negotiate_version(protocol_version) {
+                    Some(version) => version,
+                    None => {
+                        self.metrics.record_handshake(HandshakeResult::UnsupportedVersion);
+                        self.send_message(
+                            &mut send,
+                            &Message::disconnect(format!(
+                                "Unsupported protocol version {} (server supports {}-{})",
+                                protocol_version,
+                                protocol::MIN_PROTOCOL_VERSION,
+                                protocol::PROTOCOL_VERSION
+                            )),
+                        ).await?;
+                        
+                        return Ok(());
+                    }
+                };
+
+                // A client certificate names the user by itself
+                let certificate_user = self.certificate_user(&connection, &username);
+                let (username, certificate_serial) = match &certificate_user {
+                    Ok(Some((user, serial))) => (user.clone(), Some(serial.clone())),
+                    _ => (username, None),
+                };
+
+                if let Some(remaining) = self.auth_guard.username_banned(&username) {
+                    warn!(user = ?username, ban_secs = remaining.as_secs(), ip = %remote_ip, "Rejected login for banned username");
+                    self.metrics.record_handshake(HandshakeResult::Banned);
                     self.send_message(
                         &mut send,
-                        &Message::Disconnect {
-                            reason: "No available IP addresses".to_string(),
-                        },
+                        &Message::disconnect("Too many failed logins, try again later"),
                     ).await?;
                     
                     return Ok(());
+                }
+
+                let require_password = self.config().client_auth.as_ref()
+                    .is_some_and(|client_auth| client_auth.require_password);
+                
+                let authenticated = match certificate_user {
+                    Ok(Some(_)) if !require_password => self.user_db.is_active(&username),
+                    Ok(_) => {
+                        // Authenticate user; password hashing is too slow for the async runtime
+                        let user_db = self.user_db.clone();
+                        let auth_username = username.clone();
+                        tokio::task::spawn_blocking(move || {
+                            user_db.authenticate(&auth_username, &password)
+                        }).await?
+                    }
+                    Err(e) => {
+                        warn!(user = ?username, ip = %remote_ip, "Rejected client certificate: {}", e);
+                        false
+                    }
+                };
+                
+                if !authenticated {
+                    self.metrics.record_handshake(HandshakeResult::AuthFailed);
+                    
+                    // Slow down guessing before giving any answer
+                    let delay = self.auth_guard.record_failure(remote_ip, &username);
+                    tokio::time::sleep(delay).await;
+                    
+                    self.send_message(
+                        &mut send,
+                        &Message::disconnect("Authentication failed"),
+                    ).await?;
+                    
+                    return Ok(());
+                }
+
+                self.auth_guard.record_success(remote_ip, &username);
+
+                // A user who used up a quota stays out until it renews
+                if let Some(reason) = self.quotas.usage(&username).exhausted(&self.user_db.limits(&username)) {
+                    info!("Rejected login for {}: {}", username, reason);
+                    self.metrics.record_handshake(HandshakeResult::QuotaExceeded);
+                    self.send_message(&mut send, &Message::disconnect(reason)).await?;
+                    
+                    return Ok(());
+                }
+
+                // Only enable features both sides understand
+                let capabilities = capabilities.intersection(self.server_capabilities());
+                info!(
+                    "Negotiated protocol {} with {} (capabilities: {})",
+                    protocol_version, username, capabilities
+                );
+
+                // Take back the addresses of a resumed session
+                let resumed = match &resumption_token {
+                    Some(token) if capabilities.contains(Capabilities::RESUMPTION) => {
+                        self.resume_session(token, &username)
+                    }
+                    _ => None,
+                };
+
+                let (assigned_ip, assigned_ipv6, reservation) = match resumed {
+                    Some((ip, ipv6)) => (ip, ipv6, None),
+                    None => {
+                        if resumption_token.is_some() {
+                            info!("Session of {} could not be resumed, starting a new one", username);
+                        }
+
+                        let Some(reservation) = self.admit_session(&username) else {
+                            let max = self.config().session_limit.max_per_user;
+                            info!("Rejected login for {}: already has {} sessions", username, max);
+                            self.metrics.record_handshake(HandshakeResult::SessionLimit);
+                            self.send_message(
+                                &mut send,
+                                &Message::disconnect(format!("Too many sessions for this user (at most {})", max)),
+                            ).await?;
+                            
+                            return Ok(());
+                        };
+
+                        // Allocate IP address
+                        let assigned_ip = if let Some(ip) = self.ip_allocator.allocate_ip(&username) {
+                            ip
+                        } else {
+                            self.metrics.record_handshake(HandshakeResult::NoAddresses);
+                            self.send_message(
+                                &mut send,
+                                &Message::disconnect("No available IP addresses"),
+                            ).await?;
+                            
+                            return Ok(());
+                        };
+
+                        // Dual-stack clients also get an IPv6 address when one is free
+                        let assigned_ipv6 = match &self.ip_allocator_v6 {
+                            Some(allocator) if capabilities.contains(Capabilities::IPV6) => {
+                                match allocator.allocate_ip(&username) {
+                                    Some(IpAddr::V6(ip)) => Some(ip),
+                                    _ => {
+                                        warn!("No available IPv6 addresses for {}, continuing with IPv4 only", username);
+                                        None
+                                    }
+                                }
+                            }
+                            _ => None,
+                        };
+
+                        (assigned_ip, assigned_ipv6, Some(reservation))
+                    }
+                };
+
+                // Every session gets a fresh token, so a token works only once
+                let resumption_token = if capabilities.contains(Capabilities::RESUMPTION) {
+                    Some(Bytes::copy_from_slice(&crypto::random_bytes::<32>()?))
+                } else {
+                    None
                 };
 
                 // Send server hello message
-                self.send_message(
+                let config = self.config();
+                let hello = self.send_message(
                     &mut send,
                     &Message::ServerHello {
                         server_version: env!("CARGO_PKG_VERSION").to_string(),
+                        protocol_version,
+                        capabilities,
                         assigned_ip,
-                        subnet_mask: self.config.vpn_netmask,
-                        mtu: self.config.mtu,
+                        subnet_mask: config.vpn_netmask,
+                        mtu: config.mtu,
+                        assigned_ipv6: assigned_ipv6.map(|ip| (ip, config.vpn_prefix_len_v6)),
+                        resumption_token: resumption_token.clone(),
                     },
-                ).await?;
+                ).await;
+                
+                if let Err(e) = hello {
+                    self.release_addresses(assigned_ip, assigned_ipv6);
+                    return Err(e);
+                }
 
-                // Start client handler
-                let task_handle = self.start_client_handler(
-                    connection.clone(),
-                    username.clone(),
-                    assigned_ip,
-                    send,
-                    recv,
-                ).await?;
+                // Push routes right after the hello so they are in place
+                // before any traffic flows
+                let routes = if capabilities.contains(Capabilities::ROUTE_PUSH) {
+                    let routes = config.routes_for(&username, assigned_ipv6.is_some());
+                    
+                    if let Err(e) = self.send_message(&mut send, &Message::RouteUpdate { routes: routes.clone() }).await {
+                        self.release_addresses(assigned_ip, assigned_ipv6);
+                        return Err(e);
+                    }
+                    
+                    routes
+                } else {
+                    Vec::new()
+                };
 
-                // Store client info
+                // Register the client before starting its handler so the
+                // TUN forwarder can route return traffic to its queue
+                let (control_tx, control_rx) = mpsc::channel(16);
                 let client_info = ClientInfo {
-                    username,
+                    username: username.clone(),
                     assigned_ip,
+                    assigned_ipv6,
+                    capabilities,
                     connection: connection.clone(),
-                    task_handle,
+                    queue: Arc::new(PacketQueue::new(config.queue_config_for(&username))),
+                    resumption_token,
+                    control_tx,
+                    routes,
+                    stats: Arc::new(SessionStats::new()),
+                    connected_at: Instant::now(),
+                    certificate_serial,
                 };
 
                 self.clients.insert(assigned_ip, client_info);
+                match reservation {
+                    Some(reservation) => reservation.activate(assigned_ip),
+                    None => self.user_sessions.insert(&username, assigned_ip),
+                }
+                if let Some(ipv6) = assigned_ipv6 {
+                    self.ipv6_routes.insert(ipv6, assigned_ip);
+                }
+
+                // Start client handler
+                self.start_client_handler(assigned_ip, send, recv, control_rx);
+                self.metrics.record_handshake(HandshakeResult::Success);
 
-                info!("Client connected: {}", assigned_ip);
+                match assigned_ipv6 {
+                    Some(ipv6) => info!("Client connected: {}, {}", assigned_ip, ipv6),
+                    None => info!("Client connected: {}", assigned_ip),
+                }
             }
             _ => {
+                self.metrics.record_handshake(HandshakeResult::BadHello);
                 self.send_message(
                     &mut send,
-                    &Message::Disconnect {
-                        reason: "Expected ClientHello".to_string(),
-                    },
+                    &Message::disconnect("Expected ClientHello"),
                 ).await?;
                 
                 return Ok(());
@@ -149,180 +599,573 @@ impl ClientManager {
         Ok(())
     }
 
-    async fn send_message(&self, stream: &mut SendStream, message: &Message) -> Result<()> {
-        let data = message.to_bytes()?;
-        let data_len = data.len() as u32;
+    /// Return a session's addresses to their pools
+    fn release_addresses(&self, ip: IpAddr, ipv6: Option<Ipv6Addr>) {
+        self.ip_allocator.release_ip(ip);
+        
+        if let (Some(allocator), Some(ipv6)) = (&self.ip_allocator_v6, ipv6) {
+            allocator.release_ip(IpAddr::V6(ipv6));
+        }
+    }
+
+    /// Make room for a new session of `username` under the session limit,
+    /// evicting older sessions if the policy says so; returns `None` if
+    /// the login must be refused. The place is given back if the returned
+    /// reservation is dropped before the session is registered.
+    fn admit_session(&self, username: &str) -> Option<Reservation> {
+        let (reservation, evicted) = self.user_sessions.admit(username, &self.config().session_limit)?;
+        
+        for ip in evicted {
+            self.disconnect_where("Signed in from another device", None, |client| client.assigned_ip == ip);
+        }
+        
+        Some(reservation)
+    }
+
+    /// Stop counting a session towards its user's session limit
+    fn forget_session(&self, username: &str, client_ip: IpAddr) {
+        self.user_sessions.forget(username, client_ip);
+    }
+
+    /// Find the session a resumption token belongs to and detach it,
+    /// returning its addresses
+    fn resume_session(&self, token: &Bytes, username: &str) -> Option<(IpAddr, Option<Ipv6Addr>)> {
+        // A session whose connection already dropped
+        if let Some((_, parked)) = self.parked.remove_if(token, |_, parked| parked.username == username) {
+            info!("Resumed session of {} with {}", username, parked.assigned_ip);
+            return Some((parked.assigned_ip, parked.assigned_ipv6));
+        }
         
-        // Write message length
-        stream.write_all(&data_len.to_be_bytes()).await?;
+        // A session the server still thinks is alive, e.g. because the
+        // client moved to another network before the old path timed out
+        let live_ip = self.clients.iter()
+            .find(|client| client.resumption_token.as_ref() == Some(token) && client.username == username)
+            .map(|client| *client.key())?;
         
-        // Write message data
-        stream.write_all(&data).await?;
+        let (_, old) = self.clients.remove(&live_ip)?;
+        self.forget_session(&old.username, live_ip);
+        if let Some(ipv6) = old.assigned_ipv6 {
+            self.ipv6_routes.remove(&ipv6);
+        }
+        old.queue.close();
+        old.connection.close(0u32.into(), b"Session resumed on a new connection");
+        
+        info!("Resumed live session of {} with {} on a new connection", username, old.assigned_ip);
+        Some((old.assigned_ip, old.assigned_ipv6))
+    }
+
+    /// Hold a dropped session's addresses for the resumption window
+    fn park_session(&self, token: Bytes, client: ClientInfo) {
+        let timeout = Duration::from_secs(self.config().resumption_timeout_secs);
+        debug!(
+            "Holding {} for {} for {}s in case the session resumes",
+            client.assigned_ip, client.username, timeout.as_secs()
+        );
+        
+        self.parked.insert(token.clone(), ParkedSession {
+            username: client.username,
+            assigned_ip: client.assigned_ip,
+            assigned_ipv6: client.assigned_ipv6,
+        });
+        
+        let manager = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            
+            if let Some((_, parked)) = manager.parked.remove(&token) {
+                debug!("Resumption window for {} ({}) expired", parked.username, parked.assigned_ip);
+                manager.release_addresses(parked.assigned_ip, parked.assigned_ipv6);
+            }
+        });
+    }
+
+    /// Configuration currently in effect
+    pub fn config(&self) -> Arc<ServerConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Switch to a reloaded configuration. Limits apply to sessions that
+    /// start afterwards; routes are pushed to every client whose routes
+    /// changed.
+    pub fn apply_config(&self, config: ServerConfig) {
+        self.auth_guard.set_config(config.auth_protection.clone());
+        
+        for mut client in self.clients.iter_mut() {
+            if !client.capabilities.contains(Capabilities::ROUTE_PUSH) {
+                continue;
+            }
+            
+            let routes = config.routes_for(&client.username, client.assigned_ipv6.is_some());
+            if routes == client.routes {
+                continue;
+            }
+            
+            info!("Pushing {} updated routes to {} ({})", routes.len(), client.username, client.assigned_ip);
+            if client.control_tx.try_send(Message::RouteUpdate { routes: routes.clone() }).is_err() {
+                warn!("Failed to push routes to {}: control stream is busy or closed", client.assigned_ip);
+                continue;
+            }
+            client.routes = routes;
+        }
+        
+        *self.config.write().unwrap() = Arc::new(config);
+    }
+
+    /// End the sessions of users who were removed or disabled, including
+    /// dropped sessions waiting to be resumed; returns how many were live
+    pub fn disconnect_inactive_users(&self) -> usize {
+        self.parked.retain(|_, parked| {
+            if self.user_db.is_active(&parked.username) {
+                return true;
+            }
+            
+            debug!("Dropping resumable session of inactive user {}", parked.username);
+            self.release_addresses(parked.assigned_ip, parked.assigned_ipv6);
+            false
+        });
+        
+        self.disconnect_where("Account removed or disabled", None, |client| !self.user_db.is_active(&client.username))
+    }
+
+    /// Re-read the revocation list of client certificates
+    pub fn load_revocations(&self) -> Result<()> {
+        let config = self.config();
+        let revocations = match config.client_auth {
+            Some(_) => {
+                let path = config.revocation_list_path();
+                RevocationList::load(&path)
+                    .map_err(|e| anyhow!("Failed to load revocation list {}: {}", path.display(), e))?
+            }
+            None => RevocationList::default(),
+        };
+        
+        *self.revocations.write().unwrap() = revocations;
         
         Ok(())
     }
 
-    async fn receive_message(&self, stream: &mut RecvStream) -> Result<Message> {
-        // Read message length
-        let mut len_buf = [0u8; 4];
-        stream.read_exact(&mut len_buf).await?;
-        let data_len = u32::from_be_bytes(len_buf) as usize;
+    /// End the sessions whose client certificate was revoked
+    pub fn disconnect_revoked(&self) -> usize {
+        let revocations = self.revocations.read().unwrap().clone();
+        
+        self.disconnect_where("Certificate revoked", None, |client| {
+            client.certificate_serial.as_ref().is_some_and(|serial| revocations.is_revoked(serial))
+        })
+    }
+
+    /// Capabilities this server offers to clients
+    fn server_capabilities(&self) -> Capabilities {
+        let mut capabilities = Capabilities::DATAGRAMS
+            .union(Capabilities::ROUTE_PUSH)
+            .union(Capabilities::STATS)
+            .union(Capabilities::RECONNECT_HINT);
         
-        // Read message data
-        let mut data = vec![0u8; data_len];
-        stream.read_exact(&mut data).await?;
+        if self.ip_allocator_v6.is_some() {
+            capabilities.insert(Capabilities::IPV6);
+        }
         
-        let message = Message::from_bytes(&data)?;
-        Ok(message)
+        if self.config().resumption_timeout_secs > 0 {
+            capabilities.insert(Capabilities::RESUMPTION);
+        }
+        
+        capabilities
+    }
+
+    async fn send_message(&self, stream: &mut SendStream, message: &Message) -> Result<()> {
+        Ok(protocol::write_message(stream, message).await?)
+    }
+
+    async fn receive_message(&self, stream: &mut RecvStream) -> Result<Message> {
+        Ok(protocol::read_message(stream).await?)
     }
 
     fn start_packet_forwarder(&self) {
         let tun_device = self.tun_device.clone();
-        let packet_tx = self.packet_tx.clone();
         let clients = self.clients.clone();
+        let ipv6_routes = self.ipv6_routes.clone();
+        let metrics = self.metrics.clone();
+        let mut shutdown = self.shutdown.subscribe();
         
-        // Spawn task to read from TUN and forward to clients
+        // Spawn task to read from TUN and dispatch to per-client queues
+        // until the server shuts down
         tokio::spawn(async move {
-            let (tun_packet_tx, mut tun_packet_rx) = mpsc::channel(1000);
+            let mut reader = tun_device.reader();
             
-            // Start reading from TUN device
-            let _ = tun_device.start_reading(tun_packet_tx);
+            loop {
+                let packet = tokio::select! {
+                    packet = reader.read_packet() => packet,
+                    _ = shutdown.changed() => {
+                        debug!("Stopped reading from the TUN device");
+                        break;
+                    }
+                };
+                
+                let packet = match packet {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        metrics.record_tun_read_error();
+                        error!("Failed to read from TUN device: {}", e);
+                        break;
+                    }
+                };
+                
+                let dst_ip = match destination_ip(&packet) {
+                    Some(IpAddr::V6(ip)) => match ipv6_routes.get(&ip) {
+                        Some(client_ip) => *client_ip,
+                        None => {
+                            trace!("No client for destination {}", ip);
+                            continue;
+                        }
+                    },
+                    Some(ip) => ip,
+                    None => continue,
+                };
+                
+                // Clone the queue so the map entry is not held across an await
+                let queue = match clients.get(&dst_ip) {
+                    Some(client) => client.queue.clone(),
+                    None => {
+                        trace!("No client for destination {}", dst_ip);
+                        continue;
+                    }
+                };
+                
+                if !queue.push(packet).await {
+                    trace!("Dropped packet for client {}: queue full", dst_ip);
+                }
+            }
+        });
+    }
+
+    /// Bandwidth buckets shared by all sessions of a user
+    fn rate_limiter(&self, username: &str) -> Arc<RateLimiter> {
+        self.rate_limiters.entry(username.to_string())
+            .or_insert_with(|| Arc::new(RateLimiter::new(&self.user_db.limits(username))))
+            .clone()
+    }
+
+    /// Count a session's traffic towards its user's quotas, disconnecting
+    /// the user as soon as one is used up
+    fn record_usage(&self, usage: &UsageCounter, username: &str, bytes: usize) {
+        if let Some(reason) = usage.record(bytes) {
+            self.disconnect_user(username, &reason);
+        }
+    }
+
+    /// Every `LIMIT_CHECK_INTERVAL`, start new quota periods, apply changed
+    /// limits and disconnect users who used up a quota; usage is saved every
+    /// `USAGE_SAVE_INTERVAL`
+    fn start_limit_enforcement(&self) {
+        let manager = self.clone();
+        let mut shutdown = self.shutdown.subscribe();
+        
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(LIMIT_CHECK_INTERVAL);
+            let mut last_save = Instant::now();
             
-            // Process packets from TUN device
-            while let Some(packet) = tun_packet_rx.recv().await {
-                // Extract destination IP from packet
-                if packet.len() > 20 {  // IPv4 header is at least 20 bytes
-                    let version = (packet[0] >> 4) & 0xF;
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.changed() => break,
+                }
+                
+                manager.quotas.roll();
+                
+                for limiter in manager.rate_limiters.iter() {
+                    limiter.update(&manager.user_db.limits(limiter.key()));
+                }
+                
+                let mut exhausted: Vec<(String, String)> = Vec::new();
+                for client in manager.clients.iter() {
+                    if exhausted.iter().any(|(username, _)| *username == client.username) {
+                        continue;
+                    }
                     
-                    if version == 4 {  // IPv4
-                        let dst_ip = IpAddr::V4(std::net::Ipv4Addr::new(
-                            packet[16], packet[17], packet[18], packet[19]
-                        ));
-                        
-                        // Forward packet to the appropriate client
-                        if clients.contains_key(&dst_ip) {
-                            if let Err(e) = packet_tx.send((packet, dst_ip)).await {
-                                error!("Failed to forward packet to client {}: {}", dst_ip, e);
-                            }
-                        }
+                    // Quotas changed by a reload apply to traffic from now on
+                    let limits = manager.user_db.limits(&client.username);
+                    let usage = manager.quotas.usage(&client.username);
+                    usage.set_limits(&limits);
+                    if let Some(reason) = usage.exhausted(&limits) {
+                        exhausted.push((client.username.clone(), reason));
                     }
                 }
+                for (username, reason) in exhausted {
+                    manager.disconnect_user(&username, &reason);
+                }
+                
+                if last_save.elapsed() >= USAGE_SAVE_INTERVAL {
+                    last_save = Instant::now();
+                    manager.quotas.save_in_background().await;
+                }
             }
         });
     }
 
-    async fn start_client_handler(
+    /// Log a session's counters every `stats_interval_secs`, sending them to
+    /// clients that understand `Stats`, until the connection closes
+    fn start_stats_reporting(&self, client_ip: IpAddr, username: &str, capabilities: Capabilities) {
+        let (connection, control_tx, stats) = match self.clients.get(&client_ip) {
+            Some(client) => (client.connection.clone(), client.control_tx.clone(), client.stats.clone()),
+            None => return,
+        };
+        let username = username.to_string();
+        let period = Duration::from_secs(self.config().stats_interval_secs);
+        
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = connection.closed() => break,
+                }
+                
+                let traffic = stats.traffic.snapshot();
+                let rtt = connection.rtt();
+                match stats.peer() {
+                    Some(peer) => debug!(
+                        "Stats for {} ({}): sent {} bytes, received {} bytes, RTT {} ms; client reports sent {} bytes, received {} bytes, RTT {} ms",
+                        client_ip, username, traffic.bytes_sent, traffic.bytes_received, rtt.as_millis(),
+                        peer.traffic.bytes_sent, peer.traffic.bytes_received, peer.latency_ms
+                    ),
+                    None => debug!(
+                        "Stats for {} ({}): sent {} bytes, received {} bytes, RTT {} ms",
+                        client_ip, username, traffic.bytes_sent, traffic.bytes_received, rtt.as_millis()
+                    ),
+                }
+                
+                if capabilities.contains(Capabilities::STATS)
+                    && control_tx.send(traffic.to_message(rtt)).await.is_err()
+                {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn start_client_handler(
         &self,
-        connection: Connection,
-        username: String,
         client_ip: IpAddr,
         send: SendStream,
         recv: RecvStream,
-    ) -> Result<JoinHandle<()>> {
+        mut control_rx: mpsc::Receiver<Message>,
+    ) {
+        let (username, connection, capabilities, queue, stats) = match self.clients.get(&client_ip) {
+            Some(client) => (
+                client.username.clone(),
+                client.connection.clone(),
+                client.capabilities,
+                client.queue.clone(),
+                client.stats.clone(),
+            ),
+            None => return,
+        };
+        let manager = self.clone();
         let tun_device = self.tun_device.clone();
-        let packet_tx = self.packet_tx.clone();
-        let mut packet_rx = self.packet_rx.clone();
-        let clients = self.clients.clone();
+        let user_traffic = self.metrics.user_traffic(&username);
+        let usage = self.quotas.usage(&username);
+        usage.set_limits(&self.user_db.limits(&username));
+        let limiter = self.rate_limiter(&username);
+        
+        // Prefer unreliable datagrams for packet data, falling back to the
+        // control stream when the client or path does not support them
+        let datagrams = DatagramSender::new(
+            connection.clone(),
+            capabilities.contains(Capabilities::DATAGRAMS),
+        );
+        match datagrams.max_size() {
+            Some(max_size) => info!("Client {} uses QUIC datagrams (max {} bytes)", client_ip, max_size),
+            None => warn!("QUIC datagrams unavailable for client {}, using stream transport", client_ip),
+        }
+        
+        if self.config().stats_interval_secs > 0 {
+            self.start_stats_reporting(client_ip, &username, capabilities);
+        }
         
-        let handle = tokio::spawn(async move {
-            // Create bistream for client communication
+        tokio::spawn(async move {
+            let connected_at = Instant::now();
             let (mut send, mut recv) = (send, recv);
             
-            // Keep track of client streams
-            let mut client_streams = HashMap::new();
-            client_streams.insert(0, (send, recv));
-            
-            // Create channels for packet exchange
-            let (client_packet_tx, mut client_packet_rx) = mpsc::channel(1000);
-            
-            // Task to forward packets to the client
-            let forward_task = tokio::spawn(async move {
-                while let Some((packet, dst_ip)) = packet_rx.recv().await {
-                    if dst_ip == client_ip {
-                        if let Some((ref mut send, _)) = client_streams.get_mut(&0) {
-                            let msg = Message::PacketData(packet);
-                            let data = msg.to_bytes().unwrap();
-                            let data_len = data.len() as u32;
+            // Task to forward queued packets and control messages to the client
+            let forward_queue = queue.clone();
+            let forward_stats = stats.clone();
+            let forward_user_traffic = user_traffic.clone();
+            let forward_usage = usage.clone();
+            let forward_limiter = limiter.clone();
+            let forward_manager = manager.clone();
+            let forward_username = username.clone();
+            let mut forward_task = tokio::spawn(async move {
+                loop {
+                    let message = tokio::select! {
+                        Some(message) = control_rx.recv() => message,
+                        packet = forward_queue.pop() => {
+                            let Some(packet) = packet else { break };
                             
-                            if let Err(e) = send.write_all(&data_len.to_be_bytes()).await {
-                                error!("Failed to send packet length to client {}: {}", client_ip, e);
-                                break;
-                            }
-                            
-                            if let Err(e) = send.write_all(&data).await {
-                                error!("Failed to send packet to client {}: {}", client_ip, e);
-                                break;
+                            forward_limiter.download.acquire(packet.len()).await;
+                            forward_manager.record_usage(&forward_usage, &forward_username, packet.len());
+                            forward_stats.traffic.record_sent(packet.len());
+                            forward_user_traffic.record_sent(packet.len());
+                            match datagrams.send(packet) {
+                                Ok(None) => continue,
+                                Ok(Some(packet)) => Message::PacketData(packet),
+                                Err(e) => {
+                                    error!("Failed to send datagram to client {}: {}", client_ip, e);
+                                    break;
+                                }
                             }
                         }
+                    };
+                    
+                    if let Err(e) = protocol::write_message(&mut send, &message).await {
+                        error!("Failed to send message to client {}: {}", client_ip, e);
+                        break;
                     }
                 }
             });
             
-            // Task to receive packets from the client
-            let receive_task = tokio::spawn(async move {
-                while let Some((_, ref mut recv)) = client_streams.get_mut(&0) {
-                    // Read message length
-                    let mut len_buf = [0u8; 4];
-                    match recv.read_exact(&mut len_buf).await {
-                        Ok(_) => {
-                            let data_len = u32::from_be_bytes(len_buf) as usize;
-                            
-                            // Read message data
-                            let mut data = vec![0u8; data_len];
-                            match recv.read_exact(&mut data).await {
-                                Ok(_) => {
-                                    match Message::from_bytes(&data) {
-                                        Ok(Message::PacketData(packet)) => {
-                                            if let Err(e) = tun_device.write_packet(&packet).await {
-                                                error!("Failed to write packet to TUN: {}", e);
-                                            }
-                                        }
-                                        Ok(Message::KeepAlive) => {
-                                            // Handle keep-alive message
-                                        }
-                                        Ok(Message::Disconnect { reason }) => {
-                                            info!("Client {} requested disconnect: {}", client_ip, reason);
-                                            break;
-                                        }
-                                        Ok(Message::GameOptimizationInfo { game_type, latency_priority }) => {
-                                            info!(
-                                                "Client {} set game optimization: type={}, latency_priority={}",
-                                                client_ip, game_type, latency_priority
-                                            );
-                                        }
-                                        Ok(msg) => {
-                                            warn!("Unexpected message from client {}: {:?}", client_ip, msg);
-                                        }
-                                        Err(e) => {
-                                            error!("Failed to parse message from client {}: {}", client_ip, e);
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    error!("Failed to read message data from client {}: {}", client_ip, e);
-                                    break;
-                                }
+            // Task to receive datagrams from the client
+            let datagram_connection = connection.clone();
+            let datagram_tun_device = tun_device.clone();
+            let datagram_stats = stats.clone();
+            let datagram_user_traffic = user_traffic.clone();
+            let datagram_metrics = manager.metrics.clone();
+            let datagram_usage = usage.clone();
+            let datagram_limiter = limiter.clone();
+            let datagram_manager = manager.clone();
+            let datagram_username = username.clone();
+            let mut datagram_task = tokio::spawn(async move {
+                loop {
+                    match datagram_connection.read_datagram().await {
+                        Ok(packet) => {
+                            datagram_limiter.upload.acquire(packet.len()).await;
+                            datagram_manager.record_usage(&datagram_usage, &datagram_username, packet.len());
+                            datagram_stats.traffic.record_received(packet.len());
+                            datagram_user_traffic.record_received(packet.len());
+                            if let Err(e) = datagram_tun_device.write_packet(&packet).await {
+                                datagram_metrics.record_tun_write_error();
+                                error!("Failed to write packet to TUN: {}", e);
                             }
                         }
                         Err(e) => {
-                            error!("Failed to read message length from client {}: {}", client_ip, e);
+                            debug!("Datagram receive loop for client {} finished: {}", client_ip, e);
                             break;
                         }
                     }
                 }
-                
-                // Client disconnected
-                info!("Client {} disconnected", client_ip);
-                clients.remove(&client_ip);
+            });
+            
+            // Task to receive packets from the client; finishes with `true`
+            // when the client said goodbye
+            let receive_stats = stats.clone();
+            let receive_metrics = manager.metrics.clone();
+            let receive_manager = manager.clone();
+            let receive_username = username.clone();
+            let mut receive_task = tokio::spawn(async move {
+                loop {
+                    match protocol::read_message(&mut recv).await {
+                        Ok(Message::PacketData(packet)) => {
+                            limiter.upload.acquire(packet.len()).await;
+                            receive_manager.record_usage(&usage, &receive_username, packet.len());
+                            receive_stats.traffic.record_received(packet.len());
+                            user_traffic.record_received(packet.len());
+                            if let Err(e) = tun_device.write_packet(&packet).await {
+                                receive_metrics.record_tun_write_error();
+                                error!("Failed to write packet to TUN: {}", e);
+                            }
+                        }
+                        Ok(Message::KeepAlive) => {
+                            // Handle keep-alive message
+                        }
+                        Ok(Message::Disconnect { reason, .. }) => {
+                            info!("Client {} requested disconnect: {}", client_ip, reason);
+                            return true;
+                        }
+                        Ok(message @ Message::Stats { .. }) => {
+                            if let Some(peer) = PeerStats::from_message(&message) {
+                                receive_stats.record_peer(peer);
+                            }
+                        }
+                        Ok(Message::GameOptimizationInfo { game_type, latency_priority }) => {
+                            info!(
+                                "Client {} set game optimization: type={}, latency_priority={}",
+                                client_ip, game_type, latency_priority
+                            );
+                        }
+                        Ok(msg) => {
+                            warn!("Unexpected message from client {}: {:?}", client_ip, msg);
+                        }
+                        Err(e) => {
+                            error!("Failed to read message from client {}: {}", client_ip, e);
+                            return false;
+                        }
+                    }
+                }
             });
             
             // Wait for any task to complete
-            tokio::select! {
-                _ = forward_task => {},
-                _ = receive_task => {},
+            let said_goodbye = tokio::select! {
+                _ = &mut forward_task => false,
+                _ = &mut datagram_task => false,
+                result = &mut receive_task => result.unwrap_or(false),
+            };
+
+            // The others would keep the connection and queues alive
+            forward_task.abort();
+            datagram_task.abort();
+            receive_task.abort();
+            
+            // Client disconnected; a resumed session already took over the
+            // entry if it belongs to a different connection
+            let traffic = stats.traffic.snapshot();
+            info!(
+                "Client {} ({}) disconnected after {}s: {} bytes in {} packets sent, {} bytes in {} packets received",
+                client_ip,
+                username,
+                connected_at.elapsed().as_secs(),
+                traffic.bytes_sent,
+                traffic.packets_sent,
+                traffic.bytes_received,
+                traffic.packets_received
+            );
+            manager.metrics.release_user(&username);
+            let removed = manager.clients.remove_if(&client_ip, |_, client| {
+                client.connection.stable_id() == connection.stable_id()
+            });
+            if let Some((_, client)) = removed {
+                manager.forget_session(&client.username, client_ip);
+                if let Some(ipv6) = client.assigned_ipv6 {
+                    manager.ipv6_routes.remove(&ipv6);
+                }
+                
+                match client.resumption_token.clone() {
+                    Some(token) if !said_goodbye => manager.park_session(token, client),
+                    _ => manager.release_addresses(client.assigned_ip, client.assigned_ipv6),
+                }
             }
+            queue.close();
+            
+            if queue.dropped() > 0 {
+                info!("Dropped {} packets for client {} due to a full queue", queue.dropped(), client_ip);
+            }
+            
+            connection.close(0u32.into(), b"Session ended");
         });
-        
-        Ok(handle)
     }
-} 
\ No newline at end of file
+}
+
+/// Extract the destination address from a raw IP packet
+fn destination_ip(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        // IPv4 header is at least 20 bytes
+        4 if packet.len() >= 20 => Some(IpAddr::V4(std::net::Ipv4Addr::new(
+            packet[16], packet[17], packet[18], packet[19]
+        ))),
+        // IPv6 fixed header is 40 bytes, destination at offset 24
+        6 if packet.len() >= 40 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&packet[24..40]);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,242 @@
+use anyhow::Result;
+use std::fmt;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+/// Longest request line or header accepted
+const MAX_LINE_LEN: usize = 8192;
+
+/// Most headers accepted in one request
+const MAX_HEADERS: usize = 64;
+
+/// Just enough of an HTTP/1.1 request for the server's local endpoints
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Value of a header, matched case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A request that could not be read, with the status to answer it with
+#[derive(Debug)]
+pub struct RequestError {
+    pub status: u16,
+    message: String,
+}
+
+impl RequestError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+/// Read one request; the connection is closed after the response, so
+/// keep-alive and pipelining are not supported
+pub async fn read_request<S: AsyncRead + Unpin>(stream: S) -> Result<Request, RequestError> {
+    let mut reader = BufReader::new(stream);
+
+    let request_line = read_line(&mut reader, 414).await?;
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version), None) if version.starts_with("HTTP/1.") => {
+            (method.to_string(), path.to_string())
+        }
+        _ => return Err(RequestError::new(400, "Malformed request line")),
+    };
+
+    // Request bodies are not used by any endpoint, so they are never read
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(&mut reader, 431).await?;
+        if line.is_empty() {
+            return Ok(Request { method, path, headers });
+        }
+
+        if headers.len() >= MAX_HEADERS {
+            return Err(RequestError::new(431, "Too many headers"));
+        }
+
+        let (name, value) = line.split_once(':')
+            .filter(|(name, _)| !name.is_empty() && !name.ends_with(char::is_whitespace))
+            .ok_or_else(|| RequestError::new(400, "Malformed header"))?;
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+}
+
+/// Write a complete response and close the connection
+pub async fn write_response<S: AsyncWrite + Unpin>(
+    mut stream: S,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> Result<()> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason_phrase(status),
+        content_type,
+        body.len()
+    );
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// Read a CRLF-terminated line; `too_long` is the status for lines over
+/// [`MAX_LINE_LEN`]
+async fn read_line<R: AsyncRead + Unpin>(reader: &mut BufReader<R>, too_long: u16) -> Result<String, RequestError> {
+    let mut line = Vec::new();
+    (&mut *reader).take(MAX_LINE_LEN as u64 + 2).read_until(b'\n', &mut line).await
+        .map_err(|e| RequestError::new(400, format!("Failed to read request: {}", e)))?;
+
+    if line.len() > MAX_LINE_LEN && !line.ends_with(b"\r\n") {
+        return Err(RequestError::new(too_long, "Request line or header too long"));
+    }
+    let Some(line) = line.strip_suffix(b"\r\n") else {
+        return Err(RequestError::new(400, if line.ends_with(b"\n") {
+            "Line not terminated by CRLF"
+        } else {
+            "Connection closed mid-request"
+        }));
+    };
+
+    String::from_utf8(line.to_vec()).map_err(|_| RequestError::new(400, "Request is not valid UTF-8"))
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        414 => "URI Too Long",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(request: &[u8]) -> Result<Request, RequestError> {
+        read_request(request).await
+    }
+
+    async fn status_of(request: &[u8]) -> u16 {
+        read(request).await.unwrap_err().status
+    }
+
+    #[tokio::test]
+    async fn reads_method_path_and_headers() {
+        let request = read(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nAuthorization:  Bearer abc \r\n\r\nignored body").await.unwrap();
+
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/metrics");
+        assert_eq!(request.header("host"), Some("localhost"));
+        assert_eq!(request.header("AUTHORIZATION"), Some("Bearer abc"));
+        assert_eq!(request.header("accept"), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_requests() {
+        for request in [
+            &b""[..],
+            b"GET /metrics HTTP/1.1",
+            b"GET /metrics HTTP/1.1\r\n",
+            b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n",
+            b"GET /metrics HTTP/1.1\r\nHost: local",
+        ] {
+            let error = read(request).await.unwrap_err();
+            assert_eq!(error.status, 400, "{:?}", String::from_utf8_lossy(request));
+            assert_eq!(error.to_string(), "Connection closed mid-request");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_lines_without_crlf() {
+        let error = read(b"GET /metrics HTTP/1.1\n\n").await.unwrap_err();
+        assert_eq!((error.status, error.to_string().as_str()), (400, "Line not terminated by CRLF"));
+
+        let error = read(b"GET /metrics HTTP/1.1\r\nHost: localhost\n\r\n").await.unwrap_err();
+        assert_eq!((error.status, error.to_string().as_str()), (400, "Line not terminated by CRLF"));
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_request_lines_and_headers() {
+        for (request, message) in [
+            (&b"GET /metrics\r\n\r\n"[..], "Malformed request line"),
+            (b"GET /metrics HTTP/2\r\n\r\n", "Malformed request line"),
+            (b"GET /metrics HTTP/1.1 extra\r\n\r\n", "Malformed request line"),
+            (b"\r\n\r\n", "Malformed request line"),
+            (b"GET / HTTP/1.1\r\nno colon\r\n\r\n", "Malformed header"),
+            (b"GET / HTTP/1.1\r\n: empty name\r\n\r\n", "Malformed header"),
+            (b"GET / HTTP/1.1\r\nHost : localhost\r\n\r\n", "Malformed header"),
+            (b"GET /\xff HTTP/1.1\r\n\r\n", "Request is not valid UTF-8"),
+        ] {
+            let error = read(request).await.unwrap_err();
+            assert_eq!((error.status, error.to_string().as_str()), (400, message), "{:?}", String::from_utf8_lossy(request));
+        }
+    }
+
+    #[tokio::test]
+    async fn enforces_line_and_header_limits() {
+        let path = "a".repeat(MAX_LINE_LEN);
+        let request = format!("GET /{} HTTP/1.1\r\n\r\n", path);
+        assert_eq!(status_of(request.as_bytes()).await, 414);
+
+        // A header of exactly the limit is fine, one byte more is not
+        let value = "v".repeat(MAX_LINE_LEN - "X-Long: ".len());
+        let request = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", value);
+        assert_eq!(read(request.as_bytes()).await.unwrap().header("x-long"), Some(value.as_str()));
+
+        let request = format!("GET / HTTP/1.1\r\nX-Long: {}v\r\n\r\n", value);
+        let error = read(request.as_bytes()).await.unwrap_err();
+        assert_eq!((error.status, error.to_string().as_str()), (431, "Request line or header too long"));
+
+        let mut request = String::from("GET / HTTP/1.1\r\n");
+        for i in 0..MAX_HEADERS {
+            request.push_str(&format!("X-{}: {}\r\n", i, i));
+        }
+        assert!(read(format!("{}\r\n", request).as_bytes()).await.is_ok());
+
+        request.push_str("X-Extra: 1\r\n\r\n");
+        let error = read(request.as_bytes()).await.unwrap_err();
+        assert_eq!((error.status, error.to_string().as_str()), (431, "Too many headers"));
+    }
+
+    #[tokio::test]
+    async fn responses_carry_status_and_length() {
+        let mut response = Vec::new();
+        write_response(&mut response, 431, "text/plain", b"too large\n").await.unwrap();
+
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"), "{}", response);
+        assert!(response.contains("\r\nContent-Length: 10\r\n"));
+        assert!(response.ends_with("\r\n\r\ntoo large\n"));
+    }
+}
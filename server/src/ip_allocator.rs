@@ -1,61 +1,433 @@
-use std::collections::HashSet;
-use std::net::IpAddr;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::{error, warn};
 
-/// Allocates and manages IP addresses for clients
+use crate::storage;
+
+/// How long lease changes are collected before they are written together
+const LEASE_SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// Allocates and manages IP addresses for clients.
+///
+/// Addresses come from the subnet of the server's own tunnel address,
+/// excluding the network and broadcast addresses and the server itself.
+/// Users get their static reservation if they have one, otherwise an
+/// address they leased before, otherwise the first free address.
 #[derive(Clone)]
 pub struct IpAllocator {
-    base_network: IpAddr,
-    netmask: IpAddr,
-    used_ips: Arc<Mutex<HashSet<IpAddr>>>,
+    server_ip: IpAddr,
+    first_host: u128,
+    last_host: u128,
+    state: Arc<Mutex<AllocatorState>>,
+    leases: LeaseStore,
+}
+
+#[derive(Default)]
+struct AllocatorState {
+    used_ips: HashSet<IpAddr>,
     max_clients: usize,
+    /// Static reservations from the user database, address to username
+    reservations: HashMap<IpAddr, String>,
 }
 
 impl IpAllocator {
-    pub fn new(base_network: IpAddr, netmask: IpAddr, max_clients: usize) -> Self {
+    pub fn new(server_ip: IpAddr, netmask: IpAddr, max_clients: usize, leases: LeaseStore) -> Self {
+        let address = to_u128(server_ip);
+        let mask = to_u128(netmask);
+        let network = address & mask;
+        let (first_host, last_host) = match server_ip {
+            IpAddr::V4(_) => {
+                // Skip the network address and the broadcast address
+                let broadcast = network | (!mask & u32::MAX as u128);
+                (network + 1, broadcast.saturating_sub(1))
+            }
+            IpAddr::V6(_) => {
+                // Skip the subnet-router anycast address
+                (network + 1, network | !mask)
+            }
+        };
+
+        if last_host < first_host {
+            warn!("Subnet of {} has no usable client addresses", server_ip);
+        }
+
         Self {
-            base_network,
-            netmask,
-            used_ips: Arc::new(Mutex::new(HashSet::new())),
-            max_clients,
+            server_ip,
+            first_host,
+            last_host,
+            state: Arc::new(Mutex::new(AllocatorState {
+                max_clients,
+                ..Default::default()
+            })),
+            leases,
+        }
+    }
+
+    /// Replace the set of static reservations, ignoring other address families
+    pub fn set_reservations<I>(&self, reservations: I)
+    where
+        I: IntoIterator<Item = (String, IpAddr)>,
+    {
+        let mut state = self.state.lock().unwrap();
+        state.reservations.clear();
+
+        for (username, ip) in reservations {
+            if !self.is_assignable(ip) {
+                warn!("Ignoring reservation {} for {}: outside the client pool", ip, username);
+                continue;
+            }
+
+            if let Some(other) = state.reservations.insert(ip, username.clone()) {
+                warn!("Address {} is reserved for both {} and {}", ip, other, username);
+            }
         }
     }
 
-    /// Allocate a new IP address for a client
-    pub fn allocate_ip(&self) -> Option<IpAddr> {
-        let mut used_ips = self.used_ips.lock().unwrap();
-        
-        if used_ips.len() >= self.max_clients {
+    /// Allocate an address for a user's session
+    pub fn allocate_ip(&self, username: &str) -> Option<IpAddr> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.used_ips.len() >= state.max_clients {
             return None;
         }
-        
-        match self.base_network {
-            IpAddr::V4(base) => {
-                let base_u32: u32 = u32::from_be_bytes(base.octets());
-                
-                // Skip the first IP (network address) and the last IP (broadcast)
-                for i in 1..self.max_clients + 1 {
-                    let ip_u32 = base_u32 + i as u32;
-                    let ip = IpAddr::V4(std::net::Ipv4Addr::from(ip_u32.to_be_bytes()));
-                    
-                    if !used_ips.contains(&ip) {
-                        used_ips.insert(ip);
-                        return Some(ip);
-                    }
-                }
-                
-                None
-            }
-            IpAddr::V6(_) => {
-                // IPv6 not implemented yet
-                None
+
+        // A static reservation always wins when it is free
+        let reserved = state.reservations.iter()
+            .find(|(_, owner)| owner.as_str() == username)
+            .map(|(ip, _)| *ip);
+        if let Some(ip) = reserved {
+            if !state.used_ips.contains(&ip) {
+                state.used_ips.insert(ip);
+                return Some(ip);
             }
         }
+
+        // Then an address this user held before; a user with several
+        // sessions has one lease for each
+        let leased_to_others = self.leases.owners(self.server_ip.is_ipv6(), username);
+        let leased = self.leases.lookup(username, self.server_ip.is_ipv6()).into_iter().find(|ip| {
+            self.is_assignable(*ip)
+                && !state.used_ips.contains(ip)
+                && !state.reservations.contains_key(ip)
+        });
+        if let Some(ip) = leased {
+            state.used_ips.insert(ip);
+            return Some(ip);
+        }
+
+        // Prefer addresses nobody has leased, then fall back to reclaiming
+        // addresses leased to users who are not connected
+        let ip = self.find_free(&state, |ip| !leased_to_others.contains(ip))
+            .or_else(|| self.find_free(&state, |_| true))?;
+
+        state.used_ips.insert(ip);
+        self.leases.record(username, ip, |leased| self.is_assignable(*leased));
+
+        Some(ip)
     }
 
     /// Release an IP address back to the pool
     pub fn release_ip(&self, ip: IpAddr) -> bool {
-        let mut used_ips = self.used_ips.lock().unwrap();
-        used_ips.remove(&ip)
+        let mut state = self.state.lock().unwrap();
+        state.used_ips.remove(&ip)
+    }
+
+    /// Number of addresses in use and the most that can be in use at once
+    pub fn usage(&self) -> (usize, u128) {
+        let mut hosts = if self.last_host < self.first_host {
+            0
+        } else {
+            self.last_host - self.first_host + 1
+        };
+        if self.is_in_range(self.server_ip) {
+            hosts -= 1;
+        }
+
+        let state = self.state.lock().unwrap();
+        (state.used_ips.len(), hosts.min(state.max_clients as u128))
+    }
+
+    fn find_free<F>(&self, state: &AllocatorState, accept: F) -> Option<IpAddr>
+    where
+        F: Fn(&IpAddr) -> bool,
+    {
+        // Every skipped address is in one of a few finite sets, so this finds
+        // a free address quickly even in a huge IPv6 prefix
+        let mut candidate = self.first_host;
+        while candidate <= self.last_host {
+            let ip = from_u128(candidate, self.server_ip);
+
+            if ip != self.server_ip
+                && !state.used_ips.contains(&ip)
+                && !state.reservations.contains_key(&ip)
+                && accept(&ip)
+            {
+                return Some(ip);
+            }
+
+            candidate += 1;
+        }
+
+        None
+    }
+
+    fn is_assignable(&self, ip: IpAddr) -> bool {
+        ip != self.server_ip && self.is_in_range(ip)
+    }
+
+    fn is_in_range(&self, ip: IpAddr) -> bool {
+        if ip.is_ipv6() != self.server_ip.is_ipv6() {
+            return false;
+        }
+
+        let value = to_u128(ip);
+        value >= self.first_host && value <= self.last_host
+    }
+}
+
+/// Username-to-address leases, persisted so users keep their addresses
+/// across server restarts.
+///
+/// Changes are written by a background task started with `spawn_writer`,
+/// so allocating an address never waits for the disk.
+#[derive(Clone)]
+pub struct LeaseStore {
+    path: Option<PathBuf>,
+    leases: Arc<Mutex<HashMap<String, Vec<IpAddr>>>>,
+    changed: Arc<Notify>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LeaseFile {
+    leases: HashMap<String, Vec<IpAddr>>,
+}
+
+impl LeaseStore {
+    /// Load leases from `path`, starting empty if the file does not exist yet
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let leases = match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str::<LeaseFile>(&content)?.leases,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            leases: Arc::new(Mutex::new(leases)),
+            changed: Arc::new(Notify::new()),
+        })
     }
-} 
\ No newline at end of file
+
+    /// Write the leases shortly after they change, collecting changes that
+    /// come in quick succession into one write
+    pub fn spawn_writer(&self) {
+        let store = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                store.changed.notified().await;
+                tokio::time::sleep(LEASE_SAVE_DELAY).await;
+
+                let writer = store.clone();
+                match tokio::task::spawn_blocking(move || writer.save()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => error!("Failed to save IP leases: {}", e),
+                    Err(e) => error!("Failed to save IP leases: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Write the leases to disk now
+    pub fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let leases = self.leases.lock().unwrap().clone();
+        let content = serde_json::to_string_pretty(&LeaseFile { leases })?;
+        storage::write_atomic(path, content.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Addresses of one family leased to `username`, oldest first
+    fn lookup(&self, username: &str, ipv6: bool) -> Vec<IpAddr> {
+        let leases = self.leases.lock().unwrap();
+        leases.get(username)
+            .map(|ips| ips.iter().filter(|ip| ip.is_ipv6() == ipv6).copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Addresses of one family leased to anyone other than `username`
+    fn owners(&self, ipv6: bool, username: &str) -> HashSet<IpAddr> {
+        let leases = self.leases.lock().unwrap();
+        leases.iter()
+            .filter(|(owner, _)| owner.as_str() != username)
+            .flat_map(|(_, ips)| ips.iter().copied())
+            .filter(|ip| ip.is_ipv6() == ipv6)
+            .collect()
+    }
+
+    /// Lease `ip` to `username`, dropping the user's leases of the same
+    /// family that `still_valid` rejects, e.g. after the pool changed
+    fn record<F>(&self, username: &str, ip: IpAddr, still_valid: F)
+    where
+        F: Fn(&IpAddr) -> bool,
+    {
+        let mut leases = self.leases.lock().unwrap();
+
+        // An address belongs to at most one user
+        for ips in leases.values_mut() {
+            ips.retain(|leased| *leased != ip);
+        }
+
+        // Kept next to the user's other leases, which may belong to
+        // sessions that are still connected
+        let ips = leases.entry(username.to_string()).or_default();
+        ips.retain(|leased| leased.is_ipv6() != ip.is_ipv6() || still_valid(leased));
+        ips.push(ip);
+        leases.retain(|_, ips| !ips.is_empty());
+        drop(leases);
+
+        self.changed.notify_one();
+    }
+}
+
+fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u32::from(ip) as u128,
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+fn from_u128(value: u128, family: IpAddr) -> IpAddr {
+    match family {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(value as u32)),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lease_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("quicvpn-leases-{}-{}.json", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// 10.0.0.0/29 with the server on 10.0.0.1, leaving .2 to .6 for clients
+    fn allocator(leases: LeaseStore) -> IpAllocator {
+        IpAllocator::new("10.0.0.1".parse().unwrap(), "255.255.255.248".parse().unwrap(), 100, leases)
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn pool_skips_network_broadcast_and_server() {
+        let allocator = allocator(LeaseStore::load(lease_path("bounds")).unwrap());
+
+        let assigned: Vec<IpAddr> = (0..5)
+            .map(|n| allocator.allocate_ip(&format!("user{}", n)).unwrap())
+            .collect();
+        let expected: Vec<IpAddr> = (2..=6).map(|host| ip(&format!("10.0.0.{}", host))).collect();
+        assert_eq!(assigned, expected);
+        assert_eq!(allocator.usage(), (5, 5));
+    }
+
+    #[test]
+    fn ipv6_pool_skips_subnet_router_and_server() {
+        let allocator = IpAllocator::new(
+            "fd00::1".parse().unwrap(),
+            "ffff:ffff:ffff:ffff:ffff:ffff:ffff:fffc".parse().unwrap(),
+            100,
+            LeaseStore::load(lease_path("ipv6")).unwrap(),
+        );
+
+        assert_eq!(allocator.allocate_ip("a"), Some(ip("fd00::2")));
+        assert_eq!(allocator.allocate_ip("b"), Some(ip("fd00::3")));
+        assert_eq!(allocator.allocate_ip("c"), None);
+    }
+
+    #[test]
+    fn reservations_are_kept_for_their_user() {
+        let allocator = allocator(LeaseStore::load(lease_path("reservations")).unwrap());
+        allocator.set_reservations([
+            ("alice".to_string(), ip("10.0.0.2")),
+            ("bob".to_string(), ip("10.0.0.7")),
+            ("carol".to_string(), ip("fd00::2")),
+        ]);
+
+        assert_eq!(allocator.allocate_ip("bob"), Some(ip("10.0.0.3")));
+        assert_eq!(allocator.allocate_ip("alice"), Some(ip("10.0.0.2")));
+
+        // A second session of the same user cannot share the address
+        assert_eq!(allocator.allocate_ip("alice"), Some(ip("10.0.0.4")));
+    }
+
+    #[test]
+    fn exhausted_pool_refuses_until_released() {
+        let allocator = allocator(LeaseStore::load(lease_path("exhaustion")).unwrap());
+        for n in 0..5 {
+            assert!(allocator.allocate_ip(&format!("user{}", n)).is_some());
+        }
+        assert_eq!(allocator.allocate_ip("late"), None);
+
+        assert!(allocator.release_ip(ip("10.0.0.4")));
+        assert_eq!(allocator.allocate_ip("late"), Some(ip("10.0.0.4")));
+    }
+
+    #[test]
+    fn max_clients_caps_the_pool() {
+        let leases = LeaseStore::load(lease_path("max-clients")).unwrap();
+        let allocator = IpAllocator::new(ip("10.0.0.1"), ip("255.255.255.248"), 2, leases);
+
+        assert!(allocator.allocate_ip("a").is_some());
+        assert!(allocator.allocate_ip("b").is_some());
+        assert_eq!(allocator.allocate_ip("c"), None);
+        assert_eq!(allocator.usage(), (2, 2));
+    }
+
+    #[test]
+    fn leases_survive_a_reload() {
+        let path = lease_path("reload");
+        let leases = LeaseStore::load(&path).unwrap();
+        let first = allocator(leases.clone());
+        assert_eq!(first.allocate_ip("alice"), Some(ip("10.0.0.2")));
+        assert_eq!(first.allocate_ip("bob"), Some(ip("10.0.0.3")));
+        leases.save().unwrap();
+
+        let second = allocator(LeaseStore::load(&path).unwrap());
+        assert_eq!(second.allocate_ip("bob"), Some(ip("10.0.0.3")));
+        assert_eq!(second.allocate_ip("alice"), Some(ip("10.0.0.2")));
+        // New users avoid addresses leased to others while any are free
+        assert_eq!(second.allocate_ip("carol"), Some(ip("10.0.0.4")));
+    }
+
+    #[test]
+    fn concurrent_sessions_keep_their_own_leases() {
+        let path = lease_path("sessions");
+        let leases = LeaseStore::load(&path).unwrap();
+        let first = allocator(leases.clone());
+        assert_eq!(first.allocate_ip("alice"), Some(ip("10.0.0.2")));
+        assert_eq!(first.allocate_ip("alice"), Some(ip("10.0.0.3")));
+        leases.save().unwrap();
+
+        let second = allocator(LeaseStore::load(&path).unwrap());
+        assert_eq!(second.allocate_ip("bob"), Some(ip("10.0.0.4")));
+        assert_eq!(second.allocate_ip("alice"), Some(ip("10.0.0.2")));
+        assert_eq!(second.allocate_ip("alice"), Some(ip("10.0.0.3")));
+    }
+}
@@ -1,31 +1,97 @@
+mod admin;
+mod auth_guard;
+mod certificate;
 mod client_manager;
+mod http;
 mod ip_allocator;
+mod metrics;
+mod packet_queue;
+mod pki_cli;
+mod quota;
+mod rate_limit;
+mod reload;
+mod revocation;
+mod session_limit;
+mod storage;
+mod user_cli;
 mod user_db;
 
 use anyhow::Result;
-use clap::Parser;
-use common::crypto;
-use common::config::ServerConfig;
+use clap::{Parser, Subcommand};
+use common::crypto::{self, ClientAuth};
+use common::config::{
+    AdminConfig, AuthProtectionConfig, CertRotationConfig, PasswordHashConfig, QueueConfig, ServerConfig,
+    SessionLimitConfig,
+};
 use quinn::{Endpoint, ServerConfig as QuinnServerConfig};
-use std::net::SocketAddr;
+use std::net::Ipv6Addr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs;
-use tracing::{error, info};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+use tracing_subscriber::prelude::*;
 use tracing_subscriber::EnvFilter;
 
+use admin::{AdminApi, AdminCommand};
+use auth_guard::AuthGuard;
+use certificate::ServerCertificate;
 use client_manager::ClientManager;
-use ip_allocator::IpAllocator;
+use ip_allocator::{IpAllocator, LeaseStore};
+use metrics::{HandshakeResult, Metrics};
+use quota::QuotaStore;
+use reload::Reloader;
+use pki_cli::PkiCommand;
+use user_cli::UserCommand;
 use user_db::UserDatabase;
 
+/// Size of the QUIC datagram send and receive buffers
+const DATAGRAM_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// How long closing connections get to notify their peers on shutdown
+const ENDPOINT_CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
+
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
 struct Args {
-    #[clap(short, long, default_value = "config.json")]
+    #[clap(short, long, default_value = "config.json", global = true)]
     config: PathBuf,
 
     #[clap(short, long)]
     generate_cert: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage the users allowed to connect
+    User {
+        /// Tell the running server to reload its users afterwards
+        #[clap(long)]
+        reload: bool,
+
+        #[clap(subcommand)]
+        command: UserCommand,
+    },
+
+    /// Inspect and control the running server
+    Admin {
+        #[clap(subcommand)]
+        command: AdminCommand,
+    },
+
+    /// Issue and revoke certificates with a private CA
+    Pki {
+        /// Tell the running server to reload its revocation list afterwards
+        #[clap(long)]
+        reload: bool,
+
+        #[clap(subcommand)]
+        command: PkiCommand,
+    },
 }
 
 #[tokio::main]
@@ -39,23 +105,43 @@ async fn main() -> Result<()> {
 
     let config = ServerConfig::load(args.config.to_str().unwrap())?;
 
-    // Initialize logging
-    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-        EnvFilter::new(config.log_level.clone())
-    });
+    match args.command {
+        Some(Command::User { reload, command }) => return user_cli::run(&config, command, reload).await,
+        Some(Command::Admin { command }) => return admin::run(&config, command).await,
+        Some(Command::Pki { reload, command }) => return pki_cli::run(&config, command, reload).await,
+        None => {}
+    }
+
+    // Initialize logging; the configured level can be reloaded unless
+    // RUST_LOG overrides it
+    let (filter, configured_level) = match EnvFilter::try_from_default_env() {
+        Ok(filter) => (filter, false),
+        Err(_) => (EnvFilter::new(config.log_level.clone()), true),
+    };
+    let (filter, log_filter) = tracing_subscriber::reload::Layer::new(filter);
     
-    tracing_subscriber::fmt()
-        .with_env_filter(filter)
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
         .init();
 
     info!("Starting QUIC VPN Server v{}", env!("CARGO_PKG_VERSION"));
 
-    // Load certificates
-    let cert = fs::read(&config.cert_path).await?;
-    let key = fs::read(&config.key_path).await?;
+    // Load certificates; the server certificate is re-read when it changes
+    let certificate = ServerCertificate::load(&config)?;
+    let client_ca = match &config.client_auth {
+        Some(client_auth) => Some(crypto::load_certificates(&client_auth.ca_path)?),
+        None => None,
+    };
     
     // Create TLS configuration
-    let server_crypto_config = crypto::load_server_config(&cert, &key)?;
+    let client_auth = match (&config.client_auth, &client_ca) {
+        (Some(client_auth), Some(ca)) if client_auth.allow_password_only => ClientAuth::Optional(ca),
+        (Some(_), Some(ca)) => ClientAuth::Required(ca),
+        _ => ClientAuth::None,
+    };
+    let server_crypto_config = crypto::build_server_config(Arc::new(certificate.clone()), client_auth)?;
+    certificate.spawn_watcher();
     
     // Setup QUIC configuration
     let mut server_config = QuinnServerConfig::with_crypto(Arc::new(server_crypto_config));
@@ -67,9 +153,11 @@ async fn main() -> Result<()> {
         transport_config.max_idle_timeout(Some(std::time::Duration::from_secs(30).try_into()?));
         transport_config.keep_alive_interval(Some(std::time::Duration::from_secs(5)));
         
+        // Keep datagrams enabled so packets can bypass stream retransmission
+        transport_config.datagram_receive_buffer_size(Some(DATAGRAM_BUFFER_SIZE));
+        transport_config.datagram_send_buffer_size(DATAGRAM_BUFFER_SIZE);
+        
         if config.gaming_optimization {
-            // Higher priority for real-time data
-            transport_config.datagram_receive_buffer_size(None);
             // Faster recovery from packet loss
             transport_config.initial_rtt(std::time::Duration::from_millis(100));
         }
@@ -78,52 +166,184 @@ async fn main() -> Result<()> {
     });
     
     // Create user database
-    let user_db = UserDatabase::load(&config.user_db_path).await?;
+    let user_db = UserDatabase::load(&config.user_db_path, &config.password_hashing).await?;
     
-    // Create IP allocator
-    let ip_allocator = IpAllocator::new(config.vpn_network, config.vpn_netmask, config.max_clients);
+    // Create IP allocators sharing one lease database
+    let leases = LeaseStore::load(&config.lease_db_path)?;
+    leases.spawn_writer();
+    let ip_allocator = IpAllocator::new(
+        config.vpn_network,
+        config.vpn_netmask,
+        config.max_clients,
+        leases.clone(),
+    );
+    let ip_allocator_v6 = config.vpn_network_v6.map(|network| {
+        IpAllocator::new(
+            network.into(),
+            ipv6_netmask(config.vpn_prefix_len_v6).into(),
+            config.max_clients,
+            leases.clone(),
+        )
+    });
+    
+    // Keep statically reserved addresses out of the dynamic pool
+    let reservations = user_db.reservations();
+    ip_allocator.set_reservations(reservations.clone());
+    if let Some(allocator) = &ip_allocator_v6 {
+        allocator.set_reservations(reservations);
+    }
+    
+    let mut allocators = vec![ip_allocator.clone()];
+    allocators.extend(ip_allocator_v6.clone());
     
     // Create client manager
+    let metrics = Metrics::new();
+    let quotas = QuotaStore::load(&config.quota_db_path)?;
+    let auth_guard = AuthGuard::new(config.auth_protection.clone());
     let client_manager = ClientManager::new(
         config.clone(),
-        user_db,
+        user_db.clone(),
         ip_allocator,
+        ip_allocator_v6,
+        metrics.clone(),
+        quotas,
+        auth_guard.clone(),
     );
+    client_manager.load_revocations()?;
+    
+    if let Some(addr) = config.metrics_listen_addr {
+        metrics::serve(addr, metrics.clone(), client_manager.clone()).await?;
+    }
+    
+    // Reload users and configuration on SIGHUP, e.g. after `user` subcommands with --reload
+    let reloader = Reloader::new(
+        args.config.clone(),
+        &config,
+        user_db,
+        allocators,
+        client_manager.clone(),
+        certificate,
+        configured_level.then_some(log_filter),
+    );
+    reloader.spawn_signal_handler()?;
+    
+    // The socket is removed again when the guard drops at shutdown
+    let admin = AdminApi::new(client_manager.clone(), reloader);
+    let _admin_socket = admin.serve_socket(&config.admin.socket_path)?;
+    
+    if let Some(addr) = config.admin.http_listen_addr {
+        match &config.admin.token {
+            Some(token) => admin.serve_http(addr, token.clone()).await?,
+            None => error!("Admin HTTP API disabled: admin.http_listen_addr is set but admin.token is not"),
+        }
+    }
+    
+    if let Some(pid_file) = &config.pid_file {
+        fs::write(pid_file, std::process::id().to_string()).await?;
+    }
     
     // Create and setup the endpoint
     let endpoint = Endpoint::server(server_config, config.listen_addr)?;
     
     info!("Listening on {}", config.listen_addr);
     
-    // Accept new connections
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    
+    // Accept new connections until asked to stop
     loop {
-        match endpoint.accept().await {
-            Some(conn) => {
-                let client_manager = client_manager.clone();
-                
-                tokio::spawn(async move {
-                    match conn.await {
-                        Ok(connection) => {
-                            let remote_addr = connection.remote_address();
-                            info!("Connection from {}", remote_addr);
-                            
-                            if let Err(e) = client_manager.handle_connection(connection).await {
-                                error!("Error handling client connection from {}: {}", remote_addr, e);
-                            }
-                        }
-                        Err(e) => {
-                            error!("Connection failed: {}", e);
-                        }
-                    }
-                });
+        let conn = tokio::select! {
+            conn = endpoint.accept() => match conn {
+                Some(conn) => conn,
+                None => break,
+            },
+            _ = terminate.recv() => {
+                info!("Received SIGTERM, shutting down");
+                break;
+            }
+            _ = interrupt.recv() => {
+                info!("Received SIGINT, shutting down");
+                break;
             }
-            None => break,
+        };
+        
+        // Turn away banned addresses and floods before spending any work on them
+        let remote_ip = conn.remote_address().ip();
+        if let Some(remaining) = auth_guard.address_banned(remote_ip) {
+            warn!(ban_secs = remaining.as_secs(), ip = %remote_ip, "Refused connection from banned address");
+            metrics.record_handshake(HandshakeResult::Banned);
+            continue;
         }
+        let Some(permit) = auth_guard.try_start_handshake() else {
+            warn!(ip = %remote_ip, "Refused connection, too many handshakes in progress");
+            metrics.record_handshake(HandshakeResult::Overloaded);
+            continue;
+        };
+        
+        let client_manager = client_manager.clone();
+        let metrics = metrics.clone();
+        let handshake_timeout = auth_guard.handshake_timeout();
+        
+        tokio::spawn(async move {
+            let _permit = permit;
+            
+            let Ok(conn) = tokio::time::timeout(handshake_timeout, conn).await else {
+                warn!(ip = %remote_ip, "Handshake timed out");
+                metrics.record_handshake(HandshakeResult::Timeout);
+                return;
+            };
+            
+            match conn {
+                Ok(connection) => {
+                    let remote_addr = connection.remote_address();
+                    info!("Connection from {}", remote_addr);
+                    
+                    if let Err(e) = client_manager.handle_connection(connection).await {
+                        error!("Error handling client connection from {}: {}", remote_addr, e);
+                    }
+                }
+                Err(e) => {
+                    metrics.record_handshake(HandshakeResult::ConnectionFailed);
+                    error!("Connection failed: {}", e);
+                }
+            }
+        });
+    }
+    
+    // Refuse new connections, then let clients hear why they are dropped
+    // before the endpoint goes away
+    endpoint.set_server_config(None);
+    let remaining = client_manager.shutdown(
+        config.shutdown_reconnect_after_secs,
+        Duration::from_secs(config.shutdown_drain_secs),
+    ).await;
+    if remaining > 0 {
+        warn!("Closing {} sessions that did not end in time", remaining);
+    }
+    if let Err(e) = leases.save() {
+        error!("Failed to save IP leases: {}", e);
+    }
+    
+    endpoint.close(0u32.into(), b"Server shutting down");
+    if tokio::time::timeout(ENDPOINT_CLOSE_TIMEOUT, endpoint.wait_idle()).await.is_err() {
+        warn!("Timed out waiting for connections to close");
+    }
+    
+    if let Some(pid_file) = &config.pid_file {
+        let _ = fs::remove_file(pid_file).await;
     }
+    
+    info!("Server stopped");
 
     Ok(())
 }
 
+/// Netmask for an IPv6 prefix length
+fn ipv6_netmask(prefix_len: u8) -> Ipv6Addr {
+    let bits = u128::MAX.checked_shl(128 - prefix_len.min(128) as u32).unwrap_or(0);
+    Ipv6Addr::from(bits)
+}
+
 async fn generate_certificate() -> Result<()> {
     let hostname = "quicvpn.server";
     println!("Generating self-signed certificate for hostname: {}", hostname);
@@ -140,21 +360,42 @@ async fn generate_certificate() -> Result<()> {
         listen_addr: "0.0.0.0:4433".parse()?,
         cert_path: "server.crt".into(),
         key_path: "server.key".into(),
-        vpn_network: "10.10.0.0".parse()?,
+        cert_rotation: CertRotationConfig::default(),
+        vpn_network: "10.10.0.1".parse()?,
         vpn_netmask: "255.255.255.0".parse()?,
+        vpn_network_v6: None,
+        vpn_prefix_len_v6: 64,
         mtu: 1400,
         log_level: "info".to_string(),
         user_db_path: "users.json".into(),
+        lease_db_path: "leases.json".into(),
+        quota_db_path: "usage.json".into(),
         max_clients: 100,
         gaming_optimization: true,
+        pid_file: None,
+        client_queue: QueueConfig::default(),
+        client_queue_overrides: Default::default(),
+        session_limit: SessionLimitConfig::default(),
+        auth_protection: AuthProtectionConfig::default(),
+        client_auth: None,
+        pki_dir: "pki".into(),
+        password_hashing: PasswordHashConfig::default(),
+        resumption_timeout_secs: 120,
+        routes: Vec::new(),
+        user_routes: Default::default(),
+        stats_interval_secs: 30,
+        metrics_listen_addr: None,
+        admin: AdminConfig::default(),
+        shutdown_drain_secs: 10,
+        shutdown_reconnect_after_secs: None,
     };
     
     config.save("config.json")?;
     println!("Default config file has been saved to config.json");
     
     // Create a default user database
-    let mut user_db = user_db::UserDatabase::new();
-    user_db.add_user("admin".to_string(), "password".to_string());
+    let mut user_db = user_db::UserDatabase::new(&config.password_hashing)?;
+    user_db.add_user("admin".to_string(), "password".to_string())?;
     
     user_db.save("users.json").await?;
     println!("Default user database has been saved to users.json with admin:password");
@@ -0,0 +1,316 @@
+use anyhow::{anyhow, Result};
+use common::stats::TrafficCounters;
+use dashmap::DashMap;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::client_manager::ClientManager;
+use crate::http;
+
+/// How long a scraper may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Outcome of a client handshake, exported as the `result` label
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HandshakeResult {
+    Success,
+    /// TLS or QUIC connection setup failed
+    ConnectionFailed,
+    UnsupportedVersion,
+    AuthFailed,
+    NoAddresses,
+    /// The client did not start with a `ClientHello`
+    BadHello,
+    /// The stream failed part way through
+    Error,
+    /// The server was shutting down
+    ShuttingDown,
+    /// The user had used up a data quota
+    QuotaExceeded,
+    /// The user already had as many sessions as allowed
+    SessionLimit,
+    /// The source address or username was banned after failed logins
+    Banned,
+    /// Too many handshakes were already in progress
+    Overloaded,
+    /// The client did not finish its handshake in time
+    Timeout,
+}
+
+impl HandshakeResult {
+    const ALL: [Self; 13] = [
+        Self::Success,
+        Self::ConnectionFailed,
+        Self::UnsupportedVersion,
+        Self::AuthFailed,
+        Self::NoAddresses,
+        Self::BadHello,
+        Self::Error,
+        Self::ShuttingDown,
+        Self::QuotaExceeded,
+        Self::SessionLimit,
+        Self::Banned,
+        Self::Overloaded,
+        Self::Timeout,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::ConnectionFailed => "connection_failed",
+            Self::UnsupportedVersion => "unsupported_version",
+            Self::AuthFailed => "auth_failed",
+            Self::NoAddresses => "no_addresses",
+            Self::BadHello => "bad_hello",
+            Self::Error => "error",
+            Self::ShuttingDown => "shutting_down",
+            Self::QuotaExceeded => "quota_exceeded",
+            Self::SessionLimit => "session_limit",
+            Self::Banned => "banned",
+            Self::Overloaded => "overloaded",
+            Self::Timeout => "timeout",
+        }
+    }
+}
+
+/// Server-wide counters exported on the metrics endpoint.
+///
+/// Values that already live elsewhere, like the connected clients and the
+/// address pools, are read from the [`ClientManager`] at scrape time.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<MetricsInner>,
+}
+
+#[derive(Default)]
+struct MetricsInner {
+    handshakes: [AtomicU64; HandshakeResult::ALL.len()],
+    tun_read_errors: AtomicU64,
+    tun_write_errors: AtomicU64,
+    /// Traffic of the users with a session, dropped with their last one
+    users: DashMap<String, UserSeries>,
+}
+
+#[derive(Default)]
+struct UserSeries {
+    traffic: Arc<TrafficCounters>,
+    sessions: usize,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_handshake(&self, result: HandshakeResult) {
+        self.inner.handshakes[result as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_tun_read_error(&self) {
+        self.inner.tun_read_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_tun_write_error(&self) {
+        self.inner.tun_write_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counters a session adds its traffic to, shared by all of a user's
+    /// sessions. Call [`Metrics::release_user`] when the session ends.
+    pub fn user_traffic(&self, username: &str) -> Arc<TrafficCounters> {
+        let mut series = self.inner.users.entry(username.to_string()).or_default();
+        series.sessions += 1;
+        series.traffic.clone()
+    }
+
+    /// End a session started with [`Metrics::user_traffic`], dropping the
+    /// user's series with their last session so departed users do not
+    /// pile up; the counters start again from zero on the next session
+    pub fn release_user(&self, username: &str) {
+        self.inner.users.remove_if_mut(username, |_, series| {
+            series.sessions = series.sessions.saturating_sub(1);
+            series.sessions == 0
+        });
+    }
+
+    /// Render all metrics in the Prometheus text exposition format
+    pub fn render(&self, manager: &ClientManager) -> String {
+        let mut out = String::new();
+        let sessions = manager.sessions();
+
+        family(&mut out, "quicvpn_active_clients", "gauge", "Clients with an established session");
+        sample(&mut out, "quicvpn_active_clients", &[], sessions.len());
+
+        family(&mut out, "quicvpn_handshakes_total", "counter", "Client handshakes by outcome");
+        for result in HandshakeResult::ALL {
+            let count = self.inner.handshakes[result as usize].load(Ordering::Relaxed);
+            sample(&mut out, "quicvpn_handshakes_total", &[("result", result.as_str())], count);
+        }
+
+        let users: BTreeMap<String, _> = self.inner.users.iter()
+            .map(|entry| (entry.key().clone(), entry.value().traffic.snapshot()))
+            .collect();
+
+        family(&mut out, "quicvpn_user_bytes_total", "counter", "Tunnel bytes per user, sent is towards the client");
+        for (user, traffic) in &users {
+            sample(&mut out, "quicvpn_user_bytes_total", &[("user", user), ("direction", "sent")], traffic.bytes_sent);
+            sample(&mut out, "quicvpn_user_bytes_total", &[("user", user), ("direction", "received")], traffic.bytes_received);
+        }
+
+        family(&mut out, "quicvpn_user_packets_total", "counter", "Tunnel packets per user, sent is towards the client");
+        for (user, traffic) in &users {
+            sample(&mut out, "quicvpn_user_packets_total", &[("user", user), ("direction", "sent")], traffic.packets_sent);
+            sample(&mut out, "quicvpn_user_packets_total", &[("user", user), ("direction", "received")], traffic.packets_received);
+        }
+
+        let pools = manager.pool_usage();
+
+        family(&mut out, "quicvpn_ip_pool_used", "gauge", "Client addresses currently assigned");
+        for (pool, used, _) in &pools {
+            sample(&mut out, "quicvpn_ip_pool_used", &[("family", pool)], used);
+        }
+
+        family(&mut out, "quicvpn_ip_pool_capacity", "gauge", "Client addresses that can be assigned at once");
+        for (pool, _, capacity) in &pools {
+            sample(&mut out, "quicvpn_ip_pool_capacity", &[("family", pool)], capacity);
+        }
+
+        family(&mut out, "quicvpn_tun_errors_total", "counter", "Failed reads and writes on the TUN device");
+        let read_errors = self.inner.tun_read_errors.load(Ordering::Relaxed);
+        let write_errors = self.inner.tun_write_errors.load(Ordering::Relaxed);
+        sample(&mut out, "quicvpn_tun_errors_total", &[("operation", "read")], read_errors);
+        sample(&mut out, "quicvpn_tun_errors_total", &[("operation", "write")], write_errors);
+
+        family(&mut out, "quicvpn_quic_rtt_seconds", "gauge", "Smoothed round-trip time of each session");
+        for session in &sessions {
+            let address = session.assigned_ip.to_string();
+            let labels = [("user", session.username.as_str()), ("address", address.as_str())];
+            sample(&mut out, "quicvpn_quic_rtt_seconds", &labels, session.rtt.as_secs_f64());
+        }
+
+        family(&mut out, "quicvpn_quic_lost_packets_total", "counter", "QUIC packets lost on each session");
+        for session in &sessions {
+            let address = session.assigned_ip.to_string();
+            let labels = [("user", session.username.as_str()), ("address", address.as_str())];
+            sample(&mut out, "quicvpn_quic_lost_packets_total", &labels, session.lost_packets);
+        }
+
+        family(&mut out, "quicvpn_quic_congestion_window_bytes", "gauge", "Congestion window of each session");
+        for session in &sessions {
+            let address = session.assigned_ip.to_string();
+            let labels = [("user", session.username.as_str()), ("address", address.as_str())];
+            sample(&mut out, "quicvpn_quic_congestion_window_bytes", &labels, session.congestion_window);
+        }
+
+        out
+    }
+}
+
+/// Serve `GET /metrics` on `addr` in the background
+pub async fn serve(addr: SocketAddr, metrics: Metrics, manager: ClientManager) -> Result<()> {
+    let listener = TcpListener::bind(addr).await
+        .map_err(|e| anyhow!("Failed to bind metrics listener {}: {}", addr, e))?;
+    info!("Serving metrics on http://{}/metrics", addr);
+
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed to accept metrics connection: {}", e);
+                    continue;
+                }
+            };
+
+            let metrics = metrics.clone();
+            let manager = manager.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_scrape(stream, &metrics, &manager).await {
+                    debug!("Metrics request failed: {}", e);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+async fn handle_scrape(mut stream: TcpStream, metrics: &Metrics, manager: &ClientManager) -> Result<()> {
+    let request = match tokio::time::timeout(REQUEST_TIMEOUT, http::read_request(&mut stream)).await
+        .map_err(|_| anyhow!("Timed out reading request"))?
+    {
+        Ok(request) => request,
+        Err(e) => {
+            let body = format!("{}\n", e);
+            return http::write_response(stream, e.status, "text/plain", body.as_bytes()).await;
+        }
+    };
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => {
+            let body = metrics.render(manager);
+            http::write_response(stream, 200, "text/plain; version=0.0.4", body.as_bytes()).await
+        }
+        (_, "/metrics") => http::write_response(stream, 405, "text/plain", b"Method not allowed\n").await,
+        _ => http::write_response(stream, 404, "text/plain", b"Not found\n").await,
+    }
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+    out.push_str(name);
+
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels.iter()
+            .map(|(key, value)| format!("{}=\"{}\"", key, escape_label(value)))
+            .collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+
+    let _ = writeln!(out, " {}", value);
+}
+
+/// Escape a label value as the exposition format requires
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_series_live_as_long_as_a_session() {
+        let metrics = Metrics::new();
+
+        let first = metrics.user_traffic("alice");
+        let second = metrics.user_traffic("alice");
+        first.record_sent(100);
+        assert!(Arc::ptr_eq(&first, &second));
+
+        metrics.release_user("alice");
+        assert_eq!(metrics.inner.users.get("alice").unwrap().traffic.snapshot().bytes_sent, 100);
+
+        metrics.release_user("alice");
+        assert!(metrics.inner.users.is_empty());
+
+        // A later session starts a new series
+        assert_eq!(metrics.user_traffic("alice").snapshot().bytes_sent, 0);
+    }
+
+    #[test]
+    fn releasing_an_unknown_user_is_harmless() {
+        let metrics = Metrics::new();
+        metrics.release_user("nobody");
+        assert!(metrics.inner.users.is_empty());
+    }
+}
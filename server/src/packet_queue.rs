@@ -0,0 +1,209 @@
+use bytes::Bytes;
+use common::config::{QueueConfig, QueuePolicy};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+/// Bounded queue of packets waiting to be sent to a single client.
+///
+/// Has one producer (the TUN forwarder) and one consumer (the client's
+/// send task). What happens when it is full depends on the configured
+/// [`QueuePolicy`]; `Block` stalls the TUN forwarder for every client, so
+/// it should only be used for trusted, well-connected clients.
+pub struct PacketQueue {
+    state: Mutex<QueueState>,
+    capacity: usize,
+    policy: QueuePolicy,
+    items: Notify,
+    space: Notify,
+    dropped: AtomicU64,
+}
+
+struct QueueState {
+    packets: VecDeque<Bytes>,
+    closed: bool,
+}
+
+impl PacketQueue {
+    pub fn new(config: &QueueConfig) -> Self {
+        let capacity = config.size.max(1);
+
+        Self {
+            state: Mutex::new(QueueState {
+                packets: VecDeque::with_capacity(capacity),
+                closed: false,
+            }),
+            capacity,
+            policy: config.policy,
+            items: Notify::new(),
+            space: Notify::new(),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue a packet for the client, returning `false` if it was dropped
+    pub async fn push(&self, packet: Bytes) -> bool {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+
+                if state.closed {
+                    return false;
+                }
+
+                if state.packets.len() < self.capacity {
+                    state.packets.push_back(packet);
+                    self.items.notify_one();
+                    return true;
+                }
+
+                match self.policy {
+                    QueuePolicy::DropNewest => {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                        return false;
+                    }
+                    QueuePolicy::DropOldest => {
+                        state.packets.pop_front();
+                        state.packets.push_back(packet);
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                        self.items.notify_one();
+                        return true;
+                    }
+                    QueuePolicy::Block => {}
+                }
+            }
+
+            self.space.notified().await;
+        }
+    }
+
+    /// Wait for the next packet, or `None` once the queue is closed and drained
+    pub async fn pop(&self) -> Option<Bytes> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+
+                if let Some(packet) = state.packets.pop_front() {
+                    self.space.notify_one();
+                    return Some(packet);
+                }
+
+                if state.closed {
+                    return None;
+                }
+            }
+
+            self.items.notified().await;
+        }
+    }
+
+    /// Stop accepting packets and wake up any waiting producer or consumer
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.items.notify_one();
+        self.space.notify_one();
+    }
+
+    /// Number of packets discarded because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::config::ServerConfig;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn queue(size: usize, policy: QueuePolicy) -> PacketQueue {
+        PacketQueue::new(&QueueConfig { size, policy })
+    }
+
+    fn packet(byte: u8) -> Bytes {
+        Bytes::from(vec![byte])
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_queued_packets() {
+        let queue = queue(2, QueuePolicy::DropNewest);
+        assert!(queue.push(packet(1)).await);
+        assert!(queue.push(packet(2)).await);
+        assert!(!queue.push(packet(3)).await);
+
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.pop().await, Some(packet(1)));
+        assert_eq!(queue.pop().await, Some(packet(2)));
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_freshest_packets() {
+        let queue = queue(2, QueuePolicy::DropOldest);
+        for byte in 1..=3 {
+            assert!(queue.push(packet(byte)).await);
+        }
+
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.pop().await, Some(packet(2)));
+        assert_eq!(queue.pop().await, Some(packet(3)));
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_space() {
+        let queue = Arc::new(queue(1, QueuePolicy::Block));
+        assert!(queue.push(packet(1)).await);
+
+        let producer = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.push(packet(2)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!producer.is_finished());
+
+        assert_eq!(queue.pop().await, Some(packet(1)));
+        assert!(producer.await.unwrap());
+        assert_eq!(queue.pop().await, Some(packet(2)));
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn close_releases_blocked_producer_and_drains() {
+        let queue = Arc::new(queue(1, QueuePolicy::Block));
+        assert!(queue.push(packet(1)).await);
+
+        let producer = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.push(packet(2)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        queue.close();
+
+        assert!(!producer.await.unwrap());
+        assert_eq!(queue.pop().await, Some(packet(1)));
+        assert_eq!(queue.pop().await, None);
+    }
+
+    #[test]
+    fn users_get_their_override() {
+        let config: ServerConfig = serde_json::from_value(serde_json::json!({
+            "listen_addr": "0.0.0.0:4433",
+            "cert_path": "server.crt",
+            "key_path": "server.key",
+            "vpn_network": "10.10.0.1",
+            "vpn_netmask": "255.255.255.0",
+            "mtu": 1400,
+            "log_level": "info",
+            "user_db_path": "users.json",
+            "max_clients": 10,
+            "gaming_optimization": true,
+            "client_queue": { "size": 64, "policy": "drop_newest" },
+            "client_queue_overrides": { "gamer": { "size": 8, "policy": "block" } },
+        }))
+        .unwrap();
+
+        assert_eq!(config.queue_config_for("gamer"), &QueueConfig { size: 8, policy: QueuePolicy::Block });
+        assert_eq!(config.queue_config_for("other"), &QueueConfig { size: 64, policy: QueuePolicy::DropNewest });
+    }
+}
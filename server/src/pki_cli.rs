@@ -0,0 +1,455 @@
+use anyhow::{anyhow, bail, Result};
+use clap::Subcommand;
+use common::config::{ClientConfig, ReconnectConfig, ServerConfig, TunnelMode};
+use common::crypto::{self, CertificateAuthority, IssuedCertificate};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::revocation::RevocationList;
+use crate::storage;
+use crate::user_cli;
+use crate::user_db::UserDatabase;
+
+/// Manage the private CA behind client certificate authentication
+#[derive(Subcommand, Debug)]
+pub enum PkiCommand {
+    /// Create the certificate authority
+    Init {
+        #[clap(long, default_value = "QUIC VPN CA")]
+        name: String,
+
+        #[clap(long, default_value_t = 3650)]
+        days: u32,
+    },
+
+    /// Issue the server's certificate, written to cert_path and key_path
+    Server {
+        /// DNS names and IP addresses clients connect to
+        #[clap(required = true)]
+        names: Vec<String>,
+
+        #[clap(long, default_value_t = 825)]
+        days: u32,
+    },
+
+    /// Issue a certificate for a user, revoking any earlier one
+    Client {
+        username: String,
+
+        #[clap(long, default_value_t = 365)]
+        days: u32,
+    },
+
+    /// Revoke a client certificate by serial number, or every certificate
+    /// of a user; server certificates are replaced with `pki server`
+    Revoke {
+        serial_or_username: String,
+    },
+
+    /// Show all issued certificates
+    List,
+
+    /// Write a user's certificate, key and a client config to a directory
+    Bundle {
+        username: String,
+
+        /// Address clients connect to
+        #[clap(long)]
+        server: SocketAddr,
+
+        /// Name to expect in the server certificate; defaults to the first
+        /// name of the current server certificate
+        #[clap(long)]
+        hostname: Option<String>,
+
+        #[clap(long)]
+        out: PathBuf,
+    },
+}
+
+/// Every certificate the CA issued, kept next to it
+#[derive(Default, Serialize, Deserialize)]
+struct Index {
+    certificates: Vec<IssuedRecord>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct IssuedRecord {
+    serial: String,
+    kind: CertificateKind,
+    /// Username, or the first name of a server
+    name: String,
+    /// All names of a server
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    names: Vec<String>,
+    /// Seconds since the Unix epoch
+    expires_at: u64,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    revoked: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CertificateKind {
+    Server,
+    Client,
+}
+
+/// Files of the CA in `pki_dir`
+struct Pki {
+    dir: PathBuf,
+    /// Revocation list the server reads
+    revocation_list_path: PathBuf,
+}
+
+impl Pki {
+    fn new(config: &ServerConfig) -> Self {
+        Self {
+            dir: config.pki_dir.clone(),
+            revocation_list_path: config.revocation_list_path(),
+        }
+    }
+
+    fn ca_cert_path(&self) -> PathBuf {
+        self.dir.join("ca.crt")
+    }
+
+    fn client_cert_path(&self, username: &str) -> PathBuf {
+        self.dir.join("clients").join(format!("{}.crt", username))
+    }
+
+    fn client_key_path(&self, username: &str) -> PathBuf {
+        self.dir.join("clients").join(format!("{}.key", username))
+    }
+
+    fn load_ca(&self) -> Result<CertificateAuthority> {
+        let cert = fs::read(self.ca_cert_path())
+            .map_err(|e| anyhow!("Failed to read CA from {}: {}, create one with `pki init`", self.dir.display(), e))?;
+        let key = fs::read(self.dir.join("ca.key"))?;
+
+        Ok(CertificateAuthority::load(&cert, &key)?)
+    }
+
+    fn load_index(&self) -> Result<Index> {
+        match fs::read_to_string(self.dir.join("certs.json")) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Index::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save_index(&self, index: &Index) -> Result<()> {
+        let content = serde_json::to_string_pretty(index)?;
+        storage::write_atomic(&self.dir.join("certs.json"), content.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Record a newly issued certificate in the index
+    fn record(&self, kind: CertificateKind, names: &[String], issued: &IssuedCertificate) -> Result<()> {
+        let mut index = self.load_index()?;
+        index.push(kind, names, issued);
+
+        self.save_index(&index)
+    }
+}
+
+impl Index {
+    fn push(&mut self, kind: CertificateKind, names: &[String], issued: &IssuedCertificate) {
+        self.certificates.push(IssuedRecord {
+            serial: issued.serial.clone(),
+            kind,
+            name: names[0].clone(),
+            names: match kind {
+                CertificateKind::Server => names.to_vec(),
+                CertificateKind::Client => Vec::new(),
+            },
+            expires_at: unix_secs(issued.not_after),
+            revoked: false,
+        });
+    }
+
+    /// Revoke the client certificate with serial `serial_or_username`, or
+    /// every client certificate of that user; returns how many were revoked
+    fn revoke_client(&mut self, revocations: &mut RevocationList, serial_or_username: &str) -> Result<usize> {
+        let serial_match = self.certificates.iter().find(|cert| cert.serial == serial_or_username);
+        if serial_match.is_some_and(|cert| cert.kind == CertificateKind::Server) {
+            bail!("{} is a server certificate; replace it with `pki server` instead", serial_or_username);
+        }
+        let by_serial = serial_match.is_some();
+
+        Ok(self.revoke_where(revocations, |cert| {
+            cert.kind == CertificateKind::Client
+                && if by_serial { cert.serial == serial_or_username } else { cert.name == serial_or_username }
+        }))
+    }
+
+    /// Revoke every unrevoked certificate `matches` picks, adding it to
+    /// `revocations`; returns how many were revoked
+    fn revoke_where<F>(&mut self, revocations: &mut RevocationList, matches: F) -> usize
+    where
+        F: Fn(&IssuedRecord) -> bool,
+    {
+        let mut revoked = 0;
+
+        for cert in &mut self.certificates {
+            if cert.revoked || !matches(cert) {
+                continue;
+            }
+
+            cert.revoked = true;
+            revocations.revoke(&cert.serial, &cert.name);
+            revoked += 1;
+            println!("Revoked certificate {} of {}", cert.serial, cert.name);
+        }
+
+        revoked
+    }
+}
+
+/// Run a PKI command against the CA in the server config's `pki_dir`
+pub async fn run(config: &ServerConfig, command: PkiCommand, reload: bool) -> Result<()> {
+    if reload && config.pid_file.is_none() {
+        bail!("--reload needs pid_file to be set in the server config");
+    }
+
+    let pki = Pki::new(config);
+
+    match command {
+        PkiCommand::Init { name, days } => {
+            if pki.ca_cert_path().exists() {
+                bail!("A CA already exists in {}", pki.dir.display());
+            }
+
+            let ca = CertificateAuthority::generate(&name, days)?;
+            fs::create_dir_all(&pki.dir)?;
+            storage::write_atomic(&pki.dir.join("ca.key"), &ca.key_der())?;
+            fs::write(pki.ca_cert_path(), ca.cert_der())?;
+
+            println!("Created CA {:?} in {}", name, pki.dir.display());
+            println!("Set client_auth.ca_path to {} to accept its client certificates", pki.ca_cert_path().display());
+        }
+        PkiCommand::Server { names, days } => {
+            let issued = pki.load_ca()?.issue_server(&names, days)?;
+            storage::write_atomic(&config.key_path, &issued.key_der)?;
+            fs::write(&config.cert_path, &issued.cert_der)?;
+            pki.record(CertificateKind::Server, &names, &issued)?;
+
+            println!(
+                "Issued server certificate {} for {}, written to {}",
+                issued.serial,
+                names.join(", "),
+                config.cert_path.display()
+            );
+            println!("Key fingerprint for server_fingerprint: {}", crypto::spki_fingerprint(&issued.cert_der)?);
+        }
+        PkiCommand::Client { username, days } => {
+            let user_db = UserDatabase::load(&config.user_db_path, &config.password_hashing).await?;
+            if !user_db.contains(&username) {
+                bail!("User {} does not exist", username);
+            }
+            if username.starts_with('.') || username.contains(['/', '\\']) {
+                bail!("User {} cannot be used as a file name", username);
+            }
+
+            let ca = pki.load_ca()?;
+            let mut index = pki.load_index()?;
+            let mut revocations = RevocationList::load(&pki.revocation_list_path)?;
+
+            // Write the new certificate first, so a failure here leaves the
+            // user with the one they have
+            let issued = ca.issue_client(&username, days)?;
+            fs::create_dir_all(pki.dir.join("clients"))?;
+            storage::write_atomic(&pki.client_key_path(&username), &issued.key_der)?;
+            fs::write(pki.client_cert_path(&username), &issued.cert_der)?;
+
+            // The new certificate replaces the earlier ones, which must stop working
+            let replaced = index.revoke_where(&mut revocations, |cert| {
+                cert.kind == CertificateKind::Client && cert.name == username
+            });
+            index.push(CertificateKind::Client, std::slice::from_ref(&username), &issued);
+            if replaced > 0 {
+                revocations.save(&pki.revocation_list_path)?;
+            }
+            pki.save_index(&index)?;
+
+            println!("Issued certificate {} for {}, valid for {} days", issued.serial, username, days);
+
+            if reload {
+                user_cli::notify_reload(config)?;
+            }
+        }
+        PkiCommand::Revoke { serial_or_username } => {
+            let mut index = pki.load_index()?;
+            let mut revocations = RevocationList::load(&pki.revocation_list_path)?;
+            let revoked = index.revoke_client(&mut revocations, &serial_or_username)?;
+
+            if revoked == 0 {
+                bail!("No unrevoked certificate matches {}", serial_or_username);
+            }
+
+            revocations.save(&pki.revocation_list_path)?;
+            pki.save_index(&index)?;
+
+            if reload {
+                user_cli::notify_reload(config)?;
+            }
+        }
+        PkiCommand::List => {
+            let index = pki.load_index()?;
+            if index.certificates.is_empty() {
+                println!("No certificates");
+            }
+
+            let now = unix_secs(SystemTime::now());
+            for cert in &index.certificates {
+                let kind = match cert.kind {
+                    CertificateKind::Server => "server",
+                    CertificateKind::Client => "client",
+                };
+                let status = if cert.revoked {
+                    "revoked".to_string()
+                } else if cert.expires_at <= now {
+                    "expired".to_string()
+                } else {
+                    format!("expires in {} days", (cert.expires_at - now) / 86_400)
+                };
+
+                println!("{:<18} {:<7} {:<24} {}", cert.serial, kind, cert.name, status);
+            }
+        }
+        PkiCommand::Bundle { username, server, hostname, out } => {
+            let index = pki.load_index()?;
+            let latest = |kind: CertificateKind, name: Option<&str>| {
+                index.certificates.iter()
+                    .rev()
+                    .find(|cert| cert.kind == kind && name.is_none_or(|name| cert.name == name))
+            };
+
+            match latest(CertificateKind::Client, Some(&username)) {
+                Some(cert) if cert.revoked => bail!("The certificate of {} is revoked, issue a new one first", username),
+                Some(_) => {}
+                None => bail!("{} has no certificate, issue one with `pki client {}`", username, username),
+            }
+
+            let server_hostname = match hostname {
+                Some(hostname) => hostname,
+                None => latest(CertificateKind::Server, None)
+                    .map(|cert| cert.name.clone())
+                    .ok_or_else(|| anyhow!("No server certificate issued yet, pass --hostname"))?,
+            };
+
+            fs::create_dir_all(&out)?;
+            fs::copy(pki.ca_cert_path(), out.join("ca.crt"))?;
+            fs::copy(pki.client_cert_path(&username), out.join("client.crt"))?;
+            storage::write_atomic(&out.join("client.key"), &fs::read(pki.client_key_path(&username))?)?;
+
+            // Paths are relative, so the bundle works wherever it is unpacked
+            // when the client runs from inside it
+            let client_config = ClientConfig {
+                server_addr: server,
+                server_hostname,
+                server_cert_path: Some("ca.crt".into()),
+                client_cert_path: Some("client.crt".into()),
+                client_key_path: Some("client.key".into()),
+                server_fingerprint: None,
+                known_servers_path: None,
+                insecure_skip_verify: false,
+                username: username.clone(),
+                password: String::new(),
+                log_level: "info".to_string(),
+                interface_name: None,
+                gaming_optimization: true,
+                game_type: Some("default".to_string()),
+                control_socket: None,
+                reconnect: ReconnectConfig::default(),
+                tunnel_mode: TunnelMode::default(),
+                stats_interval_secs: 30,
+            };
+            client_config.save(&out.join("config.json").to_string_lossy())?;
+
+            println!("Wrote client bundle for {} to {}", username, out.display());
+        }
+    }
+
+    Ok(())
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(serial: &str, kind: CertificateKind, name: &str) -> IssuedRecord {
+        IssuedRecord {
+            serial: serial.to_string(),
+            kind,
+            name: name.to_string(),
+            names: Vec::new(),
+            expires_at: 0,
+            revoked: false,
+        }
+    }
+
+    #[test]
+    fn replacing_a_client_certificate_revokes_the_earlier_ones() {
+        let mut index = Index {
+            certificates: vec![
+                record("01", CertificateKind::Client, "alice"),
+                record("02", CertificateKind::Server, "alice"),
+                record("03", CertificateKind::Client, "bob"),
+                record("04", CertificateKind::Client, "alice"),
+            ],
+        };
+        let mut revocations = RevocationList::default();
+
+        let revoked = index.revoke_where(&mut revocations, |cert| {
+            cert.kind == CertificateKind::Client && cert.name == "alice"
+        });
+        assert_eq!(revoked, 2);
+        for serial in ["01", "04"] {
+            assert!(revocations.is_revoked(serial));
+        }
+        for serial in ["02", "03"] {
+            assert!(!revocations.is_revoked(serial));
+        }
+
+        // Certificates already revoked are not counted again
+        let revoked = index.revoke_where(&mut revocations, |cert| cert.name == "alice");
+        assert_eq!(revoked, 1);
+        assert!(index.certificates.iter().filter(|cert| cert.name == "alice").all(|cert| cert.revoked));
+    }
+
+    #[test]
+    fn revoke_takes_client_serials_or_usernames() {
+        let mut index = Index {
+            certificates: vec![
+                record("01", CertificateKind::Server, "vpn.example.com"),
+                record("02", CertificateKind::Client, "alice"),
+                record("03", CertificateKind::Client, "alice"),
+                record("04", CertificateKind::Client, "bob"),
+            ],
+        };
+        let mut revocations = RevocationList::default();
+
+        assert!(index.revoke_client(&mut revocations, "01").is_err());
+        assert!(!revocations.is_revoked("01"));
+
+        assert_eq!(index.revoke_client(&mut revocations, "02").unwrap(), 1);
+        assert!(revocations.is_revoked("02"));
+        assert!(!revocations.is_revoked("03"));
+
+        assert_eq!(index.revoke_client(&mut revocations, "alice").unwrap(), 1);
+        assert!(revocations.is_revoked("03"));
+
+        // Server names are not usernames
+        assert_eq!(index.revoke_client(&mut revocations, "vpn.example.com").unwrap(), 0);
+        assert!(!revocations.is_revoked("04"));
+    }
+}
@@ -0,0 +1,328 @@
+use anyhow::Result;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+use crate::storage;
+use crate::user_db::UserLimits;
+
+/// Data each user moved in the current day and month, persisted so quotas
+/// survive server restarts
+#[derive(Clone)]
+pub struct QuotaStore {
+    path: PathBuf,
+    users: Arc<DashMap<String, Arc<UsageCounter>>>,
+}
+
+/// Usage of one user, shared by all of their sessions
+#[derive(Default)]
+pub struct UsageCounter {
+    state: Mutex<CounterState>,
+}
+
+#[derive(Default)]
+struct CounterState {
+    usage: Usage,
+    /// Quotas checked as traffic is recorded
+    limits: UserLimits,
+}
+
+/// Bytes in both directions within the current periods. Periods are UTC
+/// dates written as numbers, e.g. day `20240131` and month `202401`.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub day: u32,
+    pub daily_bytes: u64,
+    pub month: u32,
+    pub monthly_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UsageFile {
+    users: BTreeMap<String, Usage>,
+}
+
+impl QuotaStore {
+    /// Load usage from `path`, starting empty if the file does not exist yet
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let users = match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str::<UsageFile>(&content)?.users,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let store = Self {
+            path: path.to_path_buf(),
+            users: Arc::new(DashMap::new()),
+        };
+        for (username, usage) in users {
+            let counter = UsageCounter {
+                state: Mutex::new(CounterState { usage, limits: UserLimits::default() }),
+            };
+            store.users.insert(username, Arc::new(counter));
+        }
+        store.roll();
+
+        Ok(store)
+    }
+
+    /// Counter a user's sessions add their traffic to
+    pub fn usage(&self, username: &str) -> Arc<UsageCounter> {
+        self.users.entry(username.to_string())
+            .or_insert_with(|| {
+                let counter = UsageCounter::default();
+                counter.roll(current_periods());
+                Arc::new(counter)
+            })
+            .clone()
+    }
+
+    /// Start new periods for every user once the day or month is over
+    pub fn roll(&self) {
+        let periods = current_periods();
+
+        for counter in self.users.iter() {
+            counter.roll(periods);
+        }
+    }
+
+    /// Write all usage to disk
+    pub fn save(&self) -> Result<()> {
+        let users = self.users.iter()
+            .map(|entry| (entry.key().clone(), entry.value().snapshot()))
+            .collect();
+        let content = serde_json::to_string_pretty(&UsageFile { users })?;
+
+        storage::write_atomic(&self.path, content.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// [`QuotaStore::save`] on the blocking pool, so the file write does not
+    /// stall the runtime; failures are logged
+    pub async fn save_in_background(&self) {
+        let store = self.clone();
+        match tokio::task::spawn_blocking(move || store.save()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Failed to save quota usage: {}", e),
+            Err(e) => error!("Failed to save quota usage: {}", e),
+        }
+    }
+}
+
+impl UsageCounter {
+    /// Add traffic, returning why the user must stop if this used up one
+    /// of the quotas set with [`UsageCounter::set_limits`]
+    pub fn record(&self, bytes: usize) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.usage;
+        state.usage.daily_bytes += bytes as u64;
+        state.usage.monthly_bytes += bytes as u64;
+
+        // Only report crossing a quota, not every packet after it
+        match exhausted(&before, &state.limits) {
+            Some(_) => None,
+            None => exhausted(&state.usage, &state.limits),
+        }
+    }
+
+    /// Quotas `record` checks the traffic against
+    pub fn set_limits(&self, limits: &UserLimits) {
+        self.state.lock().unwrap().limits = *limits;
+    }
+
+    pub fn snapshot(&self) -> Usage {
+        self.state.lock().unwrap().usage
+    }
+
+    /// Why the user may not move more data, if a quota is used up
+    pub fn exhausted(&self, limits: &UserLimits) -> Option<String> {
+        exhausted(&self.snapshot(), limits)
+    }
+
+    fn roll(&self, (day, month): (u32, u32)) {
+        let usage = &mut self.state.lock().unwrap().usage;
+
+        if usage.day != day {
+            usage.day = day;
+            usage.daily_bytes = 0;
+        }
+        if usage.month != month {
+            usage.month = month;
+            usage.monthly_bytes = 0;
+        }
+    }
+}
+
+fn exhausted(usage: &Usage, limits: &UserLimits) -> Option<String> {
+    if let Some(quota) = limits.daily_quota {
+        if usage.daily_bytes >= quota {
+            return Some(format!("Daily data quota of {} used up", format_bytes(quota)));
+        }
+    }
+    if let Some(quota) = limits.monthly_quota {
+        if usage.monthly_bytes >= quota {
+            return Some(format!("Monthly data quota of {} used up", format_bytes(quota)));
+        }
+    }
+
+    None
+}
+
+/// Byte count with a binary unit, e.g. `1.5 GiB`
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Current UTC day and month, as `Usage` stores them
+fn current_periods() -> (u32, u32) {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+    periods_at(secs)
+}
+
+/// UTC day and month `secs` after the Unix epoch
+fn periods_at(secs: u64) -> (u32, u32) {
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+
+    let month = year as u32 * 100 + month;
+    (month * 100 + day, month)
+}
+
+/// Gregorian date of a day counted from 1970-01-01, after Howard Hinnant's
+/// `civil_from_days`
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::{Date, Month, PrimitiveDateTime, Time};
+
+    /// Seconds since the epoch at the given UTC date and time
+    fn at(year: i32, month: Month, day: u8, hms: (u8, u8, u8)) -> u64 {
+        let date = Date::from_calendar_date(year, month, day).unwrap();
+        let time = Time::from_hms(hms.0, hms.1, hms.2).unwrap();
+        PrimitiveDateTime::new(date, time).assume_utc().unix_timestamp() as u64
+    }
+
+    fn limits(daily_quota: Option<u64>, monthly_quota: Option<u64>) -> UserLimits {
+        UserLimits { daily_quota, monthly_quota, ..UserLimits::default() }
+    }
+
+    #[test]
+    fn civil_from_days_matches_the_calendar() {
+        let epoch = Date::from_calendar_date(1970, Month::January, 1).unwrap();
+
+        // Every day from 1900 to 2200 covers the century leap year rules
+        for days in -25_567..84_000 {
+            let date = epoch + time::Duration::days(days);
+            let expected = (date.year() as i64, date.month() as u32, date.day() as u32);
+            assert_eq!(civil_from_days(days), expected, "day {}", days);
+        }
+    }
+
+    #[test]
+    fn periods_follow_leap_days() {
+        assert_eq!(periods_at(0), (19700101, 197001));
+        assert_eq!(periods_at(at(2024, Month::February, 28, (23, 59, 59)) + 1), (20240229, 202402));
+        assert_eq!(periods_at(at(2024, Month::February, 29, (23, 59, 59)) + 1), (20240301, 202403));
+        assert_eq!(periods_at(at(2023, Month::February, 28, (23, 59, 59)) + 1), (20230301, 202303));
+        assert_eq!(periods_at(at(2100, Month::February, 28, (23, 59, 59)) + 1), (21000301, 210003));
+        assert_eq!(periods_at(at(2000, Month::February, 28, (23, 59, 59)) + 1), (20000229, 200002));
+    }
+
+    #[test]
+    fn day_rollover_keeps_the_month() {
+        let counter = UsageCounter::default();
+        counter.roll(periods_at(at(2024, Month::February, 28, (23, 59, 59))));
+        counter.record(1000);
+
+        counter.roll(periods_at(at(2024, Month::February, 29, (0, 0, 0))));
+        let usage = counter.snapshot();
+        assert_eq!((usage.day, usage.daily_bytes), (20240229, 0));
+        assert_eq!((usage.month, usage.monthly_bytes), (202402, 1000));
+    }
+
+    #[test]
+    fn month_rollover_resets_both() {
+        let counter = UsageCounter::default();
+        counter.roll(periods_at(at(2024, Month::December, 31, (23, 59, 59))));
+        counter.record(1000);
+
+        counter.roll(periods_at(at(2025, Month::January, 1, (0, 0, 0))));
+        let usage = counter.snapshot();
+        assert_eq!((usage.day, usage.daily_bytes), (20250101, 0));
+        assert_eq!((usage.month, usage.monthly_bytes), (202501, 0));
+    }
+
+    #[test]
+    fn rolling_within_a_day_keeps_the_usage() {
+        let counter = UsageCounter::default();
+        counter.roll(periods_at(at(2024, Month::March, 1, (0, 0, 0))));
+        counter.record(1000);
+
+        counter.roll(periods_at(at(2024, Month::March, 1, (23, 59, 59))));
+        assert_eq!(counter.snapshot().daily_bytes, 1000);
+    }
+
+    #[test]
+    fn recording_reports_crossing_a_quota_once() {
+        let counter = UsageCounter::default();
+        assert_eq!(counter.record(5000), None);
+
+        counter.set_limits(&limits(Some(6000), None));
+        assert_eq!(counter.record(999), None);
+        let reason = counter.record(1).unwrap();
+        assert!(reason.starts_with("Daily"), "{}", reason);
+        assert_eq!(counter.record(1), None);
+
+        // A new day starts the count again
+        counter.roll((20240102, 202401));
+        assert_eq!(counter.record(5999), None);
+        assert!(counter.record(1).is_some());
+    }
+
+    #[test]
+    fn quotas_are_exhausted_once_reached() {
+        let counter = UsageCounter::default();
+        counter.record(999);
+        assert_eq!(counter.exhausted(&limits(Some(1000), Some(2000))), None);
+        assert_eq!(counter.exhausted(&UserLimits::default()), None);
+
+        counter.record(1);
+        let reason = counter.exhausted(&limits(Some(1000), Some(2000))).unwrap();
+        assert!(reason.starts_with("Daily"), "{}", reason);
+
+        let reason = counter.exhausted(&limits(None, Some(1000))).unwrap();
+        assert!(reason.starts_with("Monthly"), "{}", reason);
+    }
+}
@@ -0,0 +1,218 @@
+use std::sync::Mutex;
+use std::time::Duration;
+// Tokio's clock, so tests can move time forward
+use tokio::time::Instant;
+
+use crate::user_db::UserLimits;
+
+/// Traffic a bucket lets through at once after being idle, in time at its
+/// rate; keeps short bursts like game state updates from being delayed
+const BURST: Duration = Duration::from_millis(250);
+
+/// Smallest burst, so low rates still pass a few full-size packets
+const MIN_BURST_BYTES: f64 = 64.0 * 1024.0;
+
+/// Token bucket shaping traffic to a byte rate.
+///
+/// Packets are never dropped here: senders wait until the bucket has
+/// refilled, and the queues in front of them decide what to drop.
+pub struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    /// Bytes per second, `None` when unlimited
+    rate: Option<u64>,
+    /// Bytes that may pass right now; negative while senders are waiting
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            state: Mutex::new(BucketState {
+                rate,
+                tokens: rate.map_or(0.0, burst_for),
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        if state.rate == rate {
+            return;
+        }
+
+        state.refill();
+        let burst = rate.map_or(0.0, burst_for);
+        state.tokens = match state.rate {
+            // A bucket that was unlimited starts out full
+            None => burst,
+            Some(_) => state.tokens.min(burst),
+        };
+        state.rate = rate;
+    }
+
+    /// Take `bytes` from the bucket, waiting until they are covered
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = self.take(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take `bytes` from the bucket, returning how long until they are covered
+    fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let rate = match state.rate {
+            Some(rate) if rate > 0 => rate,
+            _ => return Duration::ZERO,
+        };
+
+        state.refill();
+        state.tokens -= bytes as f64;
+
+        if state.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-state.tokens / rate as f64)
+    }
+}
+
+impl BucketState {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.updated = now;
+
+        if let Some(rate) = self.rate {
+            self.tokens = (self.tokens + elapsed * rate as f64).min(burst_for(rate));
+        }
+    }
+}
+
+fn burst_for(rate: u64) -> f64 {
+    (rate as f64 * BURST.as_secs_f64()).max(MIN_BURST_BYTES)
+}
+
+/// Upload and download buckets shared by all sessions of one user
+pub struct RateLimiter {
+    /// Traffic from the client into the tunnel
+    pub upload: TokenBucket,
+    /// Traffic from the tunnel to the client
+    pub download: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &UserLimits) -> Self {
+        Self {
+            upload: TokenBucket::new(limits.upload_rate),
+            download: TokenBucket::new(limits.download_rate),
+        }
+    }
+
+    /// Pick up changed limits, e.g. after the user database was reloaded
+    pub fn update(&self, limits: &UserLimits) {
+        self.upload.set_rate(limits.upload_rate);
+        self.download.set_rate(limits.download_rate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::advance;
+
+    const RATE: u64 = 1024 * 1024;
+
+    fn tokens(bucket: &TokenBucket) -> f64 {
+        bucket.state.lock().unwrap().tokens
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < expected * 0.01 + 1.0, "{} is not about {}", actual, expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn starts_with_a_full_burst() {
+        let bucket = TokenBucket::new(Some(RATE));
+        let burst = burst_for(RATE);
+        assert_eq!(burst, RATE as f64 / 4.0);
+
+        let start = Instant::now();
+        bucket.acquire(burst as usize).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // Beyond the burst, senders wait for the bytes at the rate
+        bucket.acquire(RATE as usize / 10).await;
+        assert_close(start.elapsed().as_secs_f64(), 0.1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn low_rates_get_the_minimum_burst() {
+        assert_eq!(burst_for(1000), MIN_BURST_BYTES);
+
+        let bucket = TokenBucket::new(Some(1000));
+        assert_eq!(bucket.take(MIN_BURST_BYTES as usize), Duration::ZERO);
+        assert_close(bucket.take(500).as_secs_f64(), 0.5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refills_at_the_rate_up_to_the_burst() {
+        let bucket = TokenBucket::new(Some(RATE));
+        bucket.take(burst_for(RATE) as usize);
+
+        advance(Duration::from_millis(100)).await;
+        assert_eq!(bucket.take(0), Duration::ZERO);
+        assert_close(tokens(&bucket), RATE as f64 / 10.0);
+
+        advance(Duration::from_secs(10)).await;
+        bucket.take(0);
+        assert_eq!(tokens(&bucket), burst_for(RATE));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_senders_are_paid_back_first() {
+        let bucket = TokenBucket::new(Some(RATE));
+        bucket.take(burst_for(RATE) as usize + RATE as usize / 10);
+
+        // The debt is repaid before new bytes pass without waiting
+        advance(Duration::from_millis(50)).await;
+        assert_close(bucket.take(0).as_secs_f64(), 0.05);
+
+        advance(Duration::from_millis(50)).await;
+        assert_eq!(bucket.take(0), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unlimited_and_zero_rates_never_wait() {
+        let start = Instant::now();
+        for bucket in [TokenBucket::new(None), TokenBucket::new(Some(0))] {
+            assert_eq!(bucket.take(usize::MAX), Duration::ZERO);
+            bucket.acquire(usize::MAX).await;
+            bucket.acquire(1).await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_changes_keep_the_bucket_within_the_new_burst() {
+        let bucket = TokenBucket::new(None);
+        bucket.set_rate(Some(RATE));
+        assert_eq!(tokens(&bucket), burst_for(RATE));
+
+        bucket.set_rate(Some(RATE / 8));
+        assert_eq!(tokens(&bucket), burst_for(RATE / 8));
+
+        // Time spent under the old rate is credited at the old rate
+        bucket.take(burst_for(RATE / 8) as usize);
+        advance(Duration::from_millis(100)).await;
+        bucket.set_rate(Some(RATE));
+        assert_close(tokens(&bucket), RATE as f64 / 80.0);
+
+        bucket.set_rate(None);
+        assert_eq!(bucket.take(usize::MAX), Duration::ZERO);
+    }
+}
@@ -0,0 +1,251 @@
+use anyhow::{anyhow, Result};
+use common::config::ServerConfig;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::{error, info, warn};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::certificate::ServerCertificate;
+use crate::client_manager::ClientManager;
+use crate::ip_allocator::IpAllocator;
+use crate::user_db::UserDatabase;
+
+/// Handle to swap the log filter installed at startup
+pub type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Re-reads the user database and the reloadable parts of the server
+/// config, on SIGHUP or when asked through the admin API.
+///
+/// Limits, routes, the log level and the server certificate take effect
+/// without dropping anyone; sessions of users who were removed or disabled,
+/// or whose certificate was revoked, are ended.
+#[derive(Clone)]
+pub struct Reloader {
+    config_path: PathBuf,
+    user_db_path: PathBuf,
+    user_db: UserDatabase,
+    allocators: Vec<IpAllocator>,
+    client_manager: ClientManager,
+    certificate: ServerCertificate,
+    /// Unset when `RUST_LOG` overrides the configured log level
+    log_filter: Option<LogFilterHandle>,
+    /// Keeps a SIGHUP and an admin request from reloading at the same time
+    lock: Arc<AsyncMutex<()>>,
+}
+
+/// What a reload picked up
+pub struct ReloadSummary {
+    pub users: usize,
+    /// Sessions ended because their user was removed or disabled, or their
+    /// certificate revoked
+    pub disconnected: usize,
+}
+
+impl Reloader {
+    pub fn new(
+        config_path: PathBuf,
+        config: &ServerConfig,
+        user_db: UserDatabase,
+        allocators: Vec<IpAllocator>,
+        client_manager: ClientManager,
+        certificate: ServerCertificate,
+        log_filter: Option<LogFilterHandle>,
+    ) -> Self {
+        Self {
+            config_path,
+            user_db_path: config.user_db_path.clone(),
+            user_db,
+            allocators,
+            client_manager,
+            certificate,
+            log_filter,
+            lock: Arc::new(AsyncMutex::new(())),
+        }
+    }
+
+    pub async fn reload(&self) -> Result<ReloadSummary> {
+        let _guard = self.lock.lock().await;
+
+        // Check the config first so a broken file changes nothing
+        let mut config = ServerConfig::load(&self.config_path.to_string_lossy())
+            .map_err(|e| anyhow!("Failed to reload {}: {}", self.config_path.display(), e))?;
+        let log_filter = match &self.log_filter {
+            Some(_) => Some(
+                EnvFilter::try_new(&config.log_level)
+                    .map_err(|e| anyhow!("Invalid log_level {:?}: {}", config.log_level, e))?,
+            ),
+            None => None,
+        };
+        let certificate = ServerCertificate::read(&config.cert_path, &config.key_path)
+            .map_err(|e| anyhow!("Failed to reload the server certificate: {}", e))?;
+
+        let current = self.client_manager.config();
+        let ignored = keep_startup_settings(&current, &mut config);
+        if !ignored.is_empty() {
+            warn!("Changes to {} take effect after a restart", ignored.join(", "));
+        }
+
+        self.user_db.reload(&self.user_db_path).await
+            .map_err(|e| anyhow!("Failed to reload users: {}", e))?;
+
+        let reservations = self.user_db.reservations();
+        for allocator in &self.allocators {
+            allocator.set_reservations(reservations.clone());
+        }
+
+        if let (Some(handle), Some(filter)) = (&self.log_filter, log_filter) {
+            if config.log_level != current.log_level {
+                info!("Log level changed to {}", config.log_level);
+            }
+            handle.reload(filter)
+                .map_err(|e| anyhow!("Failed to change log level: {}", e))?;
+        }
+
+        self.certificate.install(certificate);
+        self.certificate.set_config(config.cert_rotation.clone());
+
+        self.client_manager.load_revocations()?;
+        self.client_manager.apply_config(config);
+        let disconnected = self.client_manager.disconnect_inactive_users()
+            + self.client_manager.disconnect_revoked();
+
+        Ok(ReloadSummary {
+            users: self.user_db.users().len(),
+            disconnected,
+        })
+    }
+
+    /// Reload whenever the process receives SIGHUP, e.g. after `user`
+    /// subcommands with --reload
+    pub fn spawn_signal_handler(&self) -> Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let reloader = self.clone();
+
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading users, configuration and certificate");
+
+                match reloader.reload().await {
+                    Ok(summary) => info!(
+                        "Reloaded {} users, disconnected {} sessions that lost access",
+                        summary.users, summary.disconnected
+                    ),
+                    Err(e) => error!("{}", e),
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+/// Put back the settings that are only read at startup, so the running
+/// server keeps describing itself as it is; returns those that changed
+fn keep_startup_settings(current: &ServerConfig, new: &mut ServerConfig) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+
+    macro_rules! keep {
+        ($($field:ident),+) => {
+            $(
+                if new.$field != current.$field {
+                    changed.push(stringify!($field));
+                    new.$field = current.$field.clone();
+                }
+            )+
+        };
+    }
+
+    keep!(
+        listen_addr,
+        vpn_network,
+        vpn_netmask,
+        vpn_network_v6,
+        vpn_prefix_len_v6,
+        mtu,
+        user_db_path,
+        lease_db_path,
+        quota_db_path,
+        max_clients,
+        gaming_optimization,
+        pid_file,
+        password_hashing,
+        metrics_listen_addr,
+        client_auth,
+        admin
+    );
+
+    // The handshake cap sizes a semaphore created at startup
+    if new.auth_protection.max_concurrent_handshakes != current.auth_protection.max_concurrent_handshakes {
+        changed.push("auth_protection.max_concurrent_handshakes");
+        new.auth_protection.max_concurrent_handshakes = current.auth_protection.max_concurrent_handshakes;
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::config::SessionLimitPolicy;
+
+    fn config(overrides: serde_json::Value) -> ServerConfig {
+        let mut config = serde_json::json!({
+            "listen_addr": "0.0.0.0:4433",
+            "cert_path": "server.crt",
+            "key_path": "server.key",
+            "vpn_network": "10.10.0.1",
+            "vpn_netmask": "255.255.255.0",
+            "mtu": 1400,
+            "log_level": "info",
+            "user_db_path": "users.json",
+            "max_clients": 10,
+            "gaming_optimization": true,
+        });
+        config.as_object_mut().unwrap().extend(overrides.as_object().unwrap().clone());
+
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[test]
+    fn startup_settings_stay_while_limits_change() {
+        let current = config(serde_json::json!({}));
+        let mut new = config(serde_json::json!({
+            "listen_addr": "0.0.0.0:5544",
+            "vpn_network": "10.20.0.1",
+            "max_clients": 50,
+            "log_level": "debug",
+            "client_queue": { "size": 32, "policy": "drop_oldest" },
+            "session_limit": { "max_per_user": 2, "policy": "evict_oldest" },
+            "auth_protection": { "max_failures": 3, "ban_secs": 60, "max_concurrent_handshakes": 8 },
+        }));
+
+        let ignored = keep_startup_settings(&current, &mut new);
+        assert_eq!(
+            ignored,
+            ["listen_addr", "vpn_network", "max_clients", "auth_protection.max_concurrent_handshakes"]
+        );
+
+        assert_eq!(new.listen_addr, current.listen_addr);
+        assert_eq!(new.vpn_network, current.vpn_network);
+        assert_eq!(new.max_clients, 10);
+        assert_eq!(new.auth_protection.max_concurrent_handshakes, current.auth_protection.max_concurrent_handshakes);
+
+        // Everything else is taken as reloaded
+        assert_eq!(new.log_level, "debug");
+        assert_eq!(new.client_queue.size, 32);
+        assert_eq!(new.session_limit.max_per_user, 2);
+        assert_eq!(new.session_limit.policy, SessionLimitPolicy::EvictOldest);
+        assert_eq!(new.auth_protection.max_failures, 3);
+        assert_eq!(new.auth_protection.ban_secs, 60);
+    }
+
+    #[test]
+    fn unchanged_config_reports_nothing() {
+        let current = config(serde_json::json!({}));
+        let mut new = current.clone();
+
+        assert!(keep_startup_settings(&current, &mut new).is_empty());
+    }
+}
@@ -0,0 +1,61 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::storage;
+
+/// Client certificates that may no longer log in, keyed by serial number.
+///
+/// Written by `pki revoke` and checked by the server on every handshake;
+/// the file can be copied to other servers trusting the same CA.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RevocationList {
+    revoked: BTreeMap<String, RevokedCertificate>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevokedCertificate {
+    /// User the certificate was issued to
+    pub name: String,
+    /// Seconds since the Unix epoch
+    pub revoked_at: u64,
+}
+
+impl RevocationList {
+    /// Load the list from `path`, starting empty if the file does not exist yet
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        storage::write_atomic(path.as_ref(), content.as_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn is_revoked(&self, serial: &str) -> bool {
+        self.revoked.contains_key(serial)
+    }
+
+    /// Add a certificate, returning `false` if it was already revoked
+    pub fn revoke(&mut self, serial: &str, name: &str) -> bool {
+        if self.is_revoked(serial) {
+            return false;
+        }
+
+        let revoked_at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        self.revoked.insert(serial.to_string(), RevokedCertificate {
+            name: name.to_string(),
+            revoked_at,
+        });
+
+        true
+    }
+}
@@ -0,0 +1,215 @@
+use common::config::{SessionLimitConfig, SessionLimitPolicy};
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Sessions of each user, counted against the per-user session limit.
+///
+/// A login takes its place when it is admitted and fills in its address
+/// once the session is registered, so concurrent logins of one user cannot
+/// both pass the limit.
+#[derive(Clone, Default)]
+pub struct UserSessions {
+    /// Sessions of each user, oldest first; sessions being evicted are
+    /// already left out
+    users: Arc<DashMap<String, Vec<Session>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+struct Session {
+    id: u64,
+    /// Key in the client table, `None` while the login is being set up
+    ip: Option<IpAddr>,
+}
+
+/// A place under a user's session limit. Dropping it before
+/// [`Reservation::activate`] gives the place back.
+pub struct Reservation {
+    sessions: UserSessions,
+    username: String,
+    id: u64,
+}
+
+impl UserSessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserve a place for a new session of `username`, evicting older
+    /// sessions if the policy says so. Returns the reservation and the
+    /// addresses of the sessions to disconnect, or `None` if the login
+    /// must be refused.
+    pub fn admit(&self, username: &str, limit: &SessionLimitConfig) -> Option<(Reservation, Vec<IpAddr>)> {
+        let mut sessions = self.users.entry(username.to_string()).or_default();
+        let mut evicted = Vec::new();
+
+        if limit.max_per_user != 0 && sessions.len() >= limit.max_per_user {
+            match limit.policy {
+                SessionLimitPolicy::RejectNew => return None,
+                SessionLimitPolicy::EvictOldest => {
+                    // Logins still being set up have nothing to disconnect
+                    let mut excess = sessions.len() + 1 - limit.max_per_user;
+                    if sessions.iter().filter(|session| session.ip.is_some()).count() < excess {
+                        return None;
+                    }
+
+                    sessions.retain(|session| match session.ip {
+                        Some(ip) if excess > 0 => {
+                            excess -= 1;
+                            evicted.push(ip);
+                            false
+                        }
+                        _ => true,
+                    });
+                }
+            }
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        sessions.push(Session { id, ip: None });
+        drop(sessions);
+
+        let reservation = Reservation {
+            sessions: self.clone(),
+            username: username.to_string(),
+            id,
+        };
+        Some((reservation, evicted))
+    }
+
+    /// Count a resumed session, which keeps the place it had before
+    pub fn insert(&self, username: &str, ip: IpAddr) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.users.entry(username.to_string()).or_default().push(Session { id, ip: Some(ip) });
+    }
+
+    /// Stop counting a session towards its user's session limit
+    pub fn forget(&self, username: &str, ip: IpAddr) {
+        self.users.remove_if_mut(username, |_, sessions| {
+            sessions.retain(|session| session.ip != Some(ip));
+            sessions.is_empty()
+        });
+    }
+}
+
+impl Reservation {
+    /// Count the registered session under its address from now on
+    pub fn activate(self, ip: IpAddr) {
+        let mut sessions = self.sessions.users.entry(self.username.clone()).or_default();
+        match sessions.iter_mut().find(|session| session.id == self.id) {
+            Some(session) => session.ip = Some(ip),
+            None => sessions.push(Session { id: self.id, ip: Some(ip) }),
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.sessions.users.remove_if_mut(&self.username, |_, sessions| {
+            sessions.retain(|session| session.id != self.id || session.ip.is_some());
+            sessions.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(max_per_user: usize, policy: SessionLimitPolicy) -> SessionLimitConfig {
+        SessionLimitConfig { max_per_user, policy }
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    fn count(sessions: &UserSessions, username: &str) -> usize {
+        sessions.users.get(username).map_or(0, |sessions| sessions.len())
+    }
+
+    #[test]
+    fn concurrent_logins_share_the_limit() {
+        let sessions = UserSessions::new();
+        let limit = limit(1, SessionLimitPolicy::RejectNew);
+
+        let (first, _) = sessions.admit("alice", &limit).unwrap();
+        assert!(sessions.admit("alice", &limit).is_none());
+        assert!(sessions.admit("bob", &limit).is_some());
+
+        first.activate(ip(2));
+        assert!(sessions.admit("alice", &limit).is_none());
+    }
+
+    #[test]
+    fn failed_logins_give_their_place_back() {
+        let sessions = UserSessions::new();
+        let limit = limit(1, SessionLimitPolicy::RejectNew);
+
+        let (reservation, _) = sessions.admit("alice", &limit).unwrap();
+        drop(reservation);
+        assert_eq!(count(&sessions, "alice"), 0);
+
+        let (reservation, _) = sessions.admit("alice", &limit).unwrap();
+        reservation.activate(ip(2));
+        assert_eq!(count(&sessions, "alice"), 1);
+    }
+
+    #[test]
+    fn evicts_the_oldest_established_session() {
+        let sessions = UserSessions::new();
+        let limit = limit(2, SessionLimitPolicy::EvictOldest);
+
+        let (oldest, _) = sessions.admit("alice", &limit).unwrap();
+        let (newer, _) = sessions.admit("alice", &limit).unwrap();
+        newer.activate(ip(3));
+        oldest.activate(ip(2));
+
+        let (third, evicted) = sessions.admit("alice", &limit).unwrap();
+        assert_eq!(evicted, vec![ip(2)]);
+        third.activate(ip(4));
+
+        let (_, evicted) = sessions.admit("alice", &limit).unwrap();
+        assert_eq!(evicted, vec![ip(3)]);
+    }
+
+    #[test]
+    fn logins_being_set_up_are_not_evicted() {
+        let sessions = UserSessions::new();
+        let limit = limit(2, SessionLimitPolicy::EvictOldest);
+
+        let (_pending, _) = sessions.admit("alice", &limit).unwrap();
+        let (established, _) = sessions.admit("alice", &limit).unwrap();
+        established.activate(ip(3));
+
+        let (_third, evicted) = sessions.admit("alice", &limit).unwrap();
+        assert_eq!(evicted, vec![ip(3)]);
+
+        // Only logins in progress are left, so there is no room to make
+        assert!(sessions.admit("alice", &limit).is_none());
+    }
+
+    #[test]
+    fn forgotten_and_resumed_sessions() {
+        let sessions = UserSessions::new();
+        let limit = limit(1, SessionLimitPolicy::RejectNew);
+
+        sessions.insert("alice", ip(2));
+        assert!(sessions.admit("alice", &limit).is_none());
+
+        sessions.forget("alice", ip(2));
+        assert_eq!(count(&sessions, "alice"), 0);
+        assert!(sessions.admit("alice", &limit).is_some());
+    }
+
+    #[test]
+    fn unlimited_admits_everyone() {
+        let sessions = UserSessions::new();
+        let limit = limit(0, SessionLimitPolicy::RejectNew);
+
+        let reservations: Vec<_> = (0..10).map(|_| sessions.admit("alice", &limit).unwrap()).collect();
+        assert!(reservations.iter().all(|(_, evicted)| evicted.is_empty()));
+        assert_eq!(count(&sessions, "alice"), 10);
+    }
+}
@@ -0,0 +1,27 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+pub use common::storage::write_atomic;
+
+/// Take an exclusive advisory lock on `<path>.lock`, waiting for any other
+/// holder. The lock is released when the returned file is dropped.
+pub fn lock_exclusive(path: &Path) -> io::Result<File> {
+    let mut lock_name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+    lock_name.push(".lock");
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path.with_file_name(lock_name))?;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(file)
+}
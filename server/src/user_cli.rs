@@ -0,0 +1,356 @@
+use anyhow::{anyhow, bail, Result};
+use clap::Subcommand;
+use common::config::ServerConfig;
+use std::net::{IpAddr, Ipv6Addr};
+use std::str::FromStr;
+
+use crate::quota::format_bytes;
+use crate::storage;
+use crate::user_db::{UserDatabase, UserLimits, UserRecord};
+
+/// Edit the user database of a server
+#[derive(Subcommand, Debug)]
+pub enum UserCommand {
+    /// Create a new user
+    Add {
+        username: String,
+
+        /// Password; prompted for when omitted
+        #[clap(long)]
+        password: Option<String>,
+
+        /// IPv4 address always assigned to this user
+        #[clap(long)]
+        static_ip: Option<IpAddr>,
+
+        /// IPv6 address always assigned to this user
+        #[clap(long)]
+        static_ipv6: Option<Ipv6Addr>,
+    },
+
+    /// Delete a user
+    Remove {
+        username: String,
+    },
+
+    /// Show all users
+    List,
+
+    /// Change a user's password
+    Passwd {
+        username: String,
+
+        /// New password; prompted for when omitted
+        #[clap(long)]
+        password: Option<String>,
+    },
+
+    /// Prevent a user from logging in without deleting the account
+    Disable {
+        username: String,
+    },
+
+    /// Allow a disabled user to log in again
+    Enable {
+        username: String,
+    },
+
+    /// Set a user's bandwidth limits and data quotas.
+    ///
+    /// Values are bytes with an optional K, M, G or T suffix (powers of
+    /// 1024), or "unlimited". Limits that are not given stay as they are.
+    Limit {
+        username: String,
+
+        /// Bytes per second from the client
+        #[clap(long)]
+        upload_rate: Option<Amount>,
+
+        /// Bytes per second to the client
+        #[clap(long)]
+        download_rate: Option<Amount>,
+
+        /// Bytes per UTC day, both directions together
+        #[clap(long)]
+        daily_quota: Option<Amount>,
+
+        /// Bytes per UTC calendar month, both directions together
+        #[clap(long)]
+        monthly_quota: Option<Amount>,
+    },
+}
+
+/// Byte amount given on the command line; `None` means unlimited
+#[derive(Debug, Clone, Copy)]
+pub struct Amount(Option<u64>);
+
+impl FromStr for Amount {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        let value = value.trim();
+        if value.eq_ignore_ascii_case("unlimited") {
+            return Ok(Amount(None));
+        }
+
+        let (digits, shift) = match value.char_indices().last() {
+            Some((i, 'K' | 'k')) => (&value[..i], 10),
+            Some((i, 'M' | 'm')) => (&value[..i], 20),
+            Some((i, 'G' | 'g')) => (&value[..i], 30),
+            Some((i, 'T' | 't')) => (&value[..i], 40),
+            _ => (value, 0),
+        };
+
+        let amount: u64 = digits.trim().parse()
+            .map_err(|_| format!("invalid amount {:?}, expected e.g. 512K, 10M or unlimited", value))?;
+        let amount = amount.checked_shl(shift)
+            .filter(|shifted| shifted >> shift == amount)
+            .ok_or_else(|| format!("amount {:?} is too large", value))?;
+
+        // Zero would stop all traffic, which is what `disable` is for
+        if amount == 0 {
+            return Err("amount must be greater than zero, use `disable` to block a user".to_string());
+        }
+
+        Ok(Amount(Some(amount)))
+    }
+}
+
+impl UserCommand {
+    fn modifies_database(&self) -> bool {
+        !matches!(self, UserCommand::List)
+    }
+}
+
+/// Run a user command against the database named in the server config
+pub async fn run(config: &ServerConfig, command: UserCommand, reload: bool) -> Result<()> {
+    if reload && config.pid_file.is_none() {
+        bail!("--reload needs pid_file to be set in the server config");
+    }
+
+    let modifies_database = command.modifies_database();
+
+    // Keep a concurrent run from saving over this one's changes
+    let _lock = if modifies_database {
+        let lock = storage::lock_exclusive(&config.user_db_path)
+            .map_err(|e| anyhow!("Failed to lock {}: {}", config.user_db_path.display(), e))?;
+        Some(lock)
+    } else {
+        None
+    };
+    let mut user_db = UserDatabase::load(&config.user_db_path, &config.password_hashing).await?;
+
+    match command {
+        UserCommand::Add { username, password, static_ip, static_ipv6 } => {
+            if user_db.contains(&username) {
+                bail!("User {} already exists", username);
+            }
+
+            if matches!(static_ip, Some(IpAddr::V6(_))) {
+                bail!("--static-ip must be an IPv4 address, use --static-ipv6 for IPv6");
+            }
+
+            let password = match password {
+                Some(password) => password,
+                None => prompt_new_password()?,
+            };
+
+            let mut record = UserRecord::new(user_db.hash_password(&password)?);
+            record.static_ip = static_ip;
+            record.static_ipv6 = static_ipv6;
+            user_db.insert_user(username.clone(), record);
+
+            println!("Added user {}", username);
+        }
+        UserCommand::Remove { username } => {
+            if !user_db.remove_user(&username) {
+                bail!("User {} does not exist", username);
+            }
+
+            println!("Removed user {}", username);
+        }
+        UserCommand::List => {
+            let users = user_db.users();
+
+            if users.is_empty() {
+                println!("No users");
+            }
+
+            for (username, user) in users {
+                let status = if user.disabled { "disabled" } else { "enabled" };
+                let addresses: Vec<String> = user.static_ip.into_iter()
+                    .chain(user.static_ipv6.map(IpAddr::V6))
+                    .map(|ip| ip.to_string())
+                    .collect();
+
+                let mut details = addresses;
+                details.extend(describe_limits(&user.limits));
+
+                if details.is_empty() {
+                    println!("{:<24} {}", username, status);
+                } else {
+                    println!("{:<24} {:<9} {}", username, status, details.join(", "));
+                }
+            }
+        }
+        UserCommand::Passwd { username, password } => {
+            if !user_db.contains(&username) {
+                bail!("User {} does not exist", username);
+            }
+
+            let password = match password {
+                Some(password) => password,
+                None => prompt_new_password()?,
+            };
+
+            user_db.set_password(&username, &password)?;
+            println!("Changed password of {}", username);
+        }
+        UserCommand::Disable { username } => {
+            if !user_db.set_disabled(&username, true) {
+                bail!("User {} does not exist", username);
+            }
+
+            println!("Disabled user {}", username);
+        }
+        UserCommand::Enable { username } => {
+            if !user_db.set_disabled(&username, false) {
+                bail!("User {} does not exist", username);
+            }
+
+            println!("Enabled user {}", username);
+        }
+        UserCommand::Limit { username, upload_rate, download_rate, daily_quota, monthly_quota } => {
+            let mut limits = user_db.limits(&username);
+            let changes = [
+                (&mut limits.upload_rate, upload_rate),
+                (&mut limits.download_rate, download_rate),
+                (&mut limits.daily_quota, daily_quota),
+                (&mut limits.monthly_quota, monthly_quota),
+            ];
+            for (limit, amount) in changes {
+                if let Some(Amount(amount)) = amount {
+                    *limit = amount;
+                }
+            }
+
+            if !user_db.set_limits(&username, limits) {
+                bail!("User {} does not exist", username);
+            }
+
+            match describe_limits(&limits) {
+                described if described.is_empty() => println!("User {} is unlimited", username),
+                described => println!("Limits of {}: {}", username, described.join(", ")),
+            }
+        }
+    }
+
+    if modifies_database {
+        user_db.save(&config.user_db_path).await?;
+
+        if reload {
+            notify_reload(config)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn describe_limits(limits: &UserLimits) -> Vec<String> {
+    let mut described = Vec::new();
+
+    if let Some(rate) = limits.upload_rate {
+        described.push(format!("up {}/s", format_bytes(rate)));
+    }
+    if let Some(rate) = limits.download_rate {
+        described.push(format!("down {}/s", format_bytes(rate)));
+    }
+    if let Some(quota) = limits.daily_quota {
+        described.push(format!("{}/day", format_bytes(quota)));
+    }
+    if let Some(quota) = limits.monthly_quota {
+        described.push(format!("{}/month", format_bytes(quota)));
+    }
+
+    described
+}
+
+fn prompt_new_password() -> Result<String> {
+    let password = rpassword::prompt_password("Password: ")?;
+    let confirmation = rpassword::prompt_password("Confirm password: ")?;
+
+    if password != confirmation {
+        bail!("Passwords do not match");
+    }
+
+    if password.is_empty() {
+        bail!("Password must not be empty");
+    }
+
+    Ok(password)
+}
+
+/// Ask a running server to reload its users by sending it SIGHUP
+pub fn notify_reload(config: &ServerConfig) -> Result<()> {
+    let pid_file = config.pid_file.as_ref()
+        .ok_or_else(|| anyhow!("Cannot notify the server: no pid_file is configured"))?;
+
+    let pid: libc::pid_t = std::fs::read_to_string(pid_file)
+        .map_err(|e| anyhow!("Failed to read {}: {}", pid_file.display(), e))?
+        .trim()
+        .parse()
+        .map_err(|e| anyhow!("Invalid pid in {}: {}", pid_file.display(), e))?;
+
+    if unsafe { libc::kill(pid, libc::SIGHUP) } != 0 {
+        bail!(
+            "Failed to signal server process {}: {}",
+            pid,
+            std::io::Error::last_os_error()
+        );
+    }
+
+    println!("Asked server process {} to reload", pid);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(value: &str) -> Option<u64> {
+        value.parse::<Amount>().unwrap().0
+    }
+
+    #[test]
+    fn parses_plain_and_suffixed_amounts() {
+        assert_eq!(amount("1500"), Some(1500));
+        assert_eq!(amount("512K"), Some(512 << 10));
+        assert_eq!(amount("10m"), Some(10 << 20));
+        assert_eq!(amount(" 2 G "), Some(2 << 30));
+        assert_eq!(amount("1T"), Some(1 << 40));
+    }
+
+    #[test]
+    fn parses_unlimited() {
+        assert_eq!(amount("unlimited"), None);
+        assert_eq!(amount("Unlimited"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_amounts() {
+        for value in ["", "K", "-1", "1.5M", "10X", "ten"] {
+            let error = value.parse::<Amount>().unwrap_err();
+            assert!(error.contains("invalid amount"), "{:?}: {}", value, error);
+        }
+    }
+
+    #[test]
+    fn rejects_zero_and_overflow() {
+        assert!("0".parse::<Amount>().unwrap_err().contains("greater than zero"));
+        assert!("0K".parse::<Amount>().is_err());
+        assert!("16777216T".parse::<Amount>().unwrap_err().contains("too large"));
+        assert!("18446744073709551616".parse::<Amount>().is_err());
+        assert_eq!(amount("16777215T"), Some(16777215 << 40));
+    }
+}
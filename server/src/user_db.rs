@@ -1,67 +1,398 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use common::config::PasswordHashConfig;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv6Addr};
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 use tokio::fs;
+use tracing::{info, warn};
 
-#[derive(Debug, Clone)]
+use crate::storage;
+
+#[derive(Clone)]
 pub struct UserDatabase {
-    users: Arc<Mutex<HashMap<String, String>>>,
+    users: Arc<Mutex<HashMap<String, UserRecord>>>,
+    hasher: Argon2<'static>,
+    /// Checked when the username is unknown, so rejecting a missing user
+    /// takes as long as rejecting a wrong password
+    dummy_hash: Arc<String>,
+}
+
+/// A single user account
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserRecord {
+    /// Argon2id hash in PHC string format
+    #[serde(alias = "password")]
+    pub password_hash: String,
+    /// IPv4 address always assigned to this user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub static_ip: Option<IpAddr>,
+    /// IPv6 address always assigned to this user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub static_ipv6: Option<Ipv6Addr>,
+    /// Disabled users keep their account but cannot log in
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub disabled: bool,
+    #[serde(default, skip_serializing_if = "UserLimits::is_unlimited")]
+    pub limits: UserLimits,
+}
+
+/// Bandwidth and data limits of one user; unset values are unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserLimits {
+    /// Bytes per second from the client into the tunnel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_rate: Option<u64>,
+    /// Bytes per second from the tunnel to the client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_rate: Option<u64>,
+    /// Bytes in both directions per UTC day
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily_quota: Option<u64>,
+    /// Bytes in both directions per UTC calendar month
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monthly_quota: Option<u64>,
+}
+
+impl UserLimits {
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl UserRecord {
+    pub fn new(password_hash: String) -> Self {
+        Self {
+            password_hash,
+            static_ip: None,
+            static_ipv6: None,
+            disabled: false,
+            limits: UserLimits::default(),
+        }
+    }
+}
+
+/// Older databases stored the password directly as the value
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredUser {
+    Record(UserRecord),
+    Legacy(String),
 }
 
-#[derive(Serialize, Deserialize)]
+impl From<StoredUser> for UserRecord {
+    fn from(stored: StoredUser) -> Self {
+        match stored {
+            StoredUser::Record(record) => record,
+            StoredUser::Legacy(password) => UserRecord::new(password),
+        }
+    }
+}
+
+#[derive(Deserialize)]
 struct UserDatabaseFile {
-    users: HashMap<String, String>,
+    users: HashMap<String, StoredUser>,
+}
+
+#[derive(Serialize)]
+struct UserDatabaseFileRef<'a> {
+    users: &'a HashMap<String, UserRecord>,
 }
 
 impl UserDatabase {
-    pub fn new() -> Self {
-        Self {
+    pub fn new(hashing: &PasswordHashConfig) -> Result<Self> {
+        let params = Params::new(hashing.memory_kib, hashing.iterations, hashing.parallelism, None)
+            .map_err(|e| anyhow!("Invalid password hashing parameters: {}", e))?;
+        let hasher = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+        let dummy_hash = hash_with(&hasher, "not a real password")?;
+
+        Ok(Self {
             users: Arc::new(Mutex::new(HashMap::new())),
-        }
+            hasher,
+            dummy_hash: Arc::new(dummy_hash),
+        })
     }
 
-    pub async fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let db = if let Ok(content) = fs::read_to_string(path).await {
-            let db_file: UserDatabaseFile = serde_json::from_str(&content)?;
-            Self {
-                users: Arc::new(Mutex::new(db_file.users)),
-            }
-        } else {
-            Self::new()
-        };
+    /// Load the database, hashing and writing back any plaintext passwords
+    pub async fn load<P: AsRef<Path>>(path: P, hashing: &PasswordHashConfig) -> Result<Self> {
+        let db = Self::new(hashing)?;
+        db.reload(path).await?;
 
         Ok(db)
     }
 
+    /// Replace every user with the contents of the file at `path`.
+    ///
+    /// Clones of this database share the users, so a running server picks
+    /// up the change immediately.
+    pub async fn reload<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+
+        let content = match fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(anyhow!("Failed to read {}: {}", path.display(), e)),
+        };
+
+        let db_file: UserDatabaseFile = serde_json::from_str(&content)?;
+
+        // Hashing migrated passwords is too slow for the async runtime
+        let hasher = self.hasher.clone();
+        let (users, upgraded) = tokio::task::spawn_blocking(move || -> Result<_> {
+            let mut users = HashMap::new();
+            let mut upgraded = 0;
+
+            for (username, stored) in db_file.users {
+                let mut user = UserRecord::from(stored);
+
+                // Anything that is not a PHC string is a legacy plaintext password
+                if PasswordHash::new(&user.password_hash).is_err() {
+                    user.password_hash = hash_with(&hasher, &user.password_hash)?;
+                    upgraded += 1;
+                }
+
+                users.insert(username, user);
+            }
+
+            Ok((users, upgraded))
+        }).await??;
+
+        *self.users.lock().unwrap() = users;
+
+        if upgraded > 0 {
+            self.save(path).await?;
+            warn!(
+                "Migrated {} plaintext passwords in {} to Argon2id hashes",
+                upgraded,
+                path.display()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Write the database, atomically replacing the existing file
     pub async fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        let users = self.users.lock().unwrap().clone();
-        let db_file = UserDatabaseFile { users };
+        let content = {
+            let users = self.users.lock().unwrap();
+            serde_json::to_string_pretty(&UserDatabaseFileRef { users: &users })?
+        };
 
-        let content = serde_json::to_string_pretty(&db_file)?;
-        fs::write(path, content).await?;
+        storage::write_atomic(path.as_ref(), content.as_bytes())?;
 
         Ok(())
     }
 
-    pub fn add_user(&mut self, username: String, password: String) {
+    pub fn add_user(&mut self, username: String, password: String) -> Result<()> {
+        let record = UserRecord::new(self.hash_password(&password)?);
+        self.insert_user(username, record);
+
+        Ok(())
+    }
+
+    pub fn insert_user(&mut self, username: String, record: UserRecord) {
         let mut users = self.users.lock().unwrap();
-        users.insert(username, password);
+        users.insert(username, record);
     }
 
-    pub fn authenticate(&self, username: &str, password: &str) -> bool {
+    pub fn remove_user(&mut self, username: &str) -> bool {
+        let mut users = self.users.lock().unwrap();
+        users.remove(username).is_some()
+    }
+
+    pub fn contains(&self, username: &str) -> bool {
+        self.users.lock().unwrap().contains_key(username)
+    }
+
+    /// Limits of a user, unlimited if the user does not exist
+    pub fn limits(&self, username: &str) -> UserLimits {
+        self.users.lock().unwrap().get(username).map(|user| user.limits).unwrap_or_default()
+    }
+
+    /// Change a user's limits, returning `false` if the user does not exist
+    pub fn set_limits(&mut self, username: &str, limits: UserLimits) -> bool {
+        let mut users = self.users.lock().unwrap();
+        match users.get_mut(username) {
+            Some(user) => {
+                user.limits = limits;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the user exists and is not disabled
+    pub fn is_active(&self, username: &str) -> bool {
+        self.users.lock().unwrap().get(username).is_some_and(|user| !user.disabled)
+    }
+
+    /// All users sorted by name
+    pub fn users(&self) -> Vec<(String, UserRecord)> {
         let users = self.users.lock().unwrap();
-        
-        if let Some(stored_password) = users.get(username) {
-            stored_password == password
-        } else {
-            false
+        let mut list: Vec<_> = users.iter()
+            .map(|(username, user)| (username.clone(), user.clone()))
+            .collect();
+        list.sort_by(|a, b| a.0.cmp(&b.0));
+
+        list
+    }
+
+    /// Change a user's password, returning `false` if the user does not exist
+    pub fn set_password(&mut self, username: &str, password: &str) -> Result<bool> {
+        let password_hash = self.hash_password(password)?;
+
+        let mut users = self.users.lock().unwrap();
+        match users.get_mut(username) {
+            Some(user) => {
+                user.password_hash = password_hash;
+                Ok(true)
+            }
+            None => Ok(false),
         }
     }
 
-    pub fn remove_user(&mut self, username: &str) -> bool {
+    /// Enable or disable a user, returning `false` if the user does not exist
+    pub fn set_disabled(&mut self, username: &str, disabled: bool) -> bool {
         let mut users = self.users.lock().unwrap();
-        users.remove(username).is_some()
+        match users.get_mut(username) {
+            Some(user) => {
+                user.disabled = disabled;
+                true
+            }
+            None => false,
+        }
     }
-} 
\ No newline at end of file
+
+    /// Check a user's password.
+    ///
+    /// This runs a full Argon2id hash, so call it from a blocking task.
+    pub fn authenticate(&self, username: &str, password: &str) -> bool {
+        let (stored_hash, disabled) = {
+            let users = self.users.lock().unwrap();
+            match users.get(username) {
+                Some(user) => (Some(user.password_hash.clone()), user.disabled),
+                None => (None, false),
+            }
+        };
+
+        let known_user = stored_hash.is_some();
+        let stored_hash = stored_hash.unwrap_or_else(|| self.dummy_hash.as_str().to_string());
+
+        // The verifier uses the cost stored in the hash and compares in constant time
+        let verified = match PasswordHash::new(&stored_hash) {
+            Ok(hash) => self.hasher.verify_password(password.as_bytes(), &hash).is_ok(),
+            Err(_) => false,
+        };
+
+        if known_user && verified && disabled {
+            info!("Rejected login for disabled user {}", username);
+            return false;
+        }
+
+        known_user && verified
+    }
+
+    /// Hash a password with the configured cost and a fresh salt
+    pub fn hash_password(&self, password: &str) -> Result<String> {
+        hash_with(&self.hasher, password)
+    }
+
+    /// Static address reservations of every user, both address families
+    pub fn reservations(&self) -> Vec<(String, IpAddr)> {
+        let users = self.users.lock().unwrap();
+
+        users.iter()
+            .flat_map(|(username, user)| {
+                user.static_ip.into_iter()
+                    .chain(user.static_ipv6.map(IpAddr::V6))
+                    .map(move |ip| (username.clone(), ip))
+            })
+            .collect()
+    }
+}
+
+fn hash_with(hasher: &Argon2<'static>, password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    hasher.hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| anyhow!("Failed to hash password: {}", e))
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Cheap parameters so the tests do not spend their time hashing
+    fn hashing() -> PasswordHashConfig {
+        PasswordHashConfig { memory_kib: 64, iterations: 1, parallelism: 1 }
+    }
+
+    fn db_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("quicvpn-users-{}-{}.json", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn migrates_plaintext_passwords() {
+        let path = db_file("migrate", r#"{"users": {"alice": "secret", "bob": {"password": "hunter2", "disabled": true}}}"#);
+        let db = UserDatabase::load(&path, &hashing()).await.unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("secret") && !content.contains("hunter2"), "{}", content);
+        for (_, user) in db.users() {
+            assert!(PasswordHash::new(&user.password_hash).is_ok());
+        }
+        assert!(db.users()[1].1.disabled);
+
+        // The written file loads again without another migration
+        let reloaded = UserDatabase::load(&path, &hashing()).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), content);
+        assert!(reloaded.authenticate("alice", "secret"));
+    }
+
+    #[tokio::test]
+    async fn verifies_migrated_legacy_entries() {
+        let path = db_file("legacy", r#"{"users": {"alice": "secret"}}"#);
+        let db = UserDatabase::load(&path, &hashing()).await.unwrap();
+
+        assert!(db.authenticate("alice", "secret"));
+        assert!(!db.authenticate("alice", "Secret"));
+    }
+
+    #[tokio::test]
+    async fn missing_file_is_an_empty_database() {
+        let path = std::env::temp_dir().join(format!("quicvpn-users-{}-missing.json", std::process::id()));
+        let db = UserDatabase::load(&path, &hashing()).await.unwrap();
+
+        assert!(db.users().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unreadable_file_is_an_error() {
+        let directory = std::env::temp_dir();
+        let db = UserDatabase::new(&hashing()).unwrap();
+
+        assert!(db.reload(&directory).await.is_err());
+    }
+
+    #[test]
+    fn rejects_disabled_and_unknown_users() {
+        let mut db = UserDatabase::new(&hashing()).unwrap();
+        db.add_user("alice".to_string(), "secret".to_string()).unwrap();
+        assert!(db.authenticate("alice", "secret"));
+
+        assert!(db.set_disabled("alice", true));
+        assert!(!db.authenticate("alice", "secret"));
+        assert!(!db.is_active("alice"));
+
+        assert!(!db.authenticate("mallory", "secret"));
+        assert!(!db.authenticate("mallory", "not a real password"));
+    }
+}